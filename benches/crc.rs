@@ -0,0 +1,36 @@
+//! Throughput of the CRC-32 implementations. Run with `cargo bench --bench crc`.
+
+use png::png::crc::{self, Crc};
+use std::{hint::black_box, time::Instant};
+
+const SIZE: usize = 64 << 20;
+const RUNS: usize = 5;
+
+fn bench(name: &str, data: &[u8], crc: impl Fn(&[u8]) -> u32) {
+    let mut best = f64::INFINITY;
+    let mut value = 0;
+
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        value = black_box(crc(black_box(data)));
+        best = best.min(start.elapsed().as_secs_f64());
+    }
+
+    let throughput = data.len() as f64 / best / (1 << 20) as f64;
+    println!("{:<16} {:>10.1} MiB/s  {:08x}", name, throughput, value);
+}
+
+fn main() {
+    let data: Vec<u8> = (0..SIZE as u32)
+        .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+        .collect();
+
+    bench("bytewise", &data, |data| crc::bytewise(0, data));
+    bench("slicing-by-16", &data, |data| crc::slicing_by_16(0, data));
+    if crc::clmul(0, &data).is_some() {
+        bench("clmul", &data, |data| crc::clmul(0, data).unwrap());
+    } else {
+        println!("{:<16} not supported by this CPU", "clmul");
+    }
+    bench("Crc::calculate", &data, |data| Crc::new().calculate(data));
+}
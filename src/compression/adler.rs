@@ -0,0 +1,190 @@
+//! Adler-32 is the checksum of the zlib format (RFC 1950), so it trails every compressed stream of
+//! a PNG file: the image data and the `iCCP`, `zTXt` and `iTXt` chunks. It is weaker than a CRC,
+//! but much faster to compute.
+//!
+//! It is composed of two sums modulo 65521, the largest prime smaller than 2^16:
+//!
+//! ```text
+//! A = 1 + D1 + D2 + ... + Dn
+//! B = (1 + D1) + (1 + D1 + D2) + ... + (1 + D1 + ... + Dn)
+//!   = n + n*D1 + (n-1)*D2 + ... + Dn
+//!
+//! Adler-32 = B * 65536 + A
+//! ```
+//!
+//! # Deferring the modulo
+//!
+//! Calculating the modulo after every byte is slow. With 32-bit sums, it can be delayed for
+//! `NMAX = 5552` bytes: the largest `n` such that `255 * n * (n + 1) / 2 + (n + 1) * (65521 - 1)`
+//! still fits, which is the worst case of `B` (all bytes 255, starting just below the modulo).
+//!
+//! # Combining checksums
+//!
+//! Given the checksums of `X` and `Y`, and the length `n` of `Y`, the checksum of `X` followed by
+//! `Y` can be calculated without the data. Every `A` of `Y` gets `A(X) - 1` added, because the
+//! sum restarts at 1 instead of `A(X)`:
+//!
+//! ```text
+//! A(XY) = A(X) + A(Y) - 1
+//! B(XY) = B(X) + B(Y) + n * (A(X) - 1)
+//! ```
+
+use std::hash::Hasher;
+
+const MOD: u32 = 65521;
+const NMAX: usize = 5552;
+
+/// Streaming Adler-32 calculation, which can also be used as a `Hasher`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Adler32 {
+    a: u32,
+    b: u32,
+    bytes: u64,
+}
+
+impl Default for Adler32 {
+    fn default() -> Self {
+        Self {
+            a: 1,
+            b: 0,
+            bytes: 0,
+        }
+    }
+}
+
+impl Adler32 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the checksum of `data`.
+    pub fn calculate(data: &[u8]) -> u32 {
+        let mut adler = Self::new();
+        adler.update(data);
+        adler.finalize()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for block in data.chunks(NMAX) {
+            for &byte in block {
+                self.a += byte as u32;
+                self.b += self.a;
+            }
+            self.a %= MOD;
+            self.b %= MOD;
+        }
+
+        self.bytes += data.len() as u64;
+    }
+
+    pub fn finalize(&self) -> u32 {
+        self.b << 16 | self.a
+    }
+
+    pub fn bytes_hashed(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Appends the data hashed by `other`, as if it had been given to `update`.
+    pub fn combine(&mut self, other: &Adler32) {
+        let adler = adler32_combine(self.finalize(), other.finalize(), other.bytes);
+        self.a = adler & 0xFFFF;
+        self.b = adler >> 16;
+        self.bytes += other.bytes;
+    }
+}
+
+impl Hasher for Adler32 {
+    fn finish(&self) -> u64 {
+        self.finalize() as u64
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.update(bytes);
+    }
+}
+
+/// Returns the checksum of `A` followed by `B` given the checksum of each part and the length of
+/// `B`.
+pub fn adler32_combine(adler_a: u32, adler_b: u32, len_b: u64) -> u32 {
+    let n = (len_b % MOD as u64) as u32;
+    let (a1, b1) = (adler_a & 0xFFFF, adler_a >> 16);
+    let (a2, b2) = (adler_b & 0xFFFF, adler_b >> 16);
+
+    // Adding `MOD` to avoid underflows, all values are smaller than it
+    let a = (a1 + a2 + MOD - 1) % MOD;
+    let b = (b1 + b2 + (n * a1) % MOD + MOD - n) % MOD;
+
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One byte at a time, with the modulo on each step
+    fn simple(data: &[u8]) -> u32 {
+        let (mut a, mut b) = (1, 0);
+        for &byte in data {
+            a = (a + byte as u32) % MOD;
+            b = (b + a) % MOD;
+        }
+        b << 16 | a
+    }
+
+    #[test]
+    fn adler_test() {
+        assert_eq!(Adler32::calculate(b""), 1);
+        assert_eq!(Adler32::calculate(b"Wikipedia"), 0x11E6_0398);
+
+        // Worst case for the deferred modulo
+        let data = vec![255; 3 * NMAX + 7];
+        assert_eq!(Adler32::calculate(&data), simple(&data));
+
+        let data: Vec<u8> = (0..100_000_u32).map(|i| (i * 7 % 251) as u8).collect();
+        let expected = simple(&data);
+        for split in [0, 1, NMAX, 70_000, data.len()] {
+            let (a, b) = data.split_at(split);
+            let combined = adler32_combine(simple(a), simple(b), b.len() as u64);
+            assert_eq!(combined, expected);
+
+            let mut adler = Adler32::new();
+            for piece in a.chunks(1000) {
+                adler.write(piece);
+            }
+            let mut rest = Adler32::new();
+            rest.update(b);
+            adler.combine(&rest);
+            assert_eq!(adler.finish(), expected as u64);
+            assert_eq!(adler.bytes_hashed(), data.len() as u64);
+        }
+    }
+
+    #[test]
+    fn combine_edge_test() {
+        let data: Vec<u8> = (0..3 * MOD as usize + 10)
+            .map(|i| (i % 253) as u8)
+            .collect();
+        let whole = simple(&data);
+
+        // Empty parts
+        assert_eq!(adler32_combine(whole, 1, 0), whole);
+        assert_eq!(adler32_combine(1, whole, data.len() as u64), whole);
+
+        // Lengths that are multiples of the modulus, or larger than it
+        for split in [10, MOD as usize + 10, 2 * MOD as usize + 10] {
+            let (a, b) = data.split_at(split);
+            assert_eq!(
+                adler32_combine(simple(a), simple(b), b.len() as u64),
+                whole,
+                "{}",
+                split
+            );
+        }
+
+        // A single changed byte changes the checksum
+        let mut corrupted = data.clone();
+        corrupted[MOD as usize] ^= 0x40;
+        assert_ne!(Adler32::calculate(&corrupted), whole);
+    }
+}
@@ -0,0 +1,152 @@
+//! DEFLATE packs data elements into bytes starting with the least-significant bit of the byte:
+//!
+//! - Data elements other than the Huffman codes are packed starting with the least-significant bit
+//!   of the data element.
+//! - Huffman codes are packed starting with the most-significant bit of the code.
+
+/// Accumulates bits and stores them as bytes once they are complete.
+#[derive(Debug, Default, Clone)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    bit_buffer: u64,
+    bit_count: u32,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Writes the `n` least-significant bits of `value`, starting by the least-significant one.
+    pub fn write_bits(&mut self, value: u32, n: u32) {
+        debug_assert!(n <= 32);
+
+        self.bit_buffer |= (value as u64 & ((1 << n) - 1)) << self.bit_count;
+        self.bit_count += n;
+
+        while self.bit_count >= 8 {
+            self.bytes.push(self.bit_buffer as u8);
+            self.bit_buffer >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    /// Writes a Huffman code of `length` bits, starting by its most-significant bit.
+    pub fn write_code(&mut self, code: u16, length: u8) {
+        self.write_bits(reverse_bits(code, length) as u32, length as u32);
+    }
+
+    /// Skips the remaining bits of the current partially written byte.
+    pub fn align_to_byte(&mut self) {
+        if self.bit_count > 0 {
+            self.write_bits(0, 8 - self.bit_count);
+        }
+    }
+
+    /// Writes whole bytes. The writer must be aligned.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        debug_assert_eq!(self.bit_count, 0, "BitWriter is not aligned to a byte");
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// Number of bits that are waiting for a byte to be completed.
+    pub fn pending_bits(&self) -> u32 {
+        self.bit_count
+    }
+
+    /// Moves the complete bytes written so far to `out`.
+    pub fn take_bytes(&mut self, out: &mut Vec<u8>) {
+        out.append(&mut self.bytes);
+    }
+}
+
+/// Reverses the order of the `length` least-significant bits of `code`.
+pub fn reverse_bits(code: u16, length: u8) -> u16 {
+    if length == 0 {
+        0
+    } else {
+        code.reverse_bits() >> (16 - length)
+    }
+}
+
+/// Reads bits from a byte slice in the same order `BitWriter` writes them. It never goes past the
+/// end of the data: the read functions return `None` instead, so the caller can wait for more input
+/// and try again from the same position.
+#[derive(Debug, Clone)]
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    /// Position in bits
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8], position: usize) -> Self {
+        Self { data, position }
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn set_position(&mut self, position: usize) {
+        self.position = position;
+    }
+
+    /// Number of bits left.
+    pub fn available(&self) -> usize {
+        8 * self.data.len() - self.position
+    }
+
+    /// Returns the next `n` bits (up to 32) without consuming them. If there are not enough bits,
+    /// the missing ones are zero.
+    pub fn peek(&self, n: u32) -> u32 {
+        debug_assert!(n <= 32);
+
+        let first_byte = (self.position / 8).min(self.data.len());
+        let shift = self.position % 8;
+
+        // At most 5 bytes are needed for 32 bits that do not start at a byte boundary
+        let bytes = &self.data[first_byte..];
+        let value = match bytes.get(..8) {
+            Some(bytes) => u64::from_le_bytes(bytes.try_into().unwrap()),
+            None => bytes
+                .iter()
+                .enumerate()
+                .fold(0, |value, (i, &byte)| value | (byte as u64) << (8 * i)),
+        };
+
+        ((value >> shift) & ((1 << n) - 1)) as u32
+    }
+
+    /// Reads `n` bits (up to 32), the first one read is the least-significant.
+    pub fn read(&mut self, n: u32) -> Option<u32> {
+        if self.available() < n as usize {
+            return None;
+        }
+
+        let value = self.peek(n);
+        self.position += n as usize;
+        Some(value)
+    }
+
+    /// Skips `n` bits, that must be available.
+    pub fn consume(&mut self, n: u32) {
+        debug_assert!(self.available() >= n as usize);
+        self.position += n as usize;
+    }
+
+    /// Skips the remaining bits of the current byte.
+    pub fn align_to_byte(&mut self) {
+        self.position = self.position.div_ceil(8) * 8;
+    }
+
+    /// Reads up to `max` whole bytes. The reader must be aligned.
+    pub fn read_bytes(&mut self, max: usize) -> &'a [u8] {
+        debug_assert_eq!(self.position % 8, 0, "BitReader is not aligned to a byte");
+
+        let start = self.position / 8;
+        let end = (start + max).min(self.data.len());
+        self.position = 8 * end;
+        &self.data[start..end]
+    }
+}
@@ -0,0 +1,502 @@
+//! DEFLATE compressor. The input is split in blocks, each block is tokenized with LZ77 and then
+//! written with whichever block type is smaller: non-compressed, fixed Huffman codes or dynamic
+//! Huffman codes.
+//!
+//! The `Deflater` works incrementally: input can be given in pieces of any size and the output is
+//! produced as soon as a block is complete, so only the window and the current block are kept in
+//! memory.
+
+use super::{
+    bits::BitWriter,
+    huffman::{self, MAX_CODE_LENGTH},
+    lz77::{MatchFinder, MatchParams, Token, MAX_MATCH, WINDOW_SIZE},
+    squeeze, Compression,
+};
+
+/// Input bytes per block
+const BLOCK_SIZE: usize = 1 << 16;
+
+/// Non-compressed blocks are limited to 65 535 bytes
+const MAX_STORED_SIZE: usize = u16::MAX as usize;
+
+pub(crate) const END_OF_BLOCK: usize = 256;
+pub(crate) const NUM_LITERALS: usize = 288;
+pub(crate) const NUM_DISTANCES: usize = 30;
+pub(crate) const NUM_CODE_LENGTHS: usize = 19;
+
+/// Base length of the length codes `257..=285`
+pub(crate) const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+pub(crate) const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// Base distance of the distance codes `0..=29`
+pub(crate) const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+pub(crate) const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Order in which the code length code lengths are stored
+pub(crate) const CODE_LENGTH_ORDER: [usize; NUM_CODE_LENGTHS] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Code lengths of the fixed Huffman codes (`BTYPE=01`) for the literal/length alphabet.
+pub(crate) fn fixed_literal_lengths() -> [u8; NUM_LITERALS] {
+    let mut lengths = [8; NUM_LITERALS];
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths
+}
+
+/// Distance codes are represented by (fixed-length) 5-bit codes in fixed blocks.
+pub(crate) const FIXED_DISTANCE_LENGTHS: [u8; 32] = [5; 32];
+
+/// Returns the index (symbol - 257) of the length code.
+pub(crate) fn length_index(length: u16) -> usize {
+    LENGTH_BASE.partition_point(|&base| base <= length) - 1
+}
+
+/// Returns the distance code.
+pub(crate) fn distance_index(distance: u16) -> usize {
+    DISTANCE_BASE.partition_point(|&base| base <= distance) - 1
+}
+
+impl Compression {
+    fn match_params(self) -> MatchParams {
+        match self {
+            Compression::None | Compression::Fast => MatchParams {
+                max_chain: 8,
+                nice_length: 32,
+                lazy: false,
+            },
+            Compression::Default => MatchParams {
+                max_chain: 128,
+                nice_length: 128,
+                lazy: true,
+            },
+            Compression::Best => MatchParams {
+                max_chain: 4096,
+                nice_length: MAX_MATCH,
+                lazy: true,
+            },
+            Compression::Exhaustive => MatchParams {
+                max_chain: 8192,
+                nice_length: MAX_MATCH,
+                lazy: true,
+            },
+        }
+    }
+
+    fn block_size(self) -> usize {
+        match self {
+            Compression::Exhaustive => squeeze::MASTER_BLOCK_SIZE,
+            _ => BLOCK_SIZE,
+        }
+    }
+}
+
+/// Incremental DEFLATE compressor. Compressed bytes are appended to the `out` argument of each
+/// method.
+#[derive(Debug, Clone)]
+pub struct Deflater {
+    level: Compression,
+    matcher: MatchFinder,
+    /// Previous input (the window) followed by the input not compressed yet
+    buffer: Vec<u8>,
+    /// Start of the input not compressed yet in `buffer`
+    position: usize,
+    writer: BitWriter,
+}
+
+impl Deflater {
+    pub fn new(level: Compression) -> Self {
+        Self {
+            level,
+            matcher: MatchFinder::new(level.match_params()),
+            buffer: Vec::new(),
+            position: 0,
+            writer: BitWriter::new(),
+        }
+    }
+
+    /// Compresses `data`. Only complete blocks are written, the rest is kept until more input is
+    /// given or the stream is flushed.
+    pub fn write(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        self.buffer.extend_from_slice(data);
+
+        // Leave enough lookahead so that matches are not cut at the end of the block
+        let block_size = self.level.block_size();
+        while self.buffer.len() - self.position >= block_size + MAX_MATCH {
+            self.compress_block(self.position + block_size, false);
+        }
+
+        self.writer.take_bytes(out);
+    }
+
+    /// Compresses all the pending input and aligns the output to a byte boundary with an empty
+    /// non-compressed block (sync flush), so everything written so far can be decompressed.
+    pub fn flush(&mut self, out: &mut Vec<u8>) {
+        if self.position < self.buffer.len() {
+            self.compress_block(self.buffer.len(), false);
+        }
+
+        self.write_stored_block(&[], false);
+        self.writer.take_bytes(out);
+    }
+
+    /// Compresses all the pending input and ends the stream with a final block.
+    pub fn finish(mut self, out: &mut Vec<u8>) {
+        if self.position < self.buffer.len() {
+            while self.position < self.buffer.len() {
+                let end = (self.position + self.level.block_size()).min(self.buffer.len());
+                self.compress_block(end, true);
+            }
+        } else {
+            // Empty block with fixed codes: just the end of block
+            self.writer.write_bits(0b011, 3);
+            self.writer.write_bits(0, 7);
+        }
+
+        self.writer.align_to_byte();
+        self.writer.take_bytes(out);
+    }
+
+    /// Compresses `buffer[position..end]` (or a bit more if the last match goes past `end`) into a
+    /// block, or several with `Compression::Exhaustive`. If `finishing`, the block that reaches the
+    /// end of the input is the final one.
+    fn compress_block(&mut self, end: usize, finishing: bool) {
+        let start = self.position;
+        let raw = std::mem::take(&mut self.buffer);
+
+        let end = match self.level {
+            Compression::None => {
+                self.write_block(&[], &raw[start..end], finishing && end == raw.len());
+                end
+            }
+            Compression::Exhaustive => {
+                for (tokens, range) in squeeze::squeeze(&mut self.matcher, &raw[..end], start) {
+                    let last = finishing && range.end == raw.len();
+                    self.write_block(&tokens, &raw[range], last);
+                }
+                end
+            }
+            _ => {
+                let (tokens, end) = self.matcher.tokenize(&raw, start, end);
+                self.write_block(&tokens, &raw[start..end], finishing && end == raw.len());
+                end
+            }
+        };
+
+        self.buffer = raw;
+        self.position = end;
+
+        // Only keep the window
+        if self.position > 2 * WINDOW_SIZE {
+            let discard = self.position - WINDOW_SIZE;
+            self.buffer.drain(..discard);
+            self.position -= discard;
+        }
+    }
+
+    /// Writes the tokens as the smallest block type. `raw` are the bytes they represent.
+    fn write_block(&mut self, tokens: &[Token], raw: &[u8], last: bool) {
+        if self.level == Compression::None {
+            self.write_stored_block(raw, last);
+            return;
+        }
+
+        let (literal_freqs, distance_freqs) = frequencies(tokens);
+        let dynamic = DynamicCodes::new(&literal_freqs, &distance_freqs);
+
+        let fixed_literals = fixed_literal_lengths();
+        let fixed_cost = data_cost(
+            &literal_freqs,
+            &distance_freqs,
+            &fixed_literals,
+            &FIXED_DISTANCE_LENGTHS,
+        );
+        let dynamic_cost = dynamic.header_cost()
+            + data_cost(
+                &literal_freqs,
+                &distance_freqs,
+                &dynamic.literal_lengths,
+                &dynamic.distance_lengths,
+            );
+        let stored_cost = stored_cost(raw.len(), self.writer.pending_bits());
+
+        if stored_cost <= fixed_cost.min(dynamic_cost) {
+            self.write_stored_block(raw, last);
+        } else if fixed_cost <= dynamic_cost {
+            self.writer.write_bits(u32::from(last) | 0b01 << 1, 3);
+            self.write_tokens(tokens, &fixed_literals, &FIXED_DISTANCE_LENGTHS);
+        } else {
+            self.writer.write_bits(u32::from(last) | 0b10 << 1, 3);
+            dynamic.write_header(&mut self.writer);
+            self.write_tokens(tokens, &dynamic.literal_lengths, &dynamic.distance_lengths);
+        }
+    }
+
+    /// Writes `raw` as non-compressed blocks (as many as needed because of the size limit).
+    fn write_stored_block(&mut self, raw: &[u8], last: bool) {
+        let mut pieces: Vec<&[u8]> = raw.chunks(MAX_STORED_SIZE).collect();
+        if pieces.is_empty() {
+            pieces.push(&[]);
+        }
+
+        let num_pieces = pieces.len();
+        for (i, piece) in pieces.into_iter().enumerate() {
+            let is_last_piece = i + 1 == num_pieces;
+            self.writer.write_bits(u32::from(last && is_last_piece), 3);
+            self.writer.align_to_byte();
+
+            let len = piece.len() as u16;
+            self.writer.write_bytes(&len.to_le_bytes());
+            self.writer.write_bytes(&(!len).to_le_bytes());
+            self.writer.write_bytes(piece);
+        }
+    }
+
+    fn write_tokens(&mut self, tokens: &[Token], literal_lengths: &[u8], distance_lengths: &[u8]) {
+        let literal_codes = huffman::canonical_codes(literal_lengths);
+        let distance_codes = huffman::canonical_codes(distance_lengths);
+
+        for token in tokens {
+            match *token {
+                Token::Literal(byte) => {
+                    let symbol = byte as usize;
+                    self.writer
+                        .write_code(literal_codes[symbol], literal_lengths[symbol]);
+                }
+                Token::Match { length, distance } => {
+                    let index = length_index(length);
+                    let symbol = 257 + index;
+                    self.writer
+                        .write_code(literal_codes[symbol], literal_lengths[symbol]);
+                    self.writer.write_bits(
+                        (length - LENGTH_BASE[index]) as u32,
+                        LENGTH_EXTRA[index] as u32,
+                    );
+
+                    let index = distance_index(distance);
+                    self.writer
+                        .write_code(distance_codes[index], distance_lengths[index]);
+                    self.writer.write_bits(
+                        (distance - DISTANCE_BASE[index]) as u32,
+                        DISTANCE_EXTRA[index] as u32,
+                    );
+                }
+            }
+        }
+
+        self.writer
+            .write_code(literal_codes[END_OF_BLOCK], literal_lengths[END_OF_BLOCK]);
+    }
+}
+
+/// Compresses all `data` at once.
+pub fn deflate(data: &[u8], level: Compression) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut deflater = Deflater::new(level);
+    deflater.write(data, &mut out);
+    deflater.finish(&mut out);
+    out
+}
+
+/// Counts how many times each literal/length and distance symbol is used (end of block included).
+pub(crate) fn frequencies(tokens: &[Token]) -> ([u32; NUM_LITERALS], [u32; NUM_DISTANCES]) {
+    let mut literal_freqs = [0; NUM_LITERALS];
+    let mut distance_freqs = [0; NUM_DISTANCES];
+
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => literal_freqs[byte as usize] += 1,
+            Token::Match { length, distance } => {
+                literal_freqs[257 + length_index(length)] += 1;
+                distance_freqs[distance_index(distance)] += 1;
+            }
+        }
+    }
+    literal_freqs[END_OF_BLOCK] = 1;
+
+    (literal_freqs, distance_freqs)
+}
+
+/// Size in bits of the compressed data of a block, with the given code lengths.
+pub(crate) fn data_cost(
+    literal_freqs: &[u32],
+    distance_freqs: &[u32],
+    literal_lengths: &[u8],
+    distance_lengths: &[u8],
+) -> u64 {
+    let mut cost = 0;
+
+    for (symbol, &freq) in literal_freqs.iter().enumerate() {
+        let extra = symbol
+            .checked_sub(257)
+            .and_then(|index| LENGTH_EXTRA.get(index))
+            .copied()
+            .unwrap_or(0);
+        cost += freq as u64 * (literal_lengths[symbol] + extra) as u64;
+    }
+
+    for (symbol, &freq) in distance_freqs.iter().enumerate() {
+        cost += freq as u64 * (distance_lengths[symbol] + DISTANCE_EXTRA[symbol]) as u64;
+    }
+
+    cost
+}
+
+/// Size in bits of `len` bytes stored as non-compressed blocks.
+fn stored_cost(len: usize, pending_bits: u32) -> u64 {
+    let blocks = len.div_ceil(MAX_STORED_SIZE).max(1) as u64;
+    let padding = (8 - (pending_bits + 3) % 8) % 8;
+    // Header, padding, LEN and NLEN for each block, and the data itself
+    blocks * (3 + 32) + padding as u64 + 8 * len as u64 + (blocks - 1) * 5
+}
+
+/// Huffman codes of a dynamic block (`BTYPE=10`) and the representation of the code lengths.
+pub(crate) struct DynamicCodes {
+    pub literal_lengths: Vec<u8>,
+    pub distance_lengths: Vec<u8>,
+    /// Code lengths of the literal/length and distance codes, compressed as pairs of code length
+    /// symbol (`0..=18`) and the value of its extra bits
+    encoded_lengths: Vec<(u8, u8)>,
+    code_length_lengths: Vec<u8>,
+    num_literals: usize,
+    num_distances: usize,
+    num_code_lengths: usize,
+}
+
+impl DynamicCodes {
+    pub fn new(literal_freqs: &[u32], distance_freqs: &[u32]) -> Self {
+        let literal_lengths = huffman::code_lengths(literal_freqs, MAX_CODE_LENGTH);
+        let mut distance_lengths = huffman::code_lengths(distance_freqs, MAX_CODE_LENGTH);
+
+        // Only literals: still give some codes to the distances to keep the code complete
+        if distance_lengths.iter().all(|&length| length == 0) {
+            distance_lengths[0] = 1;
+            distance_lengths[1] = 1;
+        }
+
+        let num_literals = 257.max(last_used(&literal_lengths) + 1);
+        let num_distances = 1.max(last_used(&distance_lengths) + 1);
+
+        let mut all_lengths = literal_lengths[..num_literals].to_vec();
+        all_lengths.extend_from_slice(&distance_lengths[..num_distances]);
+        let encoded_lengths = run_length_encode(&all_lengths);
+
+        let mut code_length_freqs = [0; NUM_CODE_LENGTHS];
+        for &(symbol, _) in &encoded_lengths {
+            code_length_freqs[symbol as usize] += 1;
+        }
+        let code_length_lengths = huffman::code_lengths(&code_length_freqs, 7);
+
+        let num_code_lengths = 4.max(
+            CODE_LENGTH_ORDER
+                .iter()
+                .rposition(|&symbol| code_length_lengths[symbol] != 0)
+                .map_or(0, |i| i + 1),
+        );
+
+        Self {
+            literal_lengths,
+            distance_lengths,
+            encoded_lengths,
+            code_length_lengths,
+            num_literals,
+            num_distances,
+            num_code_lengths,
+        }
+    }
+
+    /// Size in bits of the block header (without the 3 bits of `BFINAL` and `BTYPE`).
+    pub fn header_cost(&self) -> u64 {
+        let mut cost = 5 + 5 + 4 + 3 * self.num_code_lengths as u64;
+        for &(symbol, _) in &self.encoded_lengths {
+            cost += self.code_length_lengths[symbol as usize] as u64
+                + code_length_extra_bits(symbol) as u64;
+        }
+        cost
+    }
+
+    pub fn write_header(&self, writer: &mut BitWriter) {
+        writer.write_bits((self.num_literals - 257) as u32, 5);
+        writer.write_bits((self.num_distances - 1) as u32, 5);
+        writer.write_bits((self.num_code_lengths - 4) as u32, 4);
+
+        for &symbol in &CODE_LENGTH_ORDER[..self.num_code_lengths] {
+            writer.write_bits(self.code_length_lengths[symbol] as u32, 3);
+        }
+
+        let codes = huffman::canonical_codes(&self.code_length_lengths);
+        for &(symbol, extra) in &self.encoded_lengths {
+            writer.write_code(
+                codes[symbol as usize],
+                self.code_length_lengths[symbol as usize],
+            );
+            writer.write_bits(extra as u32, code_length_extra_bits(symbol) as u32);
+        }
+    }
+}
+
+fn last_used(lengths: &[u8]) -> usize {
+    lengths.iter().rposition(|&length| length != 0).unwrap_or(0)
+}
+
+fn code_length_extra_bits(symbol: u8) -> u8 {
+    match symbol {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0,
+    }
+}
+
+/// Compresses a sequence of code lengths with the code length alphabet:
+///
+/// - `0..=15`: code length
+/// - `16`: copy the previous code length 3-6 times (2 extra bits)
+/// - `17`: repeat a code length of 0 for 3-10 times (3 extra bits)
+/// - `18`: repeat a code length of 0 for 11-138 times (7 extra bits)
+fn run_length_encode(lengths: &[u8]) -> Vec<(u8, u8)> {
+    let mut encoded = Vec::new();
+    let mut i = 0;
+
+    while i < lengths.len() {
+        let length = lengths[i];
+        let mut run = lengths[i..].iter().take_while(|&&l| l == length).count();
+        i += run;
+
+        if length == 0 {
+            while run >= 11 {
+                let repeat = run.min(138);
+                encoded.push((18, (repeat - 11) as u8));
+                run -= repeat;
+            }
+            if run >= 3 {
+                encoded.push((17, (run - 3) as u8));
+                run = 0;
+            }
+        } else {
+            encoded.push((length, 0));
+            run -= 1;
+            while run >= 3 {
+                let repeat = run.min(6);
+                encoded.push((16, (repeat - 3) as u8));
+                run -= repeat;
+            }
+        }
+
+        encoded.extend(std::iter::repeat_n((length, 0), run));
+    }
+
+    encoded
+}
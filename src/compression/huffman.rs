@@ -0,0 +1,264 @@
+//! Huffman codes replace the symbols of an alphabet by prefix codes of different sizes, giving
+//! shorter codes to the most frequent symbols.
+//!
+//! DEFLATE only stores the length of the code of each symbol, since the codes themselves are
+//! rebuilt with the canonical rules (see `canonical_codes`). The lengths are also limited: 15 bits
+//! for the literal/length and distance alphabets and 7 bits for the code length alphabet.
+
+use super::bits::{reverse_bits, BitReader};
+use std::{cmp::Reverse, collections::BinaryHeap, io};
+
+pub const MAX_CODE_LENGTH: u8 = 15;
+
+/// Computes the code length of each symbol given their frequencies, so that no code is longer than
+/// `max_length`. Symbols with frequency 0 get length 0 (no code).
+///
+/// First the greedy algorithm is used: take the two minimum weighted nodes and merge them into a
+/// new one whose weight is the sum of both, until only one node remains. The depth of each leaf is
+/// the code length of its symbol.
+///
+/// If some code is longer than `max_length`, the lengths are adjusted by moving the longest leaves
+/// up and splitting shorter ones until the code is complete again (the same approach as miniz).
+///
+/// If there is only one used symbol, another one gets a code too so that the code is complete.
+pub fn code_lengths(frequencies: &[u32], max_length: u8) -> Vec<u8> {
+    let mut lengths = vec![0; frequencies.len()];
+
+    let used: Vec<usize> = (0..frequencies.len())
+        .filter(|&symbol| frequencies[symbol] > 0)
+        .collect();
+
+    match used.len() {
+        0 => return lengths,
+        1 => {
+            lengths[used[0]] = 1;
+            lengths[if used[0] == 0 { 1 } else { 0 }] = 1;
+            return lengths;
+        }
+        _ => {}
+    }
+
+    // Leaves are `0..used.len()`, internal nodes are added after them
+    let mut parents = vec![0; 2 * used.len() - 1];
+    let mut heap: BinaryHeap<_> = used
+        .iter()
+        .enumerate()
+        .map(|(node, &symbol)| Reverse((frequencies[symbol] as u64, node)))
+        .collect();
+
+    let mut next_node = used.len();
+    while heap.len() > 1 {
+        let Reverse((weight_a, node_a)) = heap.pop().unwrap();
+        let Reverse((weight_b, node_b)) = heap.pop().unwrap();
+
+        parents[node_a] = next_node;
+        parents[node_b] = next_node;
+        heap.push(Reverse((weight_a + weight_b, next_node)));
+        next_node += 1;
+    }
+
+    // Parents are always created after their children, so go from the root down
+    let root = parents.len() - 1;
+    let mut depths = vec![0_usize; parents.len()];
+    for node in (0..root).rev() {
+        depths[node] = depths[parents[node]] + 1;
+    }
+
+    let max_depth = depths[..used.len()].iter().copied().max().unwrap();
+    if max_depth <= max_length as usize {
+        for (node, &symbol) in used.iter().enumerate() {
+            lengths[symbol] = depths[node] as u8;
+        }
+        return lengths;
+    }
+
+    // Number of codes of each length, the overflowing ones are clamped to `max_length`
+    let max_length = max_length as usize;
+    let mut length_count = vec![0_u32; max_length + 1];
+    for &depth in &depths[..used.len()] {
+        length_count[depth.min(max_length)] += 1;
+    }
+
+    // Kraft sum scaled by 2^max_length: it must be exactly 2^max_length for a complete code
+    let mut total: u32 = (1..=max_length)
+        .map(|length| length_count[length] << (max_length - length))
+        .sum();
+
+    while total != 1 << max_length {
+        length_count[max_length] -= 1;
+        for length in (1..max_length).rev() {
+            if length_count[length] != 0 {
+                length_count[length] -= 1;
+                length_count[length + 1] += 2;
+                break;
+            }
+        }
+        total -= 1;
+    }
+
+    // Give the shortest codes to the most frequent symbols
+    let mut sorted = used;
+    sorted.sort_by_key(|&symbol| Reverse(frequencies[symbol]));
+
+    let mut symbols = sorted.into_iter();
+    for (length, &count) in length_count.iter().enumerate().skip(1) {
+        for symbol in symbols.by_ref().take(count as usize) {
+            lengths[symbol] = length as u8;
+        }
+    }
+
+    lengths
+}
+
+/// Generates the codes given the length of each one, following these two rules:
+///
+/// - Shorter codes lexicographically precede longer codes.
+/// - All codes of a given bit length have lexicographically consecutive values, in the same order
+///   as the symbols they represent.
+///
+/// Symbols with length 0 do not get a code.
+pub fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+    let max_length = lengths.iter().copied().max().unwrap_or(0) as usize;
+
+    // 1. Count the number of codes for each code length
+    let mut length_count = vec![0_u32; max_length + 1];
+    for &length in lengths.iter().filter(|&&length| length > 0) {
+        length_count[length as usize] += 1;
+    }
+
+    // 2. Find the numerical value of the smallest code for each code length
+    let mut next_code = vec![0_u32; max_length + 1];
+    let mut code = 0;
+    for bits in 1..=max_length {
+        code = (code + length_count[bits - 1]) << 1;
+        next_code[bits] = code;
+    }
+
+    // 3. Assign consecutive values to all codes of the same length
+    lengths
+        .iter()
+        .map(|&length| {
+            if length == 0 {
+                return 0;
+            }
+            let code = next_code[length as usize];
+            next_code[length as usize] += 1;
+            code as u16
+        })
+        .collect()
+}
+
+/// Decodes symbols using a lookup table indexed by the next `max_length` bits of the input (in the
+/// order they are read). Each entry stores the symbol and the length of its code, so that all the
+/// entries whose index starts with the same code point to the same symbol.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    /// `symbol << 4 | length`, a length of 0 marks bits that are not a valid code
+    table: Vec<u16>,
+    max_length: u8,
+}
+
+impl Decoder {
+    /// Builds the decoder from the code lengths. Incomplete codes are allowed (some sequences of
+    /// bits are not a valid code), but not codes that have too many symbols for their lengths.
+    pub fn new(lengths: &[u8]) -> io::Result<Self> {
+        let max_length = lengths.iter().copied().max().unwrap_or(0);
+
+        let kraft_sum: u32 = lengths
+            .iter()
+            .filter(|&&length| length > 0)
+            .map(|&length| 1 << (MAX_CODE_LENGTH - length))
+            .sum();
+        if kraft_sum > 1 << MAX_CODE_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Over-subscribed Huffman code",
+            ));
+        }
+
+        let mut table = vec![0; 1 << max_length];
+        let codes = canonical_codes(lengths);
+
+        for (symbol, (&length, &code)) in lengths.iter().zip(&codes).enumerate() {
+            if length == 0 {
+                continue;
+            }
+
+            let entry = (symbol as u16) << 4 | length as u16;
+            let first = reverse_bits(code, length) as usize;
+            for index in (first..table.len()).step_by(1 << length) {
+                table[index] = entry;
+            }
+        }
+
+        Ok(Self { table, max_length })
+    }
+
+    /// Reads the next symbol. Returns `Ok(None)` if more input is needed.
+    pub fn decode(&self, reader: &mut BitReader) -> io::Result<Option<u16>> {
+        let entry = self.table[reader.peek(self.max_length as u32) as usize];
+        let length = (entry & 0xF) as u32;
+
+        if length as usize > reader.available() {
+            return Ok(None);
+        }
+        if length == 0 {
+            if reader.available() < self.max_length as usize {
+                // The missing bits could complete a valid code
+                return Ok(None);
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid Huffman code",
+            ));
+        }
+
+        reader.consume(length);
+        Ok(Some(entry >> 4))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kraft_sum(lengths: &[u8]) -> f64 {
+        lengths
+            .iter()
+            .filter(|&&length| length > 0)
+            .map(|&length| 0.5_f64.powi(length as i32))
+            .sum()
+    }
+
+    #[test]
+    fn canonical_codes_test() {
+        // Example from the README: alphabet ABCDEFGH
+        let codes = canonical_codes(&[3, 3, 3, 3, 3, 2, 4, 4]);
+        assert_eq!(
+            codes,
+            vec![0b010, 0b011, 0b100, 0b101, 0b110, 0b00, 0b1110, 0b1111]
+        );
+    }
+
+    #[test]
+    fn code_lengths_test() {
+        // The most frequent symbol gets the shortest code
+        let lengths = code_lengths(&[10, 1, 1, 2, 0], 15);
+        assert_eq!(lengths, vec![1, 3, 3, 2, 0]);
+
+        // Only one symbol: the code must still be complete
+        let lengths = code_lengths(&[0, 0, 7], 15);
+        assert_eq!(lengths, vec![1, 0, 1]);
+
+        // Fibonacci frequencies generate the deepest possible tree
+        let mut frequencies = vec![1, 1];
+        for i in 2..30 {
+            frequencies.push(frequencies[i - 1] + frequencies[i - 2]);
+        }
+
+        let lengths = code_lengths(&frequencies, 7);
+        assert!(lengths.iter().all(|&length| (1..=7).contains(&length)));
+        assert_eq!(kraft_sum(&lengths), 1.0);
+        assert!(lengths[29] <= lengths[0]);
+    }
+}
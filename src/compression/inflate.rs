@@ -0,0 +1,462 @@
+//! DEFLATE decompressor, following the decoding algorithm of the README:
+//!
+//! ```text
+//! do
+//!    read block_header from input_stream
+//!    if stored with no compression
+//!       skip any remaining bits in current partially processed byte
+//!       read LEN and NLEN
+//!       copy LEN bytes of data to output
+//!    else
+//!       if compressed with dynamic Huffman codes
+//!          read representation of code trees
+//!       loop (until end_of_block code recognized)
+//!          decode literal/length value from input stream
+//!          if value < 256
+//!             copy value (literal byte) to output stream
+//!          else if value = end_of_block (256)
+//!             break
+//!          else (value = 257..285)
+//!             decode distance from input_stream
+//!             copy length bytes from distance bytes back in the output stream
+//!       end loop
+//! while not last_block
+//! ```
+//!
+//! The `Inflater` is incremental: the compressed data can be given in pieces of any size, even
+//! splitting a Huffman code, and the output can be limited. Each step (a block header, a symbol
+//! with its extra bits...) is only applied once all its bits are available; otherwise the reader
+//! goes back to the start of the step and waits for more input.
+
+use super::{
+    bits::BitReader,
+    deflate::{
+        fixed_literal_lengths, CODE_LENGTH_ORDER, DISTANCE_BASE, DISTANCE_EXTRA, END_OF_BLOCK,
+        FIXED_DISTANCE_LENGTHS, LENGTH_BASE, LENGTH_EXTRA, NUM_CODE_LENGTHS,
+    },
+    huffman::Decoder,
+    lz77::WINDOW_SIZE,
+};
+use std::io;
+
+#[derive(Debug, Clone)]
+enum State {
+    BlockHeader,
+    Stored {
+        remaining: usize,
+    },
+    Compressed {
+        literals: Decoder,
+        distances: Decoder,
+    },
+    Done,
+}
+
+#[derive(Debug, Clone)]
+pub struct Inflater {
+    state: State,
+    last_block: bool,
+    /// Compressed data not consumed yet
+    input: Vec<u8>,
+    /// Position in bits of the next bit to read from `input`
+    bit_position: usize,
+    /// Decompressed data: at least the last `WINDOW_SIZE` bytes, for the back references
+    history: Vec<u8>,
+}
+
+impl Default for Inflater {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Inflater {
+    pub fn new() -> Self {
+        Self {
+            state: State::BlockHeader,
+            last_block: false,
+            input: Vec::new(),
+            bit_position: 0,
+            history: Vec::new(),
+        }
+    }
+
+    /// Adds more compressed data.
+    pub fn feed(&mut self, input: &[u8]) {
+        // Forget the bytes already consumed
+        let consumed = self.bit_position / 8;
+        if consumed > 0 {
+            self.input.drain(..consumed);
+            self.bit_position -= 8 * consumed;
+        }
+
+        self.input.extend_from_slice(input);
+    }
+
+    /// Decompresses the data given so far, appending to `out` at most about `limit` bytes (a back
+    /// reference may go up to 258 bytes over). Stops earlier if more input is needed.
+    pub fn inflate(&mut self, out: &mut Vec<u8>, limit: usize) -> io::Result<()> {
+        let mut produced = 0;
+        while produced < limit {
+            let before = self.history.len();
+            let progress = self.step(limit - produced)?;
+            produced += self.history.len() - before;
+            out.extend_from_slice(&self.history[before..]);
+
+            if !progress {
+                break;
+            }
+        }
+
+        // Only keep the window
+        if self.history.len() > 2 * WINDOW_SIZE {
+            let discard = self.history.len() - WINDOW_SIZE;
+            self.history.drain(..discard);
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` once the final block has been decompressed.
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    /// Input given after the end of the DEFLATE stream (for example, the zlib checksum). Only
+    /// meaningful once `is_done`.
+    pub fn remaining_input(&self) -> &[u8] {
+        &self.input[self.bit_position.div_ceil(8).min(self.input.len())..]
+    }
+
+    /// Decodes part of the stream into `history`, producing about `limit` bytes. Returns `false` if
+    /// nothing could be done because more input is needed or the stream is over.
+    fn step(&mut self, limit: usize) -> io::Result<bool> {
+        let mut reader = BitReader::new(&self.input, self.bit_position);
+        let mut block_over = false;
+
+        let progress = match &mut self.state {
+            State::Done => false,
+
+            State::BlockHeader => match read_block_header(&mut reader)? {
+                Some((last_block, state)) => {
+                    self.last_block = last_block;
+                    self.state = state;
+                    true
+                }
+                None => false,
+            },
+
+            State::Stored { remaining } => {
+                let bytes = reader.read_bytes((*remaining).min(limit));
+                *remaining -= bytes.len();
+                self.history.extend_from_slice(bytes);
+
+                block_over = *remaining == 0;
+                block_over || !bytes.is_empty()
+            }
+
+            State::Compressed {
+                literals,
+                distances,
+            } => {
+                let start = self.history.len();
+
+                while self.history.len() - start < limit {
+                    let step_start = reader.position();
+                    match read_symbol(&mut reader, literals, distances, &mut self.history)? {
+                        Some(true) => {}
+                        Some(false) => {
+                            block_over = true;
+                            break;
+                        }
+                        None => {
+                            reader.set_position(step_start);
+                            break;
+                        }
+                    }
+                }
+
+                block_over || self.history.len() > start
+            }
+        };
+
+        self.bit_position = reader.position();
+        if block_over {
+            self.state = if self.last_block {
+                State::Done
+            } else {
+                State::BlockHeader
+            };
+        }
+
+        Ok(progress)
+    }
+}
+
+/// Decompresses a whole DEFLATE stream.
+pub fn inflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut inflater = Inflater::new();
+    inflater.feed(data);
+
+    let mut out = Vec::new();
+    inflater.inflate(&mut out, usize::MAX)?;
+
+    if !inflater.is_done() {
+        return Err(unexpected_end());
+    }
+    Ok(out)
+}
+
+fn unexpected_end() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "The compressed data ended unexpectedly",
+    )
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reads the 3-bit header (and the rest of the block header for stored and dynamic blocks).
+/// Returns `None` if there are not enough bits; the reader is then left where it was.
+fn read_block_header(reader: &mut BitReader) -> io::Result<Option<(bool, State)>> {
+    let start = reader.position();
+    let result = read_block_header_inner(reader)?;
+    if result.is_none() {
+        reader.set_position(start);
+    }
+    Ok(result)
+}
+
+fn read_block_header_inner(reader: &mut BitReader) -> io::Result<Option<(bool, State)>> {
+    let Some(header) = reader.read(3) else {
+        return Ok(None);
+    };
+    let last_block = header & 1 == 1;
+
+    let state = match header >> 1 {
+        // Non-compressed
+        0b00 => {
+            reader.align_to_byte();
+            let Some(lengths) = reader.read(32) else {
+                return Ok(None);
+            };
+
+            let (len, nlen) = (lengths & 0xFFFF, lengths >> 16);
+            if len != !nlen & 0xFFFF {
+                return Err(invalid("LEN and NLEN of a stored block do not match"));
+            }
+            State::Stored {
+                remaining: len as usize,
+            }
+        }
+
+        // Fixed Huffman codes
+        0b01 => State::Compressed {
+            literals: Decoder::new(&fixed_literal_lengths())?,
+            distances: Decoder::new(&FIXED_DISTANCE_LENGTHS)?,
+        },
+
+        // Dynamic Huffman codes
+        0b10 => match read_dynamic_codes(reader)? {
+            Some((literals, distances)) => State::Compressed {
+                literals,
+                distances,
+            },
+            None => return Ok(None),
+        },
+
+        _ => return Err(invalid("Reserved block type")),
+    };
+
+    Ok(Some((last_block, state)))
+}
+
+/// Reads the representation of the code trees of a dynamic block.
+fn read_dynamic_codes(reader: &mut BitReader) -> io::Result<Option<(Decoder, Decoder)>> {
+    let Some(counts) = reader.read(14) else {
+        return Ok(None);
+    };
+    let num_literals = (counts & 0x1F) as usize + 257;
+    let num_distances = ((counts >> 5) & 0x1F) as usize + 1;
+    let num_code_lengths = (counts >> 10) as usize + 4;
+
+    if num_literals > 286 || num_distances > 30 {
+        return Err(invalid("Too many literal/length or distance codes"));
+    }
+
+    let mut code_length_lengths = [0; NUM_CODE_LENGTHS];
+    for &symbol in &CODE_LENGTH_ORDER[..num_code_lengths] {
+        let Some(length) = reader.read(3) else {
+            return Ok(None);
+        };
+        code_length_lengths[symbol] = length as u8;
+    }
+    let code_length_decoder = Decoder::new(&code_length_lengths)?;
+
+    let mut lengths = Vec::with_capacity(num_literals + num_distances);
+    while lengths.len() < num_literals + num_distances {
+        let Some(symbol) = code_length_decoder.decode(reader)? else {
+            return Ok(None);
+        };
+
+        let (value, extra_bits, base) = match symbol {
+            0..=15 => {
+                lengths.push(symbol as u8);
+                continue;
+            }
+            16 => match lengths.last() {
+                Some(&previous) => (previous, 2, 3),
+                None => return Err(invalid("Repeated code length without a previous one")),
+            },
+            17 => (0, 3, 3),
+            _ => (0, 7, 11),
+        };
+
+        let Some(extra) = reader.read(extra_bits) else {
+            return Ok(None);
+        };
+        let repeat = base + extra as usize;
+        if lengths.len() + repeat > num_literals + num_distances {
+            return Err(invalid("Code lengths repeated past the end"));
+        }
+        lengths.extend(std::iter::repeat_n(value, repeat));
+    }
+
+    if lengths[END_OF_BLOCK] == 0 {
+        return Err(invalid("There is no code for the end of block"));
+    }
+
+    let literals = Decoder::new(&lengths[..num_literals])?;
+    let distances = Decoder::new(&lengths[num_literals..])?;
+    Ok(Some((literals, distances)))
+}
+
+/// Reads a literal or a back reference and writes it to `history`. Returns `Some(false)` at the end
+/// of the block and `None` if there are not enough bits (the reader may have been moved).
+fn read_symbol(
+    reader: &mut BitReader,
+    literals: &Decoder,
+    distances: &Decoder,
+    history: &mut Vec<u8>,
+) -> io::Result<Option<bool>> {
+    let Some(symbol) = literals.decode(reader)? else {
+        return Ok(None);
+    };
+    let symbol = symbol as usize;
+
+    if symbol < END_OF_BLOCK {
+        history.push(symbol as u8);
+        return Ok(Some(true));
+    }
+    if symbol == END_OF_BLOCK {
+        return Ok(Some(false));
+    }
+
+    let index = symbol - 257;
+    if index >= LENGTH_BASE.len() {
+        return Err(invalid("Invalid length code"));
+    }
+    let Some(extra) = reader.read(LENGTH_EXTRA[index] as u32) else {
+        return Ok(None);
+    };
+    let length = LENGTH_BASE[index] as usize + extra as usize;
+
+    let Some(index) = distances.decode(reader)? else {
+        return Ok(None);
+    };
+    let index = index as usize;
+    if index >= DISTANCE_BASE.len() {
+        return Err(invalid("Invalid distance code"));
+    }
+    let Some(extra) = reader.read(DISTANCE_EXTRA[index] as u32) else {
+        return Ok(None);
+    };
+    let distance = DISTANCE_BASE[index] as usize + extra as usize;
+
+    if distance > history.len() {
+        return Err(invalid("Distance too far back"));
+    }
+
+    // The copy may overlap with itself (length > distance), so go byte by byte
+    let start = history.len() - distance;
+    for i in 0..length {
+        history.push(history[start + i]);
+    }
+
+    Ok(Some(true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::{deflate::deflate, Compression};
+
+    fn sample_data() -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..200_000_u32 {
+            match i % 1000 {
+                0..=499 => data.push((i % 7) as u8),
+                500..=899 => data.push((i.wrapping_mul(2_654_435_761) >> 24) as u8),
+                _ => data.extend_from_slice(b"Blah blah blah blah blah!"),
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let data = sample_data();
+
+        for level in [
+            Compression::None,
+            Compression::Fast,
+            Compression::Default,
+            Compression::Best,
+        ] {
+            let compressed = deflate(&data, level);
+            assert_eq!(inflate(&compressed).unwrap(), data, "{:?}", level);
+        }
+
+        assert_eq!(
+            inflate(&deflate(&[], Compression::Default)).unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn incremental_test() {
+        let data = sample_data();
+        let compressed = deflate(&data, Compression::Default);
+
+        // Feed the data in small pieces and take the output in small pieces too
+        let mut inflater = Inflater::new();
+        let mut out = Vec::new();
+        for piece in compressed.chunks(7) {
+            inflater.feed(piece);
+            loop {
+                let before = out.len();
+                inflater.inflate(&mut out, 100).unwrap();
+                assert!(out.len() - before <= 100 + 258);
+                if out.len() == before {
+                    break;
+                }
+            }
+        }
+
+        assert!(inflater.is_done());
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn invalid_test() {
+        // Reserved block type
+        assert!(inflate(&[0b111]).is_err());
+        // Stored block with a wrong NLEN
+        assert!(inflate(&[1, 5, 0, 0, 0]).is_err());
+        // Truncated
+        let compressed = deflate(b"hello hello hello", Compression::Default);
+        assert!(inflate(&compressed[..compressed.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,223 @@
+//! LZ77 finds sequences of data that are repeated and replaces them with a pointer to the previous
+//! occurrence: a `<length, backward distance>` pair. DEFLATE limits lengths to `3..=258` and
+//! distances to `1..=32768` (the sliding window).
+//!
+//! Matches are found using hash chains: the three bytes starting at each position are hashed, and
+//! every position remembers the previous one with the same hash. Looking for a match is walking the
+//! chain of the current position until a long enough match is found or the chain is too long.
+
+pub const WINDOW_SIZE: usize = 1 << 15;
+pub const MIN_MATCH: usize = 3;
+pub const MAX_MATCH: usize = 258;
+
+const HASH_BITS: u32 = 15;
+const HASH_SIZE: usize = 1 << HASH_BITS;
+const NONE: u32 = u32::MAX;
+
+/// Length 3 matches that are this far away usually take more bits than the literals.
+const TOO_FAR: usize = 4096;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Token {
+    Literal(u8),
+    Match { length: u16, distance: u16 },
+}
+
+/// How hard the match finder looks for long matches.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MatchParams {
+    /// Maximum number of positions checked for each match
+    pub max_chain: usize,
+    /// Stop looking once a match of this length is found
+    pub nice_length: usize,
+    /// Lazy matching: before taking a match, check if the next position has a longer one
+    pub lazy: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchFinder {
+    params: MatchParams,
+    /// Last position with each hash
+    head: Vec<u32>,
+    /// Previous position with the same hash, indexed by position modulo the window size
+    prev: Vec<u32>,
+}
+
+impl MatchFinder {
+    pub fn new(params: MatchParams) -> Self {
+        Self {
+            params,
+            head: vec![NONE; HASH_SIZE],
+            prev: vec![NONE; WINDOW_SIZE],
+        }
+    }
+
+    /// Splits `data[start..end]` into tokens, using `data[..start]` as the window to search for
+    /// matches (only the last `WINDOW_SIZE` bytes are used). Matches may extend past `end` up to the
+    /// end of `data`, so the position where the tokens end is also returned.
+    pub fn tokenize(&mut self, data: &[u8], start: usize, end: usize) -> (Vec<Token>, usize) {
+        self.reset(data, start);
+
+        let mut tokens = Vec::with_capacity(end - start);
+        let mut pending = None;
+        let mut i = start;
+
+        while i < end {
+            let (length, distance) = match pending.take() {
+                Some(found) => found,
+                None => self.find(data, i),
+            };
+            self.insert(data, i);
+
+            if length < MIN_MATCH {
+                tokens.push(Token::Literal(data[i]));
+                i += 1;
+                continue;
+            }
+
+            if self.params.lazy && length < self.params.nice_length && i + 1 < end {
+                let next = self.find(data, i + 1);
+                if next.0 > length {
+                    tokens.push(Token::Literal(data[i]));
+                    pending = Some(next);
+                    i += 1;
+                    continue;
+                }
+            }
+
+            tokens.push(Token::Match {
+                length: length as u16,
+                distance: distance as u16,
+            });
+            for position in i + 1..i + length {
+                self.insert(data, position);
+            }
+            i += length;
+        }
+
+        (tokens, i)
+    }
+
+    /// Empties the hash chains and inserts the window before `start`.
+    pub fn reset(&mut self, data: &[u8], start: usize) {
+        self.head.fill(NONE);
+        self.prev.fill(NONE);
+
+        for position in start.saturating_sub(WINDOW_SIZE)..start {
+            self.insert(data, position);
+        }
+    }
+
+    /// Returns the longest `(length, distance)` match for `position`, or a length of 0 if there is
+    /// none. Positions before `position` must have been inserted already.
+    pub fn find(&self, data: &[u8], position: usize) -> (usize, usize) {
+        let max_length = MAX_MATCH.min(data.len() - position);
+        if max_length < MIN_MATCH {
+            return (0, 0);
+        }
+
+        let mut best = (0, 0);
+        let mut candidate = self.head[hash(data, position)];
+        let mut chain = self.params.max_chain;
+
+        while candidate != NONE && chain > 0 {
+            let candidate_pos = candidate as usize;
+            let distance = position - candidate_pos;
+            if distance > WINDOW_SIZE {
+                break;
+            }
+
+            // Quick rejection: the byte that would make this match the longest must be equal
+            if data[candidate_pos + best.0] == data[position + best.0] {
+                let length = data[candidate_pos..candidate_pos + max_length]
+                    .iter()
+                    .zip(&data[position..position + max_length])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+
+                if length > best.0 {
+                    best = (length, distance);
+                    if length >= self.params.nice_length || length == max_length {
+                        break;
+                    }
+                }
+            }
+
+            let previous = self.prev[candidate_pos % WINDOW_SIZE];
+            if previous == NONE || previous as usize >= candidate_pos {
+                break;
+            }
+            candidate = previous;
+            chain -= 1;
+        }
+
+        if best.0 < MIN_MATCH || (best.0 == MIN_MATCH && best.1 > TOO_FAR) {
+            (0, 0)
+        } else {
+            best
+        }
+    }
+
+    /// Finds every match length available at `position` with its shortest distance. Since the chain
+    /// is walked from the nearest position, each match longer than the previous ones is appended
+    /// to `found` as `(length, distance)`: any length up to `found[k].0` (and longer than the
+    /// previous one) can be encoded with distance `found[k].1`.
+    pub fn find_all(&self, data: &[u8], position: usize, found: &mut Vec<(u16, u16)>) {
+        let max_length = MAX_MATCH.min(data.len() - position);
+        if max_length < MIN_MATCH {
+            return;
+        }
+
+        let mut longest = MIN_MATCH - 1;
+        let mut candidate = self.head[hash(data, position)];
+        let mut chain = self.params.max_chain;
+
+        while candidate != NONE && chain > 0 {
+            let candidate_pos = candidate as usize;
+            let distance = position - candidate_pos;
+            if distance > WINDOW_SIZE {
+                break;
+            }
+
+            if data[candidate_pos + longest] == data[position + longest] {
+                let length = data[candidate_pos..candidate_pos + max_length]
+                    .iter()
+                    .zip(&data[position..position + max_length])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+
+                if length > longest {
+                    longest = length;
+                    found.push((length as u16, distance as u16));
+                    if length == max_length {
+                        break;
+                    }
+                }
+            }
+
+            let previous = self.prev[candidate_pos % WINDOW_SIZE];
+            if previous == NONE || previous as usize >= candidate_pos {
+                break;
+            }
+            candidate = previous;
+            chain -= 1;
+        }
+    }
+
+    /// Adds `position` to the hash chains.
+    pub fn insert(&mut self, data: &[u8], position: usize) {
+        if position + MIN_MATCH > data.len() {
+            return;
+        }
+
+        let hash = hash(data, position);
+        self.prev[position % WINDOW_SIZE] = self.head[hash];
+        self.head[hash] = position as u32;
+    }
+}
+
+fn hash(data: &[u8], position: usize) -> usize {
+    let bytes = &data[position..position + MIN_MATCH];
+    let value = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+    (value.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
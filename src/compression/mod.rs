@@ -0,0 +1,41 @@
+//! Implementation of the DEFLATE compression format (RFC 1951) and its zlib wrapper (RFC 1950),
+//! used by PNG to compress the image data and some ancillary chunks.
+//!
+//! A compressed data set consists of a series of blocks using a combination of LZ77 (module
+//! `lz77`) and Huffman codes (module `huffman`). See the `README.md` on this directory for a more
+//! detailed explanation of the format.
+
+pub mod adler;
+pub mod bits;
+pub mod deflate;
+pub mod huffman;
+pub mod inflate;
+pub mod lz77;
+pub mod squeeze;
+pub mod zlib;
+
+/// Trade-off between speed and compressed size.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Compression {
+    /// Only non-compressed blocks
+    None,
+    Fast,
+    #[default]
+    Default,
+    Best,
+    /// Optimal parsing and block splitting (see module `squeeze`). Much slower than `Best`, for
+    /// when only the size matters. Never chosen by `from_level`.
+    Exhaustive,
+}
+
+impl Compression {
+    /// Maps the classic zlib levels (0-9) to a `Compression`.
+    pub fn from_level(level: u8) -> Self {
+        match level {
+            0 => Compression::None,
+            1..=3 => Compression::Fast,
+            4..=6 => Compression::Default,
+            _ => Compression::Best,
+        }
+    }
+}
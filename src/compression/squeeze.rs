@@ -0,0 +1,408 @@
+//! Exhaustive compression (`Compression::Exhaustive`), following the ideas of Zopfli: spend as much
+//! time as needed looking for the tokens and blocks that take the fewest bits.
+//!
+//! - Optimal parsing (the squeeze): the usual LZ77 tokenization takes the longest match at each
+//!   position, but a shorter match (or a literal) is sometimes cheaper overall. Instead, each
+//!   position is a node of a graph, with an edge for the literal and another one for each possible
+//!   match length, weighted with its cost in bits. The tokens are then the shortest path from the
+//!   start to the end of the block. The costs depend on the Huffman codes, which depend on the
+//!   tokens, so this is repeated: the tokens of a pass give the statistics for the next one.
+//! - Block splitting: each block has its own Huffman codes, so splitting the data where the
+//!   statistics change saves bits, even with the cost of the extra headers. The split points are
+//!   searched on a first (lazy) tokenization, splitting the blocks while the estimated size goes
+//!   down.
+//!
+//! Every match of every position is searched only once and kept in memory, so the input is
+//! processed in pieces of `MASTER_BLOCK_SIZE` bytes.
+
+use super::{
+    deflate::{
+        data_cost, distance_index, fixed_literal_lengths, frequencies, length_index, DynamicCodes,
+        DISTANCE_EXTRA, FIXED_DISTANCE_LENGTHS, LENGTH_EXTRA, NUM_DISTANCES, NUM_LITERALS,
+    },
+    lz77::{MatchFinder, Token, MAX_MATCH, MIN_MATCH},
+};
+use std::ops::Range;
+
+/// Input bytes squeezed at once
+pub const MASTER_BLOCK_SIZE: usize = 1 << 20;
+
+/// Passes of the optimal parsing for each block
+const ITERATIONS: usize = 15;
+
+/// Maximum number of blocks each master block is split into
+const MAX_BLOCKS: usize = 15;
+
+/// Blocks with fewer tokens are not split
+const MIN_SPLIT_TOKENS: usize = 10;
+
+/// Candidate split points tried at each step of `find_minimum`
+const SPLIT_SAMPLES: usize = 9;
+
+/// Compresses `data[start..]`, using `data[..start]` as the window. Returns the tokens of each
+/// block and the range of `data` they represent.
+pub fn squeeze(
+    matcher: &mut MatchFinder,
+    data: &[u8],
+    start: usize,
+) -> Vec<(Vec<Token>, Range<usize>)> {
+    let (lazy_tokens, _) = matcher.tokenize(data, start, data.len());
+    let matches = Matches::new(matcher, data, start);
+
+    let mut points = split_points(&lazy_tokens);
+    points.push(lazy_tokens.len());
+
+    let mut blocks = Vec::with_capacity(points.len());
+    let mut first_token = 0;
+    let mut block_start = start;
+    for point in points {
+        let initial = &lazy_tokens[first_token..point];
+        let block_end = block_start + initial.iter().map(token_length).sum::<usize>();
+        let range = block_start..block_end;
+
+        blocks.push((
+            optimal_tokens(data, range.clone(), &matches, initial),
+            range,
+        ));
+        first_token = point;
+        block_start = block_end;
+    }
+
+    blocks
+}
+
+fn token_length(token: &Token) -> usize {
+    match *token {
+        Token::Literal(_) => 1,
+        Token::Match { length, .. } => length as usize,
+    }
+}
+
+/// Size in bits of the tokens as a block with fixed or dynamic Huffman codes, whichever is
+/// smaller.
+fn block_cost(tokens: &[Token]) -> u64 {
+    let (literal_freqs, distance_freqs) = frequencies(tokens);
+
+    let fixed = data_cost(
+        &literal_freqs,
+        &distance_freqs,
+        &fixed_literal_lengths(),
+        &FIXED_DISTANCE_LENGTHS,
+    );
+    let dynamic = DynamicCodes::new(&literal_freqs, &distance_freqs);
+    let dynamic = dynamic.header_cost()
+        + data_cost(
+            &literal_freqs,
+            &distance_freqs,
+            &dynamic.literal_lengths,
+            &dynamic.distance_lengths,
+        );
+
+    3 + fixed.min(dynamic)
+}
+
+/// Every match of each position of the input, as given by `MatchFinder::find_all`.
+struct Matches {
+    start: usize,
+    /// `found[offsets[i]..offsets[i + 1]]` are the matches of position `start + i`
+    offsets: Vec<u32>,
+    found: Vec<(u16, u16)>,
+}
+
+impl Matches {
+    fn new(matcher: &mut MatchFinder, data: &[u8], start: usize) -> Self {
+        let mut offsets = Vec::with_capacity(data.len() - start + 1);
+        let mut found = Vec::new();
+
+        matcher.reset(data, start);
+        offsets.push(0);
+        for position in start..data.len() {
+            matcher.find_all(data, position, &mut found);
+            matcher.insert(data, position);
+            offsets.push(found.len() as u32);
+        }
+
+        Self {
+            start,
+            offsets,
+            found,
+        }
+    }
+
+    fn at(&self, position: usize) -> &[(u16, u16)] {
+        let i = position - self.start;
+        &self.found[self.offsets[i] as usize..self.offsets[i + 1] as usize]
+    }
+}
+
+/// Cost in bits of each symbol (extra bits included), estimated from their frequencies as
+/// `log2(total / frequency)`. Unused symbols are given the cost of a symbol used once.
+struct SymbolCosts {
+    literals: [f64; NUM_LITERALS],
+    distances: [f64; NUM_DISTANCES],
+}
+
+impl SymbolCosts {
+    fn new(literal_freqs: &[u32], distance_freqs: &[u32]) -> Self {
+        fn entropy<const N: usize>(freqs: &[u32]) -> [f64; N] {
+            let total: u32 = freqs.iter().sum();
+            let log_total = (total.max(1) as f64).log2();
+            std::array::from_fn(|i| log_total - (freqs[i].max(1) as f64).log2())
+        }
+
+        let mut literals = entropy(literal_freqs);
+        for (cost, extra) in literals[257..].iter_mut().zip(LENGTH_EXTRA) {
+            *cost += extra as f64;
+        }
+        let mut distances = entropy(distance_freqs);
+        for (cost, extra) in distances.iter_mut().zip(DISTANCE_EXTRA) {
+            *cost += extra as f64;
+        }
+
+        Self {
+            literals,
+            distances,
+        }
+    }
+}
+
+/// Iterates the optimal parsing of `data[range]`, starting with the statistics of `initial`, and
+/// returns the tokens with the smallest block.
+fn optimal_tokens(
+    data: &[u8],
+    range: Range<usize>,
+    matches: &Matches,
+    initial: &[Token],
+) -> Vec<Token> {
+    let length_symbols: Vec<usize> = (0..=MAX_MATCH)
+        .map(|length| 257 + length_index(length.max(MIN_MATCH) as u16))
+        .collect();
+
+    let mut best_cost = block_cost(initial);
+    let mut best = initial.to_vec();
+
+    let (mut literal_freqs, mut distance_freqs) = frequencies(initial);
+    let mut last_cost = None;
+
+    for _ in 0..ITERATIONS {
+        let costs = SymbolCosts::new(&literal_freqs, &distance_freqs);
+        let tokens = shortest_path(data, range.clone(), matches, &costs, &length_symbols);
+        let cost = block_cost(&tokens);
+
+        let (new_literal_freqs, new_distance_freqs) = frequencies(&tokens);
+        if last_cost == Some(cost) {
+            // Stuck: mixing in the previous statistics may lead to a different path
+            for (freq, new) in literal_freqs.iter_mut().zip(new_literal_freqs) {
+                *freq = new + *freq / 2;
+            }
+            for (freq, new) in distance_freqs.iter_mut().zip(new_distance_freqs) {
+                *freq = new + *freq / 2;
+            }
+        } else {
+            literal_freqs = new_literal_freqs;
+            distance_freqs = new_distance_freqs;
+        }
+        last_cost = Some(cost);
+
+        if cost < best_cost {
+            best_cost = cost;
+            best = tokens;
+        }
+    }
+
+    best
+}
+
+/// Finds the cheapest tokens for `data[range]` with the given costs.
+///
+/// `cost[i]` is the cost of the cheapest way to encode the first `i` bytes, so the positions are
+/// visited in order relaxing the edges that leave them: `cost[i + 1]` with a literal and
+/// `cost[i + length]` with each match. Then the path is followed backwards from the end.
+fn shortest_path(
+    data: &[u8],
+    range: Range<usize>,
+    matches: &Matches,
+    costs: &SymbolCosts,
+    length_symbols: &[usize],
+) -> Vec<Token> {
+    let len = range.len();
+    let mut cost = vec![f64::INFINITY; len + 1];
+    // Length and distance of the token that reaches each position (length 1 for literals)
+    let mut step = vec![(0, 0); len + 1];
+    cost[0] = 0.0;
+
+    for i in 0..len {
+        let here = cost[i];
+        let position = range.start + i;
+
+        let literal = here + costs.literals[data[position] as usize];
+        if literal < cost[i + 1] {
+            cost[i + 1] = literal;
+            step[i + 1] = (1, 0);
+        }
+
+        // Each length is encoded with the shortest distance that reaches it
+        let max_length = len - i;
+        let mut length = MIN_MATCH;
+        for &(longest, distance) in matches.at(position) {
+            let longest = (longest as usize).min(max_length);
+            let distance_cost = here + costs.distances[distance_index(distance)];
+
+            while length <= longest {
+                let candidate = distance_cost + costs.literals[length_symbols[length]];
+                if candidate < cost[i + length] {
+                    cost[i + length] = candidate;
+                    step[i + length] = (length as u16, distance);
+                }
+                length += 1;
+            }
+        }
+    }
+
+    let mut tokens = Vec::new();
+    let mut i = len;
+    while i > 0 {
+        let (length, distance) = step[i];
+        if length == 1 {
+            tokens.push(Token::Literal(data[range.start + i - 1]));
+        } else {
+            tokens.push(Token::Match { length, distance });
+        }
+        i -= length as usize;
+    }
+
+    tokens.reverse();
+    tokens
+}
+
+/// Returns the token indices where the blocks should be split, in order.
+///
+/// The biggest block that has not been tried yet is split at the point that minimizes the size of
+/// both halves, if that is smaller than the block alone, until there are `MAX_BLOCKS` blocks or
+/// none can be split.
+fn split_points(tokens: &[Token]) -> Vec<usize> {
+    let mut points = Vec::new();
+    let mut unsplittable = Vec::new();
+
+    while points.len() + 1 < MAX_BLOCKS {
+        let bounds: Vec<usize> = std::iter::once(0)
+            .chain(points.iter().copied())
+            .chain(std::iter::once(tokens.len()))
+            .collect();
+
+        let Some((start, end)) = bounds
+            .windows(2)
+            .map(|pair| (pair[0], pair[1]))
+            .filter(|&(start, end)| {
+                end - start >= MIN_SPLIT_TOKENS && !unsplittable.contains(&start)
+            })
+            .max_by_key(|&(start, end)| end - start)
+        else {
+            break;
+        };
+
+        let (point, split_cost) = find_minimum(
+            |point| block_cost(&tokens[start..point]) + block_cost(&tokens[point..end]),
+            start + 1,
+            end,
+        );
+
+        if split_cost < block_cost(&tokens[start..end]) {
+            let index = points.partition_point(|&p| p < point);
+            points.insert(index, point);
+        } else {
+            unsplittable.push(start);
+        }
+    }
+
+    points
+}
+
+/// Finds a point of `start..end` where `f` is (approximately) minimum: `f` is sampled at a few
+/// evenly spaced points, and the search continues between the neighbours of the best one.
+fn find_minimum(f: impl Fn(usize) -> u64, mut start: usize, mut end: usize) -> (usize, u64) {
+    let mut best = (start, f(start));
+
+    while end - start > SPLIT_SAMPLES {
+        let samples: Vec<usize> = (1..=SPLIT_SAMPLES)
+            .map(|k| start + k * (end - start) / (SPLIT_SAMPLES + 1))
+            .collect();
+        let values: Vec<u64> = samples.iter().map(|&point| f(point)).collect();
+
+        let (k, &value) = values
+            .iter()
+            .enumerate()
+            .min_by_key(|&(_, value)| value)
+            .unwrap();
+        if value >= best.1 {
+            break;
+        }
+
+        best = (samples[k], value);
+        if k > 0 {
+            start = samples[k - 1];
+        }
+        if k + 1 < SPLIT_SAMPLES {
+            end = samples[k + 1];
+        }
+    }
+
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::{deflate::deflate, inflate::inflate, Compression};
+
+    #[test]
+    fn smaller_test() {
+        // Text, followed by something close to a filtered image row
+        let mut data = Vec::new();
+        for i in 0..400_u32 {
+            data.extend_from_slice(format!("Line {} of {}, blah blah ", i, i * 7 % 13).as_bytes());
+        }
+        for i in 0..20_000_u32 {
+            data.push(((i % 251) as u8 / 16).wrapping_mul((i / 997) as u8 % 3));
+        }
+
+        let best = deflate(&data, Compression::Best);
+        let exhaustive = deflate(&data, Compression::Exhaustive);
+        assert_eq!(inflate(&exhaustive).unwrap(), data);
+        assert!(
+            exhaustive.len() < best.len(),
+            "{} >= {}",
+            exhaustive.len(),
+            best.len()
+        );
+
+        assert_eq!(
+            inflate(&deflate(b"abc", Compression::Exhaustive)).unwrap(),
+            b"abc"
+        );
+    }
+
+    #[test]
+    fn edge_cases_test() {
+        let mut state = 0x1234_5678_u32;
+        let random: Vec<u8> = (0..5000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+
+        // Runs longer than the longest match, and data that does not compress, which must not take
+        // much more than stored blocks
+        for data in [&b""[..], b"a", &[0; 1000], &random] {
+            let compressed = deflate(data, Compression::Exhaustive);
+            assert_eq!(inflate(&compressed).unwrap(), data);
+            assert!(compressed.len() <= data.len() + 5 * (1 + data.len() / 65535));
+        }
+
+        // The streams are checked like any other
+        let compressed = deflate(&random, Compression::Exhaustive);
+        assert!(inflate(&compressed[..compressed.len() / 2]).is_err());
+    }
+}
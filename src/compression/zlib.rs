@@ -0,0 +1,321 @@
+//! The zlib format wraps a DEFLATE stream with a small header and an Adler-32 checksum of the
+//! uncompressed data:
+//!
+//! - CMF (1 byte): compression method (8, deflate) and window size (7, 32K window).
+//! - FLG (1 byte): compression level used and a check value so that `CMF * 256 + FLG` is a
+//!   multiple of 31. The preset dictionary bit is never set for PNG.
+//! - DEFLATE compressed data
+//! - Adler-32 (4 bytes, big-endian)
+//!
+//! This is the format of the image data (IDAT) and of the compressed ancillary chunks.
+
+use super::{adler::Adler32, deflate::Deflater, inflate::Inflater, Compression};
+use std::io;
+
+const CMF: u8 = 0x78;
+
+/// Incremental zlib compressor, see `Deflater`.
+#[derive(Debug, Clone)]
+pub struct ZlibEncoder {
+    deflater: Deflater,
+    adler: Adler32,
+    level: Compression,
+    header_written: bool,
+}
+
+impl ZlibEncoder {
+    pub fn new(level: Compression) -> Self {
+        Self {
+            deflater: Deflater::new(level),
+            adler: Adler32::new(),
+            level,
+            header_written: false,
+        }
+    }
+
+    pub fn write(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        self.write_header(out);
+        self.adler.update(data);
+        self.deflater.write(data, out);
+    }
+
+    /// Sync flush: everything written so far can be decompressed.
+    pub fn flush(&mut self, out: &mut Vec<u8>) {
+        self.write_header(out);
+        self.deflater.flush(out);
+    }
+
+    pub fn finish(mut self, out: &mut Vec<u8>) {
+        self.write_header(out);
+        self.deflater.finish(out);
+        out.extend_from_slice(&self.adler.finalize().to_be_bytes());
+    }
+
+    fn write_header(&mut self, out: &mut Vec<u8>) {
+        if self.header_written {
+            return;
+        }
+
+        out.extend_from_slice(&header(self.level));
+        self.header_written = true;
+    }
+}
+
+/// The CMF and FLG bytes for the compression level.
+fn header(level: Compression) -> [u8; 2] {
+    let level: u8 = match level {
+        Compression::None => 0,
+        Compression::Fast => 1,
+        Compression::Default => 2,
+        Compression::Best | Compression::Exhaustive => 3,
+    };
+
+    let flg = level << 6;
+    let check = 31 - ((CMF as u16 * 256 + flg as u16) % 31) as u8;
+    [CMF, flg | (check % 31)]
+}
+
+/// Compresses all `data` at once into a zlib stream.
+pub fn compress(data: &[u8], level: Compression) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut encoder = ZlibEncoder::new(level);
+    encoder.write(data, &mut out);
+    encoder.finish(&mut out);
+    out
+}
+
+/// A piece of a zlib stream compressed on its own, see `compress_piece`.
+#[derive(Debug, Clone)]
+pub struct Piece {
+    /// DEFLATE blocks, without the zlib header
+    pub deflate: Vec<u8>,
+    /// Checksum of the uncompressed data of this piece
+    pub adler: Adler32,
+}
+
+/// Compresses `data` as one piece of a zlib stream, so that the pieces can be compressed in
+/// parallel and then joined with `join_pieces`. All but the `last` piece end with a sync flush, so
+/// they finish on a byte boundary and can be concatenated.
+///
+/// Each piece starts with an empty window: matches cannot reach into the previous piece, which
+/// makes the result slightly bigger than compressing everything at once.
+pub fn compress_piece(data: &[u8], level: Compression, last: bool) -> Piece {
+    let mut deflate = Vec::new();
+    let mut deflater = Deflater::new(level);
+    deflater.write(data, &mut deflate);
+    if last {
+        deflater.finish(&mut deflate);
+    } else {
+        deflater.flush(&mut deflate);
+    }
+
+    let mut adler = Adler32::new();
+    adler.update(data);
+
+    Piece { deflate, adler }
+}
+
+/// Joins the pieces, in order, into a single zlib stream. The checksum of the whole data is
+/// calculated combining the checksums of the pieces.
+pub fn join_pieces(pieces: impl IntoIterator<Item = Piece>, level: Compression) -> Vec<u8> {
+    let mut out = header(level).to_vec();
+    let mut adler = Adler32::new();
+
+    for piece in pieces {
+        out.extend_from_slice(&piece.deflate);
+        adler.combine(&piece.adler);
+    }
+
+    out.extend_from_slice(&adler.finalize().to_be_bytes());
+    out
+}
+
+/// Incremental zlib decompressor, see `Inflater`. The header is checked as soon as it arrives and
+/// the Adler-32 checksum once the whole stream has been decompressed.
+#[derive(Debug, Clone, Default)]
+pub struct ZlibDecoder {
+    inflater: Inflater,
+    /// First bytes of the stream, until the 2-byte header is complete
+    header: Vec<u8>,
+    adler: Adler32,
+    checked: bool,
+}
+
+impl ZlibDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds more compressed data.
+    pub fn feed(&mut self, mut input: &[u8]) -> io::Result<()> {
+        if self.header.len() < 2 {
+            let needed = (2 - self.header.len()).min(input.len());
+            self.header.extend_from_slice(&input[..needed]);
+            input = &input[needed..];
+
+            if self.header.len() == 2 {
+                check_header(self.header[0], self.header[1])?;
+            }
+        }
+
+        self.inflater.feed(input);
+        Ok(())
+    }
+
+    /// Decompresses the data given so far, appending to `out` about `limit` bytes at most (see
+    /// `Inflater::inflate`).
+    pub fn decompress(&mut self, out: &mut Vec<u8>, limit: usize) -> io::Result<()> {
+        if self.header.len() < 2 {
+            return Ok(());
+        }
+
+        let start = out.len();
+        self.inflater.inflate(out, limit)?;
+        self.adler.update(&out[start..]);
+
+        if self.inflater.is_done() && !self.checked {
+            if let Some(checksum) = self.inflater.remaining_input().get(..4) {
+                let checksum = u32::from_be_bytes(checksum.try_into().unwrap());
+                let calculated = self.adler.finalize();
+                if checksum != calculated {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "The Adler-32 checksums do not match: read {}, calculated {}",
+                            checksum, calculated
+                        ),
+                    ));
+                }
+                self.checked = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` once all the data has been decompressed and the checksum verified.
+    pub fn is_done(&self) -> bool {
+        self.checked
+    }
+}
+
+/// Decompresses a whole zlib stream.
+pub fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    decompress_limited(data, usize::MAX)
+}
+
+/// Decompresses a whole zlib stream, failing if it is bigger than `limit` bytes. A few bytes can
+/// decompress to gigabytes, so the data of a file should always be decompressed with a limit.
+pub fn decompress_limited(data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+    let mut decoder = ZlibDecoder::new();
+    decoder.feed(data)?;
+
+    let mut out = Vec::new();
+    decoder.decompress(&mut out, limit.saturating_add(1))?;
+
+    if out.len() > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("The zlib stream decompresses to more than {} bytes", limit),
+        ));
+    }
+    if !decoder.is_done() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "The zlib stream ended unexpectedly",
+        ));
+    }
+    Ok(out)
+}
+
+fn check_header(cmf: u8, flg: u8) -> io::Result<()> {
+    let invalid = |message: &str| Err(io::Error::new(io::ErrorKind::InvalidData, message));
+
+    if cmf & 0x0F != 8 || cmf >> 4 > 7 {
+        return invalid("Unknown zlib compression method or window size");
+    }
+    if !(cmf as u16 * 256 + flg as u16).is_multiple_of(31) {
+        return invalid("Invalid zlib header check value");
+    }
+    if flg & (1 << 5) != 0 {
+        return invalid("zlib preset dictionaries are not allowed");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_test() {
+        let data: Vec<u8> = (0..100_000_u32)
+            .map(|i| (i.wrapping_mul(i) >> 9) as u8)
+            .collect();
+        let compressed = compress(&data, Compression::Default);
+        assert_eq!(decompress(&compressed).unwrap(), data);
+
+        // Corrupted checksum
+        let mut corrupted = compressed.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(decompress(&corrupted).is_err());
+
+        // Invalid header
+        assert!(decompress(&[0x78, 0x00]).is_err());
+
+        // Limited to the exact size, or less
+        assert_eq!(decompress_limited(&compressed, data.len()).unwrap(), data);
+        let error = decompress_limited(&compressed, data.len() - 1).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let bomb = compress(&vec![0; 1 << 20], Compression::Best);
+        assert!(decompress_limited(&bomb, 1000).is_err());
+    }
+
+    #[test]
+    fn pieces_test() {
+        let data: Vec<u8> = (0..50_000_u32)
+            .map(|i| ((i % 251) ^ (i / 97)) as u8)
+            .collect();
+
+        for level in [Compression::None, Compression::Fast, Compression::Best] {
+            let pieces: Vec<_> = data.chunks(12_000).collect();
+            let joined = join_pieces(
+                pieces
+                    .iter()
+                    .enumerate()
+                    .map(|(i, piece)| compress_piece(piece, level, i == pieces.len() - 1)),
+                level,
+            );
+            assert_eq!(decompress(&joined).unwrap(), data);
+            assert_eq!(joined[..2], compress(&data, level)[..2]);
+        }
+    }
+
+    #[test]
+    fn checksum_test() {
+        let data = b"Some text that is stored as it is";
+        let compressed = compress(data, Compression::None);
+
+        // A changed byte in stored data is only found by the checksum
+        let mut corrupted = compressed.clone();
+        corrupted[10] ^= 1;
+        let error = decompress(&corrupted).unwrap_err();
+        assert!(error.to_string().contains("Adler-32"), "{}", error);
+
+        // Missing part of the checksum
+        let error = decompress(&compressed[..compressed.len() - 2]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        // The checksum arriving one byte at a time
+        let mut decoder = ZlibDecoder::new();
+        let mut out = Vec::new();
+        for byte in &compressed {
+            assert!(!decoder.is_done());
+            decoder.feed(&[*byte]).unwrap();
+            decoder.decompress(&mut out, usize::MAX).unwrap();
+        }
+        assert!(decoder.is_done());
+        assert_eq!(out, data);
+    }
+}
@@ -0,0 +1,409 @@
+//! Generic CRC engine for any width up to 64 bits. The CRC of PNG (`png::crc`) is just one of many:
+//! each format picks its own, and they are described with the parameters of the Rocksoft model
+//! (see "A Painless Guide to CRC Error Detection Algorithms", by Ross Williams):
+//!
+//! - width: number of bits of the CRC.
+//! - poly: generator polynomial, without the highest term (`x^width`), with the most significant
+//!   bit as the highest power.
+//! - init: initial value of the register.
+//! - refin: whether the bits of each input byte are reflected, so the least significant bit goes
+//!   first.
+//! - refout: whether the final register is reflected.
+//! - xorout: value XORed to the final register.
+//! - check: the CRC of the ASCII string `123456789`, to check an implementation.
+//!
+//! The engine uses a 256-entry table, like `png::crc`. When the input is reflected the whole
+//! algorithm works on reflected values (so the register shifts right), otherwise the register is
+//! aligned to the most significant bit of a `u64` and shifts left, which also works for widths
+//! smaller than 8.
+//!
+//! The parameters of the catalog come from the CRC RevEng catalogue:
+//! https://reveng.sourceforge.io/crc-catalogue/all.htm
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Params {
+    pub name: &'static str,
+    pub width: u8,
+    pub poly: u64,
+    pub init: u64,
+    pub refin: bool,
+    pub refout: bool,
+    pub xorout: u64,
+    pub check: u64,
+}
+
+/// Used by USB token packets.
+pub const CRC_5_USB: Params = Params {
+    name: "CRC-5/USB",
+    width: 5,
+    poly: 0x05,
+    init: 0x1F,
+    refin: true,
+    refout: true,
+    xorout: 0x1F,
+    check: 0x19,
+};
+
+/// Used by MultiMediaCards and SD cards.
+pub const CRC_7_MMC: Params = Params {
+    name: "CRC-7/MMC",
+    width: 7,
+    poly: 0x09,
+    init: 0x00,
+    refin: false,
+    refout: false,
+    xorout: 0x00,
+    check: 0x75,
+};
+
+/// Known as CRC-8.
+pub const CRC_8_SMBUS: Params = Params {
+    name: "CRC-8/SMBUS",
+    width: 8,
+    poly: 0x07,
+    init: 0x00,
+    refin: false,
+    refout: false,
+    xorout: 0x00,
+    check: 0xF4,
+};
+
+/// Used by 1-Wire devices.
+pub const CRC_8_MAXIM_DOW: Params = Params {
+    name: "CRC-8/MAXIM-DOW",
+    width: 8,
+    poly: 0x31,
+    init: 0x00,
+    refin: true,
+    refout: true,
+    xorout: 0x00,
+    check: 0xA1,
+};
+
+/// Known as CRC-16 and CRC-IBM.
+pub const CRC_16_ARC: Params = Params {
+    name: "CRC-16/ARC",
+    width: 16,
+    poly: 0x8005,
+    init: 0x0000,
+    refin: true,
+    refout: true,
+    xorout: 0x0000,
+    check: 0xBB3D,
+};
+
+/// Known as CRC-16/CCITT-FALSE.
+pub const CRC_16_IBM_3740: Params = Params {
+    name: "CRC-16/IBM-3740",
+    width: 16,
+    poly: 0x1021,
+    init: 0xFFFF,
+    refin: false,
+    refout: false,
+    xorout: 0x0000,
+    check: 0x29B1,
+};
+
+/// Known as CRC-16/CCITT.
+pub const CRC_16_KERMIT: Params = Params {
+    name: "CRC-16/KERMIT",
+    width: 16,
+    poly: 0x1021,
+    init: 0x0000,
+    refin: true,
+    refout: true,
+    xorout: 0x0000,
+    check: 0x2189,
+};
+
+pub const CRC_16_MODBUS: Params = Params {
+    name: "CRC-16/MODBUS",
+    width: 16,
+    poly: 0x8005,
+    init: 0xFFFF,
+    refin: true,
+    refout: true,
+    xorout: 0x0000,
+    check: 0x4B37,
+};
+
+pub const CRC_16_XMODEM: Params = Params {
+    name: "CRC-16/XMODEM",
+    width: 16,
+    poly: 0x1021,
+    init: 0x0000,
+    refin: false,
+    refout: false,
+    xorout: 0x0000,
+    check: 0x31C3,
+};
+
+/// The CRC of PNG, zlib, gzip and Ethernet, known as CRC-32.
+pub const CRC_32_ISO_HDLC: Params = Params {
+    name: "CRC-32/ISO-HDLC",
+    width: 32,
+    poly: 0x04C1_1DB7,
+    init: 0xFFFF_FFFF,
+    refin: true,
+    refout: true,
+    xorout: 0xFFFF_FFFF,
+    check: 0xCBF4_3926,
+};
+
+/// Known as CRC-32C (Castagnoli).
+pub const CRC_32_ISCSI: Params = Params {
+    name: "CRC-32/ISCSI",
+    width: 32,
+    poly: 0x1EDC_6F41,
+    init: 0xFFFF_FFFF,
+    refin: true,
+    refout: true,
+    xorout: 0xFFFF_FFFF,
+    check: 0xE306_9283,
+};
+
+pub const CRC_32_BZIP2: Params = Params {
+    name: "CRC-32/BZIP2",
+    width: 32,
+    poly: 0x04C1_1DB7,
+    init: 0xFFFF_FFFF,
+    refin: false,
+    refout: false,
+    xorout: 0xFFFF_FFFF,
+    check: 0xFC89_1918,
+};
+
+pub const CRC_32_MPEG_2: Params = Params {
+    name: "CRC-32/MPEG-2",
+    width: 32,
+    poly: 0x04C1_1DB7,
+    init: 0xFFFF_FFFF,
+    refin: false,
+    refout: false,
+    xorout: 0x0000_0000,
+    check: 0x0376_E6E7,
+};
+
+pub const CRC_64_ECMA_182: Params = Params {
+    name: "CRC-64/ECMA-182",
+    width: 64,
+    poly: 0x42F0_E1EB_A9EA_3693,
+    init: 0x0000_0000_0000_0000,
+    refin: false,
+    refout: false,
+    xorout: 0x0000_0000_0000_0000,
+    check: 0x6C40_DF5F_0B49_7347,
+};
+
+pub const CRC_64_GO_ISO: Params = Params {
+    name: "CRC-64/GO-ISO",
+    width: 64,
+    poly: 0x0000_0000_0000_001B,
+    init: 0xFFFF_FFFF_FFFF_FFFF,
+    refin: true,
+    refout: true,
+    xorout: 0xFFFF_FFFF_FFFF_FFFF,
+    check: 0xB909_56C7_75A4_1001,
+};
+
+/// Used by the XZ format.
+pub const CRC_64_XZ: Params = Params {
+    name: "CRC-64/XZ",
+    width: 64,
+    poly: 0x42F0_E1EB_A9EA_3693,
+    init: 0xFFFF_FFFF_FFFF_FFFF,
+    refin: true,
+    refout: true,
+    xorout: 0xFFFF_FFFF_FFFF_FFFF,
+    check: 0x995D_C9BB_DF19_39FA,
+};
+
+pub const CATALOG: [Params; 16] = [
+    CRC_5_USB,
+    CRC_7_MMC,
+    CRC_8_SMBUS,
+    CRC_8_MAXIM_DOW,
+    CRC_16_ARC,
+    CRC_16_IBM_3740,
+    CRC_16_KERMIT,
+    CRC_16_MODBUS,
+    CRC_16_XMODEM,
+    CRC_32_ISO_HDLC,
+    CRC_32_ISCSI,
+    CRC_32_BZIP2,
+    CRC_32_MPEG_2,
+    CRC_64_ECMA_182,
+    CRC_64_GO_ISO,
+    CRC_64_XZ,
+];
+
+impl Params {
+    /// Finds a CRC of the catalog by its name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        CATALOG.into_iter().find(|params| params.name == name)
+    }
+}
+
+/// Reflects the lowest `width` bits.
+const fn reflect(value: u64, width: u8) -> u64 {
+    value.reverse_bits() >> (64 - width as u32)
+}
+
+/// Calculates the CRC described by its `Params`. It can be built at compile time:
+///
+/// ```
+/// use png::crc::{Engine, CRC_32_ISCSI};
+///
+/// static CRC_32C: Engine = Engine::new(CRC_32_ISCSI);
+/// assert_eq!(CRC_32C.checksum(b"123456789"), 0xE306_9283);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Engine {
+    params: Params,
+    table: [u64; 256],
+}
+
+impl Engine {
+    pub const fn new(params: Params) -> Self {
+        assert!(params.width >= 1 && params.width <= 64, "Invalid CRC width");
+
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut value;
+            let mut bit = 0;
+
+            if params.refin {
+                let poly = reflect(params.poly, params.width);
+                value = i as u64;
+                while bit < 8 {
+                    value = if value & 1 == 1 {
+                        (value >> 1) ^ poly
+                    } else {
+                        value >> 1
+                    };
+                    bit += 1;
+                }
+            } else {
+                let poly = params.poly << (64 - params.width as u32);
+                value = (i as u64) << 56;
+                while bit < 8 {
+                    value = if value >> 63 == 1 {
+                        (value << 1) ^ poly
+                    } else {
+                        value << 1
+                    };
+                    bit += 1;
+                }
+            }
+
+            table[i] = value;
+            i += 1;
+        }
+
+        Self { params, table }
+    }
+
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    /// Returns the CRC of `data`.
+    pub fn checksum(&self, data: &[u8]) -> u64 {
+        let mut digest = self.digest();
+        digest.update(data);
+        digest.finalize()
+    }
+
+    /// Starts a CRC calculation, to give the data in pieces.
+    pub fn digest(&self) -> Digest<'_> {
+        let params = &self.params;
+        let register = if params.refin {
+            reflect(params.init, params.width)
+        } else {
+            params.init << (64 - params.width as u32)
+        };
+
+        Digest {
+            engine: self,
+            register,
+        }
+    }
+}
+
+/// A CRC calculation in progress, see `Engine::digest`.
+#[derive(Debug, Clone)]
+pub struct Digest<'a> {
+    engine: &'a Engine,
+    register: u64,
+}
+
+impl Digest<'_> {
+    pub fn update(&mut self, data: &[u8]) {
+        let table = &self.engine.table;
+
+        if self.engine.params.refin {
+            for &byte in data {
+                let index = (self.register as u8 ^ byte) as usize;
+                // `checked_shr` because of widths smaller than 8
+                self.register = table[index] ^ self.register.checked_shr(8).unwrap_or(0);
+            }
+        } else {
+            for &byte in data {
+                let index = ((self.register >> 56) as u8 ^ byte) as usize;
+                self.register = table[index] ^ (self.register << 8);
+            }
+        }
+    }
+
+    /// Returns the CRC of all the data given so far.
+    pub fn finalize(&self) -> u64 {
+        let params = &self.engine.params;
+
+        let mut value = if params.refin {
+            self.register
+        } else {
+            self.register >> (64 - params.width as u32)
+        };
+        // The register is reflected if the input was
+        if params.refin != params.refout {
+            value = reflect(value, params.width);
+        }
+
+        value ^ params.xorout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_test() {
+        for params in CATALOG {
+            let engine = Engine::new(params);
+            assert_eq!(
+                engine.checksum(b"123456789"),
+                params.check,
+                "{}",
+                params.name
+            );
+
+            let mut digest = engine.digest();
+            digest.update(b"1234");
+            digest.update(b"");
+            digest.update(b"56789");
+            assert_eq!(digest.finalize(), params.check, "{}", params.name);
+        }
+    }
+
+    #[test]
+    fn png_test() {
+        let data: Vec<u8> = (0..1000_u32).map(|i| (i * 31 % 256) as u8).collect();
+        let engine = Engine::new(Params::from_name("CRC-32/ISO-HDLC").unwrap());
+        assert_eq!(
+            engine.checksum(&data),
+            crate::png::crc::Crc::new().calculate(&data) as u64
+        );
+    }
+}
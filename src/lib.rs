@@ -1,8 +1,21 @@
+pub mod compression;
+pub mod crc;
+pub mod netpbm;
 pub mod png;
-pub mod wav;
 
-pub use png::chunks::{Chunk, ImageHeader, ImageTrailer, IDAT, IEND, IHDR};
+pub use compression::Compression;
+pub use png::chunks::{
+    Background, Chromaticities, Chunk, ChunkType, Gamma, IccProfile, ImageData, ImageHeader,
+    ImageTrailer, Palette, StandardRgb, Transparency, BKGD, CHRM, GAMA, ICCP, IDAT, IEND, IHDR,
+    PLTE, SRGB, TRNS,
+};
+pub use png::color::{ColorType, ConvertOptions, Luminance};
+pub use png::colorspace::{ColorProfile, LinearImage};
+pub use png::decoder::{Row, StreamingDecoder};
+pub use png::encoder::{EncodeOptions, FilterStrategy, StreamingEncoder};
+pub use png::image::{Image, PixelData};
+pub use png::incremental::{DecodedRow, Event, IncrementalDecoder};
+pub use png::optimize::{OptimizeOptions, OptimizeReport};
+pub use png::quantize::{QuantizeMethod, QuantizeOptions};
+pub use png::strip::{Category, StripOptions};
 pub use png::Png;
-
-pub use wav::synth::{Oscillator, SawOsc, SinOsc, SqrOsc};
-pub use wav::{Wav, WavSamples};
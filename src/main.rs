@@ -1,36 +1,338 @@
-use png::{Oscillator, SawOsc, SinOsc, SqrOsc, Wav};
-use png::{Png, IDAT};
-use std::{env::args, path::Path};
-
-fn main() {
-    let file_type = args().nth(1).unwrap();
-    let file_name = args().nth(2).unwrap();
-
-    match &file_type[..] {
-        "png" => {
-            let png = Png::read(Path::new(&file_name)).unwrap();
-            for idat in png.chunks.iter().filter(|x| x.get_type() == IDAT) {
-                println!("{:?}", idat);
-            }
-        }
-
-        "wav" => {
-            let wav = Wav::read(Path::new(&file_name)).unwrap();
-            println!("{:?}", wav.data);
-        }
-
-        "sin-wav" => SinOsc::new(44100, 220.0, 64, 128)
-            .to_wav(5000)
-            .write(Path::new(&file_name))
-            .unwrap(),
-        "saw-wav" => SawOsc::new(44100, 220.0, 64, 128)
-            .to_wav(5000)
-            .write(Path::new(&file_name))
-            .unwrap(),
-        "sqr-wav" => SqrOsc::new(44100, 220.0, 0.5, 64, 128)
-            .to_wav(5000)
-            .write(Path::new(&file_name))
-            .unwrap(),
-        _ => println!("Unknown option: {}", file_type),
+use png::netpbm::{self, Format};
+use png::png::{diff, inspect};
+use png::{Background, ChunkType, ColorType, ConvertOptions, EncodeOptions, Image};
+use png::{Compression, OptimizeOptions, Png, StripOptions};
+use std::{
+    env,
+    io::{self, Write},
+    path::Path,
+    process::ExitCode,
+};
+
+const USAGE: &str = "Usage: png <command> <file> [options]
+
+Commands:
+  inspect <file> [--json]                 List the chunks of a PNG file
+  convert <input> <output> [--color=<grey|grey-alpha|rgb|rgba|indexed>] [--depth=<bits>]
+          [--interlace] [--level=<0-9>]
+                                          Convert between PNG and PGM, PPM or PAM (by extension).
+                                          Interlacing and level only apply to PNG output
+  extract <file> --type <type> [-o <output>] [--raw]
+                                          Write the payload of a chunk (decompressed unless
+                                          --raw) to a file or to the standard output
+  inject <file> --type <type> --data <file> [-o <output>]
+                                          Insert an ancillary chunk with the given data.
+                                          Without -o, the input file is overwritten
+  diff <a> <b> [-o <diff image>]          Compare the chunks and the pixels of two PNG files.
+                                          Exits with 1 if the pixels are different
+  optimize <input> [output] [--exhaustive]
+                                          Recompress a PNG file losslessly. Without an
+                                          output, the input file is overwritten
+  strip <input> [output] [--remove=<list>] [--keep=<list>]
+                                          Remove metadata chunks (by default text, time, exif
+                                          and private chunks). Without an output, the input
+                                          file is overwritten";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match run(&args) {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            if error.kind() == io::ErrorKind::InvalidInput {
+                eprintln!("\n{}", USAGE);
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &[String]) -> io::Result<ExitCode> {
+    let Some(command) = args.first() else {
+        return invalid_input("Missing command".to_string());
+    };
+    let rest = &args[1..];
+    let files = positional(rest);
+    let Some(&file_name) = files.first() else {
+        return invalid_input("Missing file".to_string());
+    };
+
+    match &command[..] {
+        "inspect" => {
+            let data = std::fs::read(file_name)?;
+            let inspection = inspect::inspect(&data)?;
+
+            if has_flag(rest, "--json") {
+                print!("{}", inspection.to_json());
+            } else {
+                print!("{}", inspection.to_table());
+            }
+        }
+
+        "convert" => {
+            let Some(output) = files.get(1) else {
+                return invalid_input("Missing output file".to_string());
+            };
+            let (input, output) = (Path::new(file_name), Path::new(output));
+            let format = Format::from_path(output);
+            if format.is_none() && !has_extension(output, "png") {
+                return invalid_input(format!("Unknown output format: {}", output.display()));
+            }
+
+            let (image, background) = if Format::from_path(input).is_some() {
+                (netpbm::read(&std::fs::read(input)?)?, None)
+            } else {
+                let png = Png::read(input)?;
+                (png.decode()?, png.chunk_of::<Background>().cloned())
+            };
+
+            let color_type = match option_value(rest, "--color") {
+                Some(name) => parse_color_type(name)?,
+                None => default_color_type(&image, format),
+            };
+            let bit_depth = match option_value(rest, "--depth") {
+                Some(depth) => depth
+                    .parse()
+                    .or_else(|_| invalid_input(format!("Invalid bit depth: {}", depth)))?,
+                // The same, or the closest one allowed for the color type
+                None => {
+                    let depths = color_type.bit_depths();
+                    *depths
+                        .iter()
+                        .find(|&&depth| depth >= image.bit_depth)
+                        .unwrap_or(&depths[depths.len() - 1])
+                }
+            };
+
+            let image = if (color_type.code(), bit_depth) == (image.color_type, image.bit_depth) {
+                image
+            } else {
+                let options = ConvertOptions {
+                    background,
+                    ..Default::default()
+                };
+                image.convert(color_type, bit_depth, &options)?
+            };
+
+            let size = match format {
+                Some(format) => {
+                    let data = netpbm::write(&image, format)?;
+                    std::fs::write(output, &data)?;
+                    data.len() as u64
+                }
+                None => {
+                    let mut options = EncodeOptions {
+                        interlace: has_flag(rest, "--interlace"),
+                        ..Default::default()
+                    };
+                    if let Some(level) = option_value(rest, "--level") {
+                        let level = level
+                            .parse()
+                            .or_else(|_| invalid_input(format!("Invalid level: {}", level)))?;
+                        options.compression = Compression::from_level(level);
+                    }
+                    Png::from_image(&image, &options)?.write(output)?
+                }
+            };
+
+            println!(
+                "{}x{}, color type {}, {}-bit -> {} bytes",
+                image.width, image.height, image.color_type, image.bit_depth, size
+            );
+        }
+
+        "extract" => {
+            let Some(chunk_type) = option_value(rest, "--type") else {
+                return invalid_input("Missing chunk type".to_string());
+            };
+            let png = Png::read(Path::new(file_name))?;
+            let data = png.extract(ChunkType::from_code(chunk_type)?, has_flag(rest, "--raw"))?;
+
+            match option_value(rest, "-o") {
+                Some(output) => std::fs::write(output, &data)?,
+                None => io::stdout().write_all(&data)?,
+            }
+        }
+
+        "inject" => {
+            let (Some(chunk_type), Some(data)) =
+                (option_value(rest, "--type"), option_value(rest, "--data"))
+            else {
+                return invalid_input("Missing chunk type or data file".to_string());
+            };
+            let data = std::fs::read(data)?;
+
+            let mut png = Png::read(Path::new(file_name))?;
+            png.inject(ChunkType::from_code(chunk_type)?, &data)?;
+
+            let output = option_value(rest, "-o").unwrap_or(file_name);
+            png.write(Path::new(output))?;
+            println!("Injected {} bytes", data.len());
+        }
+
+        "diff" => {
+            let Some(other) = files.get(1) else {
+                return invalid_input("Missing second file".to_string());
+            };
+            let (a, b) = (
+                Png::read(Path::new(file_name))?,
+                Png::read(Path::new(other))?,
+            );
+
+            let changes = diff::diff_chunks(&a, &b);
+            println!("Chunks: {} differences", changes.len());
+            for change in &changes {
+                println!("  {}", change);
+            }
+
+            let (old, new) = (a.decode()?, b.decode()?);
+            if (old.width, old.height) != (new.width, new.height) {
+                println!("Pixels: not compared, the sizes are different");
+                return Ok(ExitCode::FAILURE);
+            }
+
+            let pixels = diff::diff_pixels(&old, &new)?;
+            println!(
+                "Pixels: {} of {} different",
+                pixels.different, pixels.pixels
+            );
+            let [r, g, b, a] = pixels.max_delta;
+            println!(
+                "Max delta ({}-bit): R {}, G {}, B {}, A {}",
+                pixels.bit_depth, r, g, b, a
+            );
+            println!("PSNR: {:.2} dB", pixels.psnr);
+
+            if let Some(output) = option_value(rest, "-o") {
+                let highlighted = diff::highlight(&old, &new)?;
+                Png::from_image(&highlighted, &EncodeOptions::default())?
+                    .write(Path::new(output))?;
+            }
+
+            if pixels.different > 0 {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+
+        "optimize" => {
+            let mut options = OptimizeOptions::default();
+            if has_flag(rest, "--exhaustive") {
+                options.compressions.push(Compression::Exhaustive);
+            }
+
+            let png = Png::read(Path::new(file_name))?;
+            let (optimized, report) = png.optimize(&options)?;
+
+            optimized.write(Path::new(output(&files)))?;
+            println!(
+                "{} -> {} bytes ({} saved)",
+                report.original_size,
+                report.optimized_size,
+                report.saved()
+            );
+        }
+
+        "strip" => {
+            let mut options = match option_value(rest, "--remove") {
+                Some(list) => StripOptions::from_list(list)?,
+                None => StripOptions::privacy(),
+            };
+            if let Some(keep) = option_value(rest, "--keep") {
+                options.keep = StripOptions::from_list(keep)?.types;
+            }
+
+            let mut png = Png::read(Path::new(file_name))?;
+            let removed = png.strip(&options);
+
+            png.write(Path::new(output(&files)))?;
+            for chunk_type in removed {
+                println!("Removed {}", chunk_type.get_char_code().unwrap_or("????"));
+            }
+        }
+
+        _ => return invalid_input(format!("Unknown command: {}", command)),
     }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn invalid_input<T>(message: String) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidInput, message))
+}
+
+fn has_flag(options: &[String], flag: &str) -> bool {
+    options.iter().any(|option| option == flag)
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension()
+        .is_some_and(|found| found.eq_ignore_ascii_case(extension))
+}
+
+fn parse_color_type(name: &str) -> io::Result<ColorType> {
+    match name {
+        "grey" | "gray" => Ok(ColorType::Greyscale),
+        "grey-alpha" | "gray-alpha" => Ok(ColorType::GreyscaleAlpha),
+        "rgb" => Ok(ColorType::Truecolour),
+        "rgba" => Ok(ColorType::TruecolourAlpha),
+        "indexed" => Ok(ColorType::Indexed),
+        _ => invalid_input(format!("Unknown color type: {}", name)),
+    }
+}
+
+/// The color type of the image if the output format can store it. Otherwise, the closest one
+/// that keeps the colours and the transparency (but PGM and PPM have a single choice).
+fn default_color_type(image: &Image, format: Option<Format>) -> ColorType {
+    let color_type = ColorType::from_code(image.color_type).unwrap_or(ColorType::TruecolourAlpha);
+    let transparent = image.transparency.is_some();
+
+    match format {
+        None => color_type,
+        Some(Format::Pgm) => ColorType::Greyscale,
+        Some(Format::Ppm) => ColorType::Truecolour,
+        Some(Format::Pam) => match color_type {
+            ColorType::Greyscale if transparent => ColorType::GreyscaleAlpha,
+            ColorType::Truecolour | ColorType::Indexed if transparent => ColorType::TruecolourAlpha,
+            ColorType::Indexed => ColorType::Truecolour,
+            _ => color_type,
+        },
+    }
+}
+
+/// Options that take a value, which can be given as `--type=iCCP` or `--type iCCP`.
+const VALUE_OPTIONS: [&str; 8] = [
+    "--type", "--data", "-o", "--remove", "--keep", "--color", "--depth", "--level",
+];
+
+/// Value of an option like `--remove=text` or `--remove text`.
+fn option_value<'a>(options: &'a [String], name: &str) -> Option<&'a str> {
+    options.iter().enumerate().find_map(|(i, option)| {
+        match option.strip_prefix(name)?.strip_prefix('=') {
+            Some(value) => Some(value),
+            None if option == name => options.get(i + 1).map(String::as_str),
+            None => None,
+        }
+    })
+}
+
+/// The arguments that are neither options nor their values.
+fn positional(options: &[String]) -> Vec<&str> {
+    let mut files = Vec::new();
+    let mut options = options.iter();
+
+    while let Some(option) = options.next() {
+        if VALUE_OPTIONS.contains(&option.as_str()) {
+            options.next();
+        } else if !option.starts_with('-') {
+            files.push(option.as_str());
+        }
+    }
+
+    files
+}
+
+/// The second file, or the input file to overwrite it.
+fn output<'a>(files: &[&'a str]) -> &'a str {
+    files.get(1).unwrap_or(&files[0])
 }
@@ -0,0 +1,399 @@
+//! The Netpbm formats are the simplest raster formats: a short text header followed by the samples
+//! without any compression, so they are a convenient way to move pixels in and out of PNG files.
+//!
+//! ```text
+//! | Magic | Format           | Pixels                       | Samples               |
+//! |-------|------------------|------------------------------|-----------------------|
+//! | P2    | PGM (plain)      | grey                         | decimal numbers       |
+//! | P3    | PPM (plain)      | red, green, blue             | decimal numbers       |
+//! | P5    | PGM              | grey                         | binary                |
+//! | P6    | PPM              | red, green, blue             | binary                |
+//! | P7    | PAM              | 1 to 4 samples (TUPLTYPE)    | binary                |
+//! ```
+//!
+//! PGM and PPM headers are the magic number, the width, the height and the maximum value of a
+//! sample (MAXVAL), separated by whitespace and with `#` comments until the end of the line. A
+//! single whitespace character separates the header from binary samples. PAM headers are lines of
+//! `KEY value` instead, ending in `ENDHDR`:
+//!
+//! ```text
+//! P7
+//! WIDTH 227
+//! HEIGHT 149
+//! DEPTH 4
+//! MAXVAL 255
+//! TUPLTYPE RGB_ALPHA
+//! ENDHDR
+//! ```
+//!
+//! Binary samples take one byte if MAXVAL is below 256, and two bytes big-endian otherwise. When
+//! reading, MAXVAL is mapped to the smallest PNG bit depth that can hold it, and the samples are
+//! scaled if it is not exactly `2^bit_depth - 1`. When writing, MAXVAL is always
+//! `2^bit_depth - 1`, so PNG images are stored exactly. Only the binary formats are written, and
+//! PBM bitmaps (P1 and P4) are not supported.
+
+use crate::png::{
+    chunks::MAX_SIZE,
+    color::ColorType,
+    image::{Image, PixelData},
+};
+use std::{io, path::Path};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    /// Greyscale
+    Pgm,
+    /// Truecolour
+    Ppm,
+    /// Any color type except indexed-colour
+    Pam,
+}
+
+impl Format {
+    /// The format of a file with the extension `.pgm`, `.ppm` or `.pam`.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match &extension[..] {
+            "pgm" => Some(Format::Pgm),
+            "ppm" => Some(Format::Ppm),
+            "pam" => Some(Format::Pam),
+            _ => None,
+        }
+    }
+
+    /// Whether images of the color type can be stored in this format.
+    pub fn supports(self, color_type: ColorType) -> bool {
+        match self {
+            Format::Pgm => color_type == ColorType::Greyscale,
+            Format::Ppm => color_type == ColorType::Truecolour,
+            Format::Pam => color_type != ColorType::Indexed,
+        }
+    }
+}
+
+fn invalid<T>(message: String) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+/// Decodes a PGM, PPM or PAM file.
+pub fn read(data: &[u8]) -> io::Result<Image> {
+    let mut parser = Parser { data, pos: 2 };
+
+    let (width, height, maxval, color_type) = match data.get(..2) {
+        Some(b"P2" | b"P5") => parser.header(ColorType::Greyscale)?,
+        Some(b"P3" | b"P6") => parser.header(ColorType::Truecolour)?,
+        Some(b"P7") => parser.pam_header()?,
+        Some(b"P1" | b"P4") => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "PBM bitmaps are not supported",
+            ))
+        }
+        _ => return invalid("Not a Netpbm file".to_string()),
+    };
+
+    if !(1..=MAX_SIZE).contains(&width) || !(1..=MAX_SIZE).contains(&height) {
+        return invalid(format!("Invalid image size {}x{}", width, height));
+    }
+    if !(1..=65535).contains(&maxval) {
+        return invalid(format!("Invalid MAXVAL {}", maxval));
+    }
+
+    let Some(len) = (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|pixels| pixels.checked_mul(color_type.samples()))
+    else {
+        return invalid(format!("Image of {}x{} pixels is too big", width, height));
+    };
+    let samples = if data[1] == b'2' || data[1] == b'3' {
+        parser.plain_samples(len)?
+    } else {
+        parser.binary_samples(len, maxval)?
+    };
+
+    if let Some(sample) = samples.iter().find(|&&sample| sample as u32 > maxval) {
+        return invalid(format!(
+            "Sample {} is greater than MAXVAL {}",
+            sample, maxval
+        ));
+    }
+
+    // The smallest bit depth that can hold MAXVAL always exists, since 16 bits hold any
+    let bit_depth = *color_type
+        .bit_depths()
+        .iter()
+        .find(|&&depth| (1 << depth) > maxval)
+        .unwrap();
+    let max = (1 << bit_depth) - 1;
+    let scale = |sample: u16| ((sample as u32 * max + maxval / 2) / maxval) as u16;
+
+    let image = if bit_depth == 16 {
+        let data = samples.into_iter().map(scale).collect();
+        Image::new_16(width, height, color_type.code(), data)
+    } else {
+        let data = samples
+            .into_iter()
+            .map(|sample| scale(sample) as u8)
+            .collect();
+        Image::new(width, height, color_type.code(), bit_depth, data)
+    };
+
+    Ok(image)
+}
+
+/// Encodes the image in the given format, which must support its color type (see
+/// `Format::supports`). The tRNS chunk is ignored.
+pub fn write(image: &Image, format: Format) -> io::Result<Vec<u8>> {
+    let color_type = ColorType::from_code(image.color_type)
+        .filter(|&color_type| format.supports(color_type))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Images of color type {} cannot be stored as {:?}",
+                    image.color_type, format
+                ),
+            )
+        })?;
+
+    let maxval = (1_u32 << image.bit_depth) - 1;
+    let header = match format {
+        Format::Pgm => format!("P5\n{} {}\n{}\n", image.width, image.height, maxval),
+        Format::Ppm => format!("P6\n{} {}\n{}\n", image.width, image.height, maxval),
+        Format::Pam => {
+            let tuple_type = match color_type {
+                ColorType::GreyscaleAlpha => "GRAYSCALE_ALPHA",
+                ColorType::Truecolour => "RGB",
+                ColorType::TruecolourAlpha => "RGB_ALPHA",
+                _ => "GRAYSCALE",
+            };
+            format!(
+                "P7\nWIDTH {}\nHEIGHT {}\nDEPTH {}\nMAXVAL {}\nTUPLTYPE {}\nENDHDR\n",
+                image.width,
+                image.height,
+                color_type.samples(),
+                maxval,
+                tuple_type
+            )
+        }
+    };
+
+    let mut output = header.into_bytes();
+    match &image.data {
+        PixelData::U8(data) => output.extend_from_slice(data),
+        PixelData::U16(data) => output.extend(data.iter().flat_map(|sample| sample.to_be_bytes())),
+    }
+
+    Ok(output)
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// Next whitespace-separated token, skipping comments.
+    fn token(&mut self) -> io::Result<&'a [u8]> {
+        loop {
+            match self.data.get(self.pos) {
+                Some(byte) if byte.is_ascii_whitespace() => self.pos += 1,
+                Some(b'#') => {
+                    while self.data.get(self.pos).is_some_and(|&byte| byte != b'\n') {
+                        self.pos += 1;
+                    }
+                }
+                Some(_) => break,
+                None => return invalid("Unexpected end of the header".to_string()),
+            }
+        }
+
+        let start = self.pos;
+        while self
+            .data
+            .get(self.pos)
+            .is_some_and(|byte| !byte.is_ascii_whitespace())
+        {
+            self.pos += 1;
+        }
+        Ok(&self.data[start..self.pos])
+    }
+
+    fn number(&mut self) -> io::Result<u32> {
+        let token = self.token()?;
+        std::str::from_utf8(token)
+            .ok()
+            .and_then(|token| token.parse().ok())
+            .map_or_else(
+                || {
+                    invalid(format!(
+                        "Expected a number, got {:?}",
+                        String::from_utf8_lossy(token)
+                    ))
+                },
+                Ok,
+            )
+    }
+
+    /// Width, height and MAXVAL of a PGM or PPM.
+    fn header(&mut self, color_type: ColorType) -> io::Result<(u32, u32, u32, ColorType)> {
+        let (width, height, maxval) = (self.number()?, self.number()?, self.number()?);
+        Ok((width, height, maxval, color_type))
+    }
+
+    /// Width, height, MAXVAL and color type of a PAM.
+    fn pam_header(&mut self) -> io::Result<(u32, u32, u32, ColorType)> {
+        let (mut width, mut height, mut depth, mut maxval) = (0, 0, 0, 0);
+        let mut tuple_type = String::new();
+
+        loop {
+            let key = self.token()?;
+            match key {
+                b"ENDHDR" => break,
+                b"WIDTH" => width = self.number()?,
+                b"HEIGHT" => height = self.number()?,
+                b"DEPTH" => depth = self.number()?,
+                b"MAXVAL" => maxval = self.number()?,
+                // The rest of the line, which can be given in several TUPLTYPE lines
+                b"TUPLTYPE" => {
+                    let end = self.data[self.pos..]
+                        .iter()
+                        .position(|&byte| byte == b'\n')
+                        .map_or(self.data.len(), |end| self.pos + end);
+                    let value = String::from_utf8_lossy(&self.data[self.pos..end]);
+                    if !tuple_type.is_empty() {
+                        tuple_type.push(' ');
+                    }
+                    tuple_type.push_str(value.trim());
+                    self.pos = end;
+                }
+                _ => {
+                    return invalid(format!(
+                        "Unknown PAM header line {:?}",
+                        String::from_utf8_lossy(key)
+                    ))
+                }
+            }
+        }
+
+        let color_type = match depth {
+            1 => ColorType::Greyscale,
+            2 => ColorType::GreyscaleAlpha,
+            3 => ColorType::Truecolour,
+            4 => ColorType::TruecolourAlpha,
+            _ => return invalid(format!("PAM files of depth {} are not supported", depth)),
+        };
+
+        // Unknown tuple types are interpreted from the depth
+        let expected = match &tuple_type[..] {
+            "BLACKANDWHITE" | "GRAYSCALE" => Some(ColorType::Greyscale),
+            "BLACKANDWHITE_ALPHA" | "GRAYSCALE_ALPHA" => Some(ColorType::GreyscaleAlpha),
+            "RGB" => Some(ColorType::Truecolour),
+            "RGB_ALPHA" => Some(ColorType::TruecolourAlpha),
+            _ => None,
+        };
+        if expected.is_some_and(|expected| expected != color_type) {
+            return invalid(format!(
+                "TUPLTYPE {} does not have depth {}",
+                tuple_type, depth
+            ));
+        }
+
+        Ok((width, height, maxval, color_type))
+    }
+
+    fn plain_samples(&mut self, len: usize) -> io::Result<Vec<u16>> {
+        (0..len)
+            .map(|_| match self.number() {
+                Ok(sample) if sample <= u16::MAX as u32 => Ok(sample as u16),
+                Ok(sample) => invalid(format!("Sample {} is too big", sample)),
+                Err(_) => invalid("Truncated image data".to_string()),
+            })
+            .collect()
+    }
+
+    fn binary_samples(&mut self, len: usize, maxval: u32) -> io::Result<Vec<u16>> {
+        // Exactly one whitespace character after MAXVAL or ENDHDR
+        let start = self.pos + 1;
+        let sample_size = if maxval < 256 { 1 } else { 2 };
+
+        let Some(data) = self
+            .data
+            .get(start..)
+            .zip(len.checked_mul(sample_size))
+            .and_then(|(data, size)| data.get(..size))
+        else {
+            return invalid("Truncated image data".to_string());
+        };
+
+        Ok(if sample_size == 1 {
+            data.iter().map(|&sample| sample as u16).collect()
+        } else {
+            data.chunks_exact(2)
+                .map(|sample| u16::from_be_bytes([sample[0], sample[1]]))
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_test() {
+        let grey = Image::new(3, 2, ColorType::Greyscale.code(), 2, vec![0, 1, 2, 3, 2, 1]);
+        let rgb = Image::rgb(2, 1, vec![1, 2, 3, 4, 5, 6]);
+        let rgba = Image::new_16(
+            1,
+            2,
+            ColorType::TruecolourAlpha.code(),
+            vec![1, 2, 3, 4, 5, 6, 7, 65535],
+        );
+
+        for (image, format) in [
+            (&grey, Format::Pgm),
+            (&rgb, Format::Ppm),
+            (&grey, Format::Pam),
+            (&rgba, Format::Pam),
+        ] {
+            let encoded = write(image, format).unwrap();
+            assert_eq!(&read(&encoded).unwrap(), image);
+        }
+
+        assert_eq!(&write(&rgb, Format::Ppm).unwrap()[..11], b"P6\n2 1\n255\n");
+        assert!(write(&rgb, Format::Pgm).is_err());
+    }
+
+    #[test]
+    fn plain_test() {
+        // MAXVAL 1000 is scaled to 16 bits
+        let image = read(b"P2 # comment\n2 1\n1000\n0 # another\n 500\n").unwrap();
+        assert_eq!(image, Image::grey_16(2, 1, vec![0, 32768]));
+
+        let image = read(b"P3\n1 1 255\n10 20 30").unwrap();
+        assert_eq!(image, Image::rgb(1, 1, vec![10, 20, 30]));
+
+        assert!(read(b"P3\n1 1 255\n10 20").is_err());
+        assert!(read(b"P2\n1 1 3\n4").is_err());
+        assert!(read(
+            b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 3\nMAXVAL 255\nTUPLTYPE GRAYSCALE\nENDHDR\nabc"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn size_test() {
+        // Sizes above the PNG limit, and sizes whose number of bytes overflows
+        for header in [
+            &b"P6 3000000000 3000000000 65535
+"[..],
+            b"P5 0 1 255
+",
+            b"P7\nWIDTH 2147483647\nHEIGHT 2147483647\nDEPTH 4\nMAXVAL 65535\nTUPLTYPE RGB_ALPHA\nENDHDR\n",
+        ] {
+            let error = read(header).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{:?}", header);
+        }
+    }
+}
@@ -7,8 +7,13 @@
 //!
 //! Note that the bytes (u32) are stored in Big-Endian
 
-use super::crc::Crc;
-use std::mem::size_of;
+use super::{color::ColorType, crc::CrcHasher};
+use crate::compression::{zlib, Compression};
+use std::{
+    any::Any,
+    io::{self, Write},
+    mem::size_of,
+};
 
 /// The ChunkCode consists in four bytes whose values are between 65-90 and 97-122 decimal, so
 /// uppercase and lowercase ASCII letters. However they should be always treated as integers and not
@@ -20,32 +25,32 @@ use std::mem::size_of;
 /// - 2nd byte: 0: public special-purpose code, 1: private unregistered code
 /// - 3rd byte: 0: using current version of PNG
 /// - 4th byte: 0: not safe to copy, 1: save to copy (related to PNG
-/// editors and they should handle unrecognized chunks: if it is unsafe to copy, it means the
-/// chunk is dependent on the image data, and if the image was modified, it it no longer valid)
+///   editors and they should handle unrecognized chunks: if it is unsafe to copy, it means the
+///   chunk is dependent on the image data, and if the image was modified, it it no longer valid)
 #[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
 pub struct ChunkType([u8; 4]);
 
 pub const IHDR: ChunkType = ChunkType([73, 72, 68, 82]);
+pub const PLTE: ChunkType = ChunkType([80, 76, 84, 69]);
 pub const IDAT: ChunkType = ChunkType([73, 68, 65, 84]);
 pub const IEND: ChunkType = ChunkType([73, 69, 78, 68]);
+pub const TRNS: ChunkType = ChunkType([116, 82, 78, 83]);
+pub const BKGD: ChunkType = ChunkType([98, 75, 71, 68]);
+pub const GAMA: ChunkType = ChunkType([103, 65, 77, 65]);
+pub const CHRM: ChunkType = ChunkType([99, 72, 82, 77]);
+pub const SRGB: ChunkType = ChunkType([115, 82, 71, 66]);
+pub const ICCP: ChunkType = ChunkType([105, 67, 67, 80]);
 
 impl ChunkType {
-    pub fn from_code(code: &str) -> Self {
-        // TODO: Return error instead
-        assert_eq!(
-            4,
-            code.len(),
-            "The code length should be 4, got {}",
-            code.len()
-        );
-
-        let mut chunk_code = [0; 4];
-
-        for (i, char) in code.chars().enumerate() {
-            chunk_code[i] = char as u8;
+    /// Parses the code of a chunk type, like `tEXt`: four ASCII letters, with any case.
+    pub fn from_code(code: &str) -> io::Result<Self> {
+        match <[u8; 4]>::try_from(code.as_bytes()) {
+            Ok(bytes) if bytes.iter().all(u8::is_ascii_alphabetic) => Ok(Self(bytes)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid chunk type {:?}", code),
+            )),
         }
-
-        Self(chunk_code)
     }
 
     pub fn from_slice(data: &[u8]) -> Result<Self, std::array::TryFromSliceError> {
@@ -53,6 +58,10 @@ impl ChunkType {
         Ok(Self(bytes))
     }
 
+    pub fn as_bytes(&self) -> &[u8; 4] {
+        &self.0
+    }
+
     pub fn get_char_code(&self) -> Result<&str, std::str::Utf8Error> {
         std::str::from_utf8(&self.0)
     }
@@ -62,39 +71,85 @@ impl ChunkType {
     }
 
     pub fn is_public(&self) -> bool {
-        self.0[2] & (1 << 5) == 0
+        self.0[1] & (1 << 5) == 0
     }
 
     pub fn is_safe_to_copy(&self) -> bool {
-        // TODO: clippy: incompatible bit mask: `_ & 32` can never be equal to `1`
-        self.0[3] & (1 << 5) == 1
+        self.0[3] & (1 << 5) != 0
+    }
+
+    /// Whether the reserved bit (the case of the third letter) is valid, which is required for
+    /// chunks of the current version of the spec.
+    pub fn is_reserved_bit_valid(&self) -> bool {
+        self.0[2] & (1 << 5) == 0
     }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
-pub trait Chunk: std::fmt::Debug {
+/// Allows going back from a `dyn Chunk` to the concrete type that implements it (see
+/// `downcast_ref`). It is implemented automatically for every type.
+pub trait AsAny {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+pub trait Chunk: std::fmt::Debug + AsAny {
     /// Returns the size of the data section (not including type)
     fn data_size(&self) -> u32;
     fn get_type(&self) -> ChunkType;
     fn data_to_bytes(&self) -> Vec<u8>;
 
-    fn to_bytes(&self, crc: &Crc) -> Vec<u8> {
+    /// Writes the data section. By default it writes the result of `data_to_bytes`, chunks that
+    /// already store their data as bytes override it to avoid the copy.
+    fn write_data(&self, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(&self.data_to_bytes())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
         let data_size = self.data_size();
+        let data = self.data_to_bytes();
+
+        // The CRC covers the type and the data
+        let mut crc = CrcHasher::new();
+        crc.update(&self.get_type().0);
+        crc.update(&data);
 
         let mut bytes = Vec::with_capacity(data_size as usize + 3 * size_of::<u32>());
         bytes.extend_from_slice(&data_size.to_be_bytes());
         bytes.extend_from_slice(&self.get_type().0);
-        bytes.extend_from_slice(&self.data_to_bytes());
-
-        // CRC calculation
-        let crc = crc.calculate(&bytes[4..]); // Jump size
-        bytes.extend_from_slice(&crc.to_be_bytes());
+        bytes.extend_from_slice(&data);
+        bytes.extend_from_slice(&crc.finalize().to_be_bytes());
 
         bytes
     }
 }
 
+impl dyn Chunk {
+    /// Returns the concrete chunk if it is of type `T`.
+    pub fn downcast_ref<T: Chunk + 'static>(&self) -> Option<&T> {
+        self.as_any().downcast_ref()
+    }
+
+    pub fn downcast_mut<T: Chunk + 'static>(&mut self) -> Option<&mut T> {
+        self.as_any_mut().downcast_mut()
+    }
+
+    pub fn is<T: Chunk + 'static>(&self) -> bool {
+        self.as_any().is::<T>()
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 #[derive(Default, Debug, Clone)]
@@ -124,6 +179,10 @@ impl Chunk for GenericChunk {
     fn data_to_bytes(&self) -> Vec<u8> {
         self.data.clone()
     }
+
+    fn write_data(&self, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(&self.data)
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -131,16 +190,17 @@ impl Chunk for GenericChunk {
 /// IHDR Chunk must appear first:
 ///
 ///  - Width (4 bytes) and Height (4 bytes) store the size of the image in pixels. Valid range is
-///    0..=2^31.
+///    1..=2^31-1.
 ///
 ///  - Bit Depth (1 byte) : is the number of bits per sample or per palette index (not per pixel). Valid values
 ///    are 1, 2, 4, 8, and 16, although not all values are allowed for all color types.
 ///
 ///  - Color type (1 byte): represent sums of the following values:
-///        - 0: Grayscale used
-///        - 1: Palette used         (1st bit set)
-///        - 2: Color used           (2nd bit set)
-///        - 4: Alpha channel used   (3rd bit set)
+///    - 0: Grayscale used
+///    - 1: Palette used         (1st bit set)
+///    - 2: Color used           (2nd bit set)
+///    - 4: Alpha channel used   (3rd bit set)
+///
 ///    Valid values are 0, 2, 3, 4, and 6.
 ///
 ///  - Compression method (1 byte): indicates the method used to compress the image data. At
@@ -164,7 +224,7 @@ impl Chunk for GenericChunk {
 /// | Indexed-colour        | 3          | 1, 2, 4, 8         | Each pixel is a palette index; a PLTE chunk shall appear.       |
 /// | Greyscale with alpha  | 4          | 8, 16              | Each pixel is a greyscale sample followed by an alpha sample.   |
 /// | Truecolour with alpha | 6          | 8, 16              | Each pixel is an R,G,B triple followed by an alpha sample.      |
-#[derive(Debug, Copy, Clone, Default)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
 pub struct ImageHeader {
     pub width: u32,
     pub height: u32,
@@ -175,7 +235,16 @@ pub struct ImageHeader {
     pub interlace: u8,
 }
 
+/// Largest width and height allowed by the spec, 2^31 - 1.
+pub const MAX_SIZE: u32 = (1 << 31) - 1;
+
 impl ImageHeader {
+    pub const GREYSCALE: u8 = ColorType::Greyscale as u8;
+    pub const TRUECOLOUR: u8 = ColorType::Truecolour as u8;
+    pub const INDEXED: u8 = ColorType::Indexed as u8;
+    pub const GREYSCALE_ALPHA: u8 = ColorType::GreyscaleAlpha as u8;
+    pub const TRUECOLOUR_ALPHA: u8 = ColorType::TruecolourAlpha as u8;
+
     pub fn new(size: (u32, u32), bit_depth: u8, color_type: u8, adam7_interlace: bool) -> Self {
         // TODO: check for valid combinations of bit_depth and color_type
         Self {
@@ -209,6 +278,52 @@ impl ImageHeader {
     }
 }
 
+impl ImageHeader {
+    /// Checks the restrictions described above: the size, the bit depth for each color type and
+    /// the methods.
+    pub fn validate(&self) -> io::Result<()> {
+        let invalid = |message: String| Err(io::Error::new(io::ErrorKind::InvalidData, message));
+
+        if !(1..=MAX_SIZE).contains(&self.width) || !(1..=MAX_SIZE).contains(&self.height) {
+            return invalid(format!("Invalid image size {}x{}", self.width, self.height));
+        }
+
+        let Some(color_type) = ColorType::from_code(self.color_type) else {
+            return invalid(format!("Invalid color type {}", self.color_type));
+        };
+        if !color_type.bit_depths().contains(&self.bit_depth) {
+            return invalid(format!(
+                "Bit depth {} is not allowed for color type {}",
+                self.bit_depth, self.color_type
+            ));
+        }
+
+        if self.compression != 0 || self.filter != 0 || self.interlace > 1 {
+            return invalid(format!(
+                "Unknown compression ({}), filter ({}) or interlace ({}) method",
+                self.compression, self.filter, self.interlace
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Number of samples of each pixel (see `ColorType::samples`), 1 for invalid color types.
+    pub fn samples_per_pixel(&self) -> usize {
+        ColorType::from_code(self.color_type).map_or(1, ColorType::samples)
+    }
+
+    pub fn bits_per_pixel(&self) -> usize {
+        self.samples_per_pixel() * self.bit_depth as usize
+    }
+
+    /// Size in bytes of a scanline of `width` pixels (without the filter-type byte). Pixels
+    /// smaller than a byte are packed, so the last byte may be only partially used.
+    pub fn row_size(&self, width: u32) -> usize {
+        (width as usize * self.bits_per_pixel()).div_ceil(8)
+    }
+}
+
 impl Chunk for ImageHeader {
     fn data_size(&self) -> u32 {
         13
@@ -233,6 +348,494 @@ impl Chunk for ImageHeader {
 
 ////////////////////////////////////////////////////////////////////////////////
 
+/// PLTE contains from 1 to 256 palette entries, each a three-byte series of the form red, green
+/// and blue (8 bits each, whatever the bit depth of the image).
+///
+/// It must appear for color type 3 (indexed-colour), and it is optional for color types 2 and 6
+/// (as a suggested palette for viewers that cannot display truecolour). It must not appear for
+/// color types 0 and 4, and there must not be more than one PLTE chunk.
+///
+/// The number of entries is determined from the chunk length, that must be divisible by 3. It
+/// shall not exceed the range that can be represented in the image bit depth (`2^bit_depth`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Palette {
+    pub entries: Vec<[u8; 3]>,
+}
+
+impl Palette {
+    pub fn new(entries: Vec<[u8; 3]>) -> Self {
+        Self { entries }
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Self {
+        assert_eq!(
+            data.len() % 3,
+            0,
+            "Palette length must be divisible by 3, got {}",
+            data.len()
+        );
+
+        Self {
+            entries: data
+                .chunks_exact(3)
+                .map(|rgb| [rgb[0], rgb[1], rgb[2]])
+                .collect(),
+        }
+    }
+}
+
+impl Chunk for Palette {
+    fn data_size(&self) -> u32 {
+        3 * self.entries.len() as u32
+    }
+
+    fn get_type(&self) -> ChunkType {
+        PLTE
+    }
+
+    fn data_to_bytes(&self) -> Vec<u8> {
+        self.entries.concat()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// IDAT contains the actual image data, which is the output stream of the compression algorithm.
+///
+/// There may be multiple IDAT chunks; if so, they must appear consecutively with no other
+/// intervening chunks. The compressed data stream is then the concatenation of the contents of all
+/// of them, and the boundaries have no meaning: they can even split a single byte of the stream.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImageData {
+    pub data: Vec<u8>,
+}
+
+impl ImageData {
+    pub fn from_bytes(data: &[u8]) -> Self {
+        Self {
+            data: data.to_owned(),
+        }
+    }
+}
+
+impl Chunk for ImageData {
+    fn data_size(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    fn get_type(&self) -> ChunkType {
+        IDAT
+    }
+
+    fn data_to_bytes(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+
+    fn write_data(&self, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(&self.data)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// tRNS specifies that the image uses simple transparency: either alpha values associated with
+/// palette entries (for indexed-colour images) or a single transparent colour (for greyscale and
+/// truecolour images).
+///
+/// - Color type 3: a series of one-byte alpha values, one for each palette entry. It may contain
+///   fewer values than palette entries, in which case the alpha for the remaining entries is 255.
+/// - Color type 0: a single two-byte grey level. Pixels of this grey level are transparent.
+/// - Color type 2: three two-byte values (red, green and blue) of the transparent colour.
+///
+/// It is prohibited for color types 4 and 6, since a full alpha channel is already present.
+///
+/// The meaning of the data depends on the color type, so it is stored as read.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Transparency {
+    pub data: Vec<u8>,
+}
+
+impl Transparency {
+    pub fn from_bytes(data: &[u8]) -> Self {
+        Self {
+            data: data.to_owned(),
+        }
+    }
+
+    pub fn palette(alphas: Vec<u8>) -> Self {
+        Self { data: alphas }
+    }
+
+    pub fn grey(value: u16) -> Self {
+        Self {
+            data: value.to_be_bytes().to_vec(),
+        }
+    }
+
+    pub fn rgb(red: u16, green: u16, blue: u16) -> Self {
+        Self {
+            data: [red, green, blue]
+                .iter()
+                .flat_map(|value| value.to_be_bytes())
+                .collect(),
+        }
+    }
+
+    /// Alpha of the palette entry `index`.
+    pub fn palette_alpha(&self, index: usize) -> u8 {
+        *self.data.get(index).unwrap_or(&255)
+    }
+
+    /// The transparent grey level, if this is the tRNS of a greyscale image.
+    pub fn grey_value(&self) -> Option<u16> {
+        match self.data[..] {
+            [high, low] => Some(u16::from_be_bytes([high, low])),
+            _ => None,
+        }
+    }
+
+    /// The transparent colour, if this is the tRNS of a truecolour image.
+    pub fn rgb_value(&self) -> Option<[u16; 3]> {
+        if self.data.len() != 6 {
+            return None;
+        }
+
+        let value = |i: usize| u16::from_be_bytes([self.data[i], self.data[i + 1]]);
+        Some([value(0), value(2), value(4)])
+    }
+}
+
+impl Chunk for Transparency {
+    fn data_size(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    fn get_type(&self) -> ChunkType {
+        TRNS
+    }
+
+    fn data_to_bytes(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+
+    fn write_data(&self, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(&self.data)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// bKGD specifies a default background colour to present the image against. Viewers may use it
+/// when they have no better choice, and it is also the colour used to remove the alpha channel
+/// (see module `color`).
+///
+/// - Color type 3: a single byte, the index of the palette entry of the background.
+/// - Color types 0 and 4: a single two-byte grey level.
+/// - Color types 2 and 6: three two-byte values (red, green and blue).
+///
+/// The values have the same bit depth as the image. As in tRNS, the meaning of the data depends on
+/// the color type, so it is stored as read.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Background {
+    pub data: Vec<u8>,
+}
+
+impl Background {
+    pub fn from_bytes(data: &[u8]) -> Self {
+        Self {
+            data: data.to_owned(),
+        }
+    }
+
+    pub fn palette(index: u8) -> Self {
+        Self { data: vec![index] }
+    }
+
+    pub fn grey(value: u16) -> Self {
+        Self {
+            data: value.to_be_bytes().to_vec(),
+        }
+    }
+
+    pub fn rgb(red: u16, green: u16, blue: u16) -> Self {
+        Self {
+            data: [red, green, blue]
+                .iter()
+                .flat_map(|value| value.to_be_bytes())
+                .collect(),
+        }
+    }
+
+    /// The palette index, if this is the bKGD of an indexed-colour image.
+    pub fn palette_index(&self) -> Option<u8> {
+        match self.data[..] {
+            [index] => Some(index),
+            _ => None,
+        }
+    }
+
+    /// The grey level, if this is the bKGD of a greyscale image.
+    pub fn grey_value(&self) -> Option<u16> {
+        match self.data[..] {
+            [high, low] => Some(u16::from_be_bytes([high, low])),
+            _ => None,
+        }
+    }
+
+    /// The colour, if this is the bKGD of a truecolour image.
+    pub fn rgb_value(&self) -> Option<[u16; 3]> {
+        if self.data.len() != 6 {
+            return None;
+        }
+
+        let value = |i: usize| u16::from_be_bytes([self.data[i], self.data[i + 1]]);
+        Some([value(0), value(2), value(4)])
+    }
+}
+
+impl Chunk for Background {
+    fn data_size(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    fn get_type(&self) -> ChunkType {
+        BKGD
+    }
+
+    fn data_to_bytes(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+
+    fn write_data(&self, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(&self.data)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// gAMA specifies the relationship between the samples and the light intensity they represent:
+///
+/// ```text
+/// sample = light_intensity ^ gamma
+/// ```
+///
+/// The gamma is stored as a four-byte integer, times 100000. For example, a gamma of 1/2.2 (the
+/// usual one for computer displays) is stored as 45455.
+///
+/// It is ignored if there is an sRGB or iCCP chunk. It must go before PLTE and IDAT.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Gamma {
+    pub gamma: u32,
+}
+
+impl Gamma {
+    pub fn from_bytes(data: &[u8]) -> Self {
+        Self {
+            gamma: u32::from_be_bytes(data.try_into().expect("gAMA must be 4 bytes long")),
+        }
+    }
+
+    pub fn new(gamma: f64) -> Self {
+        Self {
+            gamma: (gamma * 100000.0).round() as u32,
+        }
+    }
+
+    pub fn value(&self) -> f64 {
+        self.gamma as f64 / 100000.0
+    }
+}
+
+impl Chunk for Gamma {
+    fn data_size(&self) -> u32 {
+        4
+    }
+
+    fn get_type(&self) -> ChunkType {
+        GAMA
+    }
+
+    fn data_to_bytes(&self) -> Vec<u8> {
+        self.gamma.to_be_bytes().to_vec()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// cHRM specifies the CIE 1931 xy chromaticities of the white point and of the red, green and blue
+/// primaries used by the image, each one as two four-byte integers times 100000:
+///
+/// ```text
+/// White point x, White point y, Red x, Red y, Green x, Green y, Blue x, Blue y
+/// ```
+///
+/// It is ignored if there is an sRGB or iCCP chunk. It must go before PLTE and IDAT.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Chromaticities {
+    /// `[white, red, green, blue]`, each one as `[x, y]`
+    pub points: [[u32; 2]; 4],
+}
+
+impl Chromaticities {
+    pub fn from_bytes(data: &[u8]) -> Self {
+        assert_eq!(
+            data.len(),
+            32,
+            "cHRM must be 32 bytes long, got {}",
+            data.len()
+        );
+
+        let value = |i: usize| u32::from_be_bytes(data[4 * i..4 * i + 4].try_into().unwrap());
+        Self {
+            points: [0, 1, 2, 3].map(|i| [value(2 * i), value(2 * i + 1)]),
+        }
+    }
+
+    /// `[white, red, green, blue]` as real numbers.
+    pub fn values(&self) -> [[f64; 2]; 4] {
+        self.points
+            .map(|point| point.map(|value| value as f64 / 100000.0))
+    }
+}
+
+impl Chunk for Chromaticities {
+    fn data_size(&self) -> u32 {
+        32
+    }
+
+    fn get_type(&self) -> ChunkType {
+        CHRM
+    }
+
+    fn data_to_bytes(&self) -> Vec<u8> {
+        self.points
+            .iter()
+            .flatten()
+            .flat_map(|value| value.to_be_bytes())
+            .collect()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// sRGB indicates that the image samples conform to the sRGB colour space, so the gAMA and cHRM
+/// chunks (if any) should be ignored. Its only byte is the rendering intent, which tells how to
+/// map colours that the output device cannot reproduce:
+///
+/// - 0: Perceptual
+/// - 1: Relative colorimetric
+/// - 2: Saturation
+/// - 3: Absolute colorimetric
+///
+/// It must go before PLTE and IDAT, and it cannot appear along with iCCP.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct StandardRgb {
+    pub rendering_intent: u8,
+}
+
+impl StandardRgb {
+    pub fn from_bytes(data: &[u8]) -> Self {
+        assert_eq!(
+            data.len(),
+            1,
+            "sRGB must be 1 byte long, got {}",
+            data.len()
+        );
+        Self {
+            rendering_intent: data[0],
+        }
+    }
+}
+
+impl Chunk for StandardRgb {
+    fn data_size(&self) -> u32 {
+        1
+    }
+
+    fn get_type(&self) -> ChunkType {
+        SRGB
+    }
+
+    fn data_to_bytes(&self) -> Vec<u8> {
+        vec![self.rendering_intent]
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// iCCP contains an embedded ICC profile (see module `icc`), which describes the colour space of
+/// the image:
+///
+/// - Profile name: 1 to 79 bytes (Latin-1), followed by a null byte
+/// - Compression method (1 byte): only 0 (zlib) is defined
+/// - Compressed profile
+///
+/// It must go before PLTE and IDAT, and it cannot appear along with sRGB.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IccProfile {
+    pub name: Vec<u8>,
+    pub compression: u8,
+    pub compressed: Vec<u8>,
+}
+
+impl IccProfile {
+    /// Largest decompressed profile accepted. Real profiles are at most a few megabytes, and the
+    /// limit keeps a small chunk from decompressing to gigabytes.
+    pub const MAX_PROFILE_SIZE: usize = 1 << 24;
+
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let end = data
+            .iter()
+            .position(|&byte| byte == 0)
+            .expect("iCCP must have a null-terminated name");
+
+        Self {
+            name: data[..end].to_vec(),
+            compression: data[end + 1],
+            compressed: data[end + 2..].to_vec(),
+        }
+    }
+
+    /// Compresses the given profile.
+    pub fn new(name: &str, profile: &[u8]) -> Self {
+        Self {
+            name: name.bytes().collect(),
+            compression: 0,
+            compressed: zlib::compress(profile, Compression::Default),
+        }
+    }
+
+    /// Decompresses the profile, up to `MAX_PROFILE_SIZE` bytes.
+    pub fn profile(&self) -> io::Result<Vec<u8>> {
+        if self.compression != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown compression method {}", self.compression),
+            ));
+        }
+        zlib::decompress_limited(&self.compressed, Self::MAX_PROFILE_SIZE)
+    }
+}
+
+impl Chunk for IccProfile {
+    fn data_size(&self) -> u32 {
+        (self.name.len() + 2 + self.compressed.len()) as u32
+    }
+
+    fn get_type(&self) -> ChunkType {
+        ICCP
+    }
+
+    fn data_to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.name.clone();
+        bytes.extend_from_slice(&[0, self.compression]);
+        bytes.extend_from_slice(&self.compressed);
+        bytes
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
 /// IEND describes the end of the PNG. It must be empty.
 #[derive(Debug, Copy, Clone)]
 pub struct ImageTrailer;
@@ -253,15 +856,179 @@ impl Chunk for ImageTrailer {
 
 ////////////////////////////////////////////////////////////////////////////////
 
-/// This function returns the most apropiated Chunk for the data read.
-/// The first 4 bytes are considered as the type and the rest are data.
-pub fn from_bytes(bytes: &[u8]) -> Box<dyn Chunk> {
-    match ChunkType::from_slice(&bytes[..4]) {
-        Ok(IHDR) => Box::new(ImageHeader::from_bytes(&bytes[4..])),
-        Ok(IEND) => Box::new(ImageTrailer {}),
-        Ok(other) => Box::new(GenericChunk::from_bytes(other, &bytes[4..])),
-        Err(error) => unreachable!("{}", error),
+/// Decodes a chunk given its type followed by its data (the bytes covered by the CRC). See
+/// `parse`.
+pub fn from_bytes(bytes: &[u8]) -> io::Result<Box<dyn Chunk>> {
+    match bytes.split_first_chunk::<4>() {
+        Some((&chunk_type, data)) => parse(ChunkType(chunk_type), data),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Chunk of {} bytes is too short to have a type", bytes.len()),
+        )),
     }
 }
 
+/// Splits at the first null byte, which ends keywords and names in the text and iCCP chunks.
+pub fn split_null(data: &[u8]) -> io::Result<(&[u8], &[u8])> {
+    match data.iter().position(|&byte| byte == 0) {
+        Some(end) => Ok((&data[..end], &data[end + 1..])),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Missing null separator",
+        )),
+    }
+}
+
+/// Returns the most appropriate chunk for the data. The known chunks are checked to have data of
+/// a valid size, since it may come from a broken file, so that their constructors do not panic.
+pub fn parse(chunk_type: ChunkType, data: &[u8]) -> io::Result<Box<dyn Chunk>> {
+    let valid = match chunk_type {
+        IHDR => data.len() == 13,
+        PLTE => data.len().is_multiple_of(3) && (3..=3 * 256).contains(&data.len()),
+        IEND => data.is_empty(),
+        BKGD => matches!(data.len(), 1 | 2 | 6),
+        GAMA => data.len() == 4,
+        CHRM => data.len() == 32,
+        SRGB => data.len() == 1,
+        // Name of 1 to 79 bytes, null separator and compression method
+        ICCP => data
+            .iter()
+            .position(|&byte| byte == 0)
+            .is_some_and(|end| (1..80).contains(&end) && end + 2 <= data.len()),
+        _ => true,
+    };
+
+    if !valid {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Invalid data length {} for chunk {:?}",
+                data.len(),
+                chunk_type
+            ),
+        ));
+    }
+
+    Ok(match chunk_type {
+        IHDR => Box::new(ImageHeader::from_bytes(data)),
+        PLTE => Box::new(Palette::from_bytes(data)),
+        IDAT => Box::new(ImageData::from_bytes(data)),
+        TRNS => Box::new(Transparency::from_bytes(data)),
+        BKGD => Box::new(Background::from_bytes(data)),
+        GAMA => Box::new(Gamma::from_bytes(data)),
+        CHRM => Box::new(Chromaticities::from_bytes(data)),
+        SRGB => Box::new(StandardRgb::from_bytes(data)),
+        ICCP => Box::new(IccProfile::from_bytes(data)),
+        IEND => Box::new(ImageTrailer),
+        other => Box::new(GenericChunk::from_bytes(other, data)),
+    })
+}
+
 // TODO: http://libpng.org/pub/png/spec/1.2/PNG-Chunks.html
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_test() {
+        let valid = ImageHeader::new((MAX_SIZE, 1), 8, ImageHeader::GREYSCALE, true);
+        assert!(valid.validate().is_ok());
+
+        let invalid = [
+            ImageHeader { width: 0, ..valid },
+            ImageHeader { height: 0, ..valid },
+            ImageHeader {
+                width: 1 << 31,
+                ..valid
+            },
+            ImageHeader {
+                height: 1 << 31,
+                ..valid
+            },
+            ImageHeader {
+                color_type: 1,
+                ..valid
+            },
+            ImageHeader {
+                bit_depth: 3,
+                ..valid
+            },
+            ImageHeader {
+                color_type: ImageHeader::TRUECOLOUR,
+                bit_depth: 4,
+                ..valid
+            },
+            ImageHeader {
+                color_type: ImageHeader::INDEXED,
+                bit_depth: 16,
+                ..valid
+            },
+            ImageHeader {
+                compression: 1,
+                ..valid
+            },
+            ImageHeader { filter: 1, ..valid },
+            ImageHeader {
+                interlace: 2,
+                ..valid
+            },
+        ];
+        for header in invalid {
+            let error = header.validate().unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn from_bytes_test() {
+        let chunk = from_bytes(b"gAMA\x00\x00\xB1\x8F").unwrap();
+        assert_eq!(chunk.downcast_ref::<Gamma>(), Some(&Gamma::new(0.45455)));
+        assert_eq!(
+            from_bytes(b"prVw").unwrap().get_type(),
+            ChunkType::from_code("prVw").unwrap()
+        );
+
+        // Errors instead of panicking on data that the constructors do not accept
+        for bytes in [
+            &b"IHD"[..],
+            b"IHDR\x00",
+            b"PLTE\x01\x02",
+            b"cHRM",
+            b"iCCP\x00\x00",
+        ] {
+            assert!(from_bytes(bytes).is_err(), "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn from_code_test() {
+        let private = ChunkType::from_code("prvw").unwrap();
+        assert_eq!(private.as_bytes(), b"prvw");
+        assert!(!private.is_reserved_bit_valid());
+        assert!(ChunkType::from_code("prVw")
+            .unwrap()
+            .is_reserved_bit_valid());
+
+        for code in ["", "IDA", "IDATA", "pr1w", "tEX\0", "gÁMA"] {
+            assert!(ChunkType::from_code(code).is_err(), "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn icc_profile_test() {
+        let icc = IccProfile::new("sRGB", b"profile");
+        assert_eq!(icc.profile().unwrap(), b"profile");
+
+        let bomb = IccProfile {
+            name: b"bomb".to_vec(),
+            compression: 0,
+            compressed: zlib::compress(
+                &vec![0; IccProfile::MAX_PROFILE_SIZE + 1],
+                Compression::Fast,
+            ),
+        };
+        assert!(bomb.compressed.len() < 100_000);
+        assert!(bomb.profile().is_err());
+    }
+}
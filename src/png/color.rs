@@ -0,0 +1,492 @@
+//! The color type of the IHDR tells how the samples of each pixel are interpreted:
+//!
+//! ```text
+//! | Color type            | Code | Samples of each pixel       | Bit depths     |
+//! |-----------------------|------|-----------------------------|----------------|
+//! | Greyscale             | 0    | grey                        | 1, 2, 4, 8, 16 |
+//! | Truecolour            | 2    | red, green, blue            | 8, 16          |
+//! | Indexed-colour        | 3    | palette index               | 1, 2, 4, 8     |
+//! | Greyscale with alpha  | 4    | grey, alpha                 | 8, 16          |
+//! | Truecolour with alpha | 6    | red, green, blue, alpha     | 8, 16          |
+//! ```
+//!
+//! The code is a bit field (1: palette, 2: colour, 4: alpha), but it is easier to list the five
+//! valid combinations, which is what `ColorType` does. The rest of the crate uses it instead of
+//! looking at the bits.
+//!
+//! This module also converts an `Image` between color types and bit depths. Every pixel goes
+//! through RGBA with 16-bit samples, which can represent any PNG pixel exactly:
+//!
+//! - Samples of lower bit depths are scaled to 16 bits, and the palette and tRNS are applied.
+//! - Colours become grey using their luminance, unless they are already grey (all three samples
+//!   are equal), in which case they are kept exactly.
+//! - If the new color type has no alpha channel, the pixels are composited over the background
+//!   colour (usually the one of the bKGD chunk). Without a background, the alpha is just dropped.
+//! - Samples are rounded to the new bit depth.
+//!
+//! Note that the luminance is computed directly on the stored samples, which are usually gamma
+//! encoded, so it is only an approximation of the real luminance (this is also what most
+//! libraries do).
+
+use super::{
+    chunks::{Background, Palette, Transparency},
+    image::{Image, PixelData},
+    samples,
+};
+use std::{collections::HashMap, io};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ColorType {
+    Greyscale = 0,
+    Truecolour = 2,
+    Indexed = 3,
+    GreyscaleAlpha = 4,
+    TruecolourAlpha = 6,
+}
+
+impl ColorType {
+    pub const ALL: [Self; 5] = [
+        Self::Greyscale,
+        Self::Truecolour,
+        Self::Indexed,
+        Self::GreyscaleAlpha,
+        Self::TruecolourAlpha,
+    ];
+
+    /// The color type of the given IHDR code, if it is valid.
+    pub fn from_code(code: u8) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|color_type| *color_type as u8 == code)
+    }
+
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    /// Number of samples of each pixel: 1 for greyscale and indexed, 3 for truecolour plus 1 if
+    /// there is an alpha channel.
+    pub fn samples(self) -> usize {
+        match self {
+            Self::Greyscale | Self::Indexed => 1,
+            Self::GreyscaleAlpha => 2,
+            Self::Truecolour => 3,
+            Self::TruecolourAlpha => 4,
+        }
+    }
+
+    /// Whether the pixels have an alpha sample (tRNS does not count).
+    pub fn has_alpha(self) -> bool {
+        matches!(self, Self::GreyscaleAlpha | Self::TruecolourAlpha)
+    }
+
+    /// Whether the pixels can have colour, not only grey levels.
+    pub fn has_colour(self) -> bool {
+        matches!(
+            self,
+            Self::Truecolour | Self::Indexed | Self::TruecolourAlpha
+        )
+    }
+
+    /// The allowed bit depths, see the table of `ImageHeader`.
+    pub fn bit_depths(self) -> &'static [u8] {
+        match self {
+            Self::Greyscale => &[1, 2, 4, 8, 16],
+            Self::Indexed => &[1, 2, 4, 8],
+            Self::Truecolour | Self::GreyscaleAlpha | Self::TruecolourAlpha => &[8, 16],
+        }
+    }
+}
+
+/// Weights of red, green and blue used to compute the luminance of a colour.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Luminance {
+    /// ITU-R BT.601 (standard-definition television): `0.299 R + 0.587 G + 0.114 B`
+    #[default]
+    Rec601,
+    /// ITU-R BT.709 (high-definition television and sRGB): `0.2126 R + 0.7152 G + 0.0722 B`
+    Rec709,
+}
+
+impl Luminance {
+    pub fn coefficients(self) -> [f64; 3] {
+        match self {
+            Self::Rec601 => [0.299, 0.587, 0.114],
+            Self::Rec709 => [0.2126, 0.7152, 0.0722],
+        }
+    }
+
+    /// Luminance of a colour, in the same scale as the samples.
+    pub fn of(self, rgb: [u16; 3]) -> u16 {
+        let [r, g, b] = self.coefficients();
+        (r * rgb[0] as f64 + g * rgb[1] as f64 + b * rgb[2] as f64).round() as u16
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConvertOptions {
+    /// How to turn colours into grey levels
+    pub luminance: Luminance,
+    /// Multiplies the colour samples by the alpha, if the new color type has an alpha channel
+    pub premultiply: bool,
+    /// Colour to composite the pixels over when the alpha is removed, in the format of the source
+    /// image (usually its bKGD chunk)
+    pub background: Option<Background>,
+}
+
+/// Maximum value of a sample of `bit_depth` bits.
+fn max_value(bit_depth: u8) -> u32 {
+    (1 << bit_depth) - 1
+}
+
+/// Scales a sample of `bit_depth` bits to 16 bits. Since the maximum values for bit depths 1, 2, 4
+/// and 8 divide 65535, this is exact.
+fn to_16(sample: u16, bit_depth: u8) -> u16 {
+    (sample as u32 * (65535 / max_value(bit_depth))) as u16
+}
+
+/// Reduces a 16-bit sample to `bit_depth` bits, rounding to the nearest value.
+fn from_16(sample: u16, bit_depth: u8) -> u16 {
+    let max = max_value(bit_depth);
+    ((sample as u32 * max + 32767) / 65535) as u16
+}
+
+/// `a * b / 65535`, rounded.
+fn multiply(a: u16, b: u16) -> u16 {
+    ((a as u32 * b as u32 + 32767) / 65535) as u16
+}
+
+fn invalid<T>(message: String) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidInput, message))
+}
+
+impl Image {
+    /// The color type of the image, if it is valid.
+    pub fn color(&self) -> Option<ColorType> {
+        ColorType::from_code(self.color_type)
+    }
+
+    /// Every pixel of the image as RGBA with 16-bit samples, after applying the palette and tRNS.
+    pub fn to_rgba_16(&self) -> io::Result<Vec<[u16; 4]>> {
+        let Some(color_type) = self.color() else {
+            return invalid(format!("Invalid color type {}", self.color_type));
+        };
+
+        let samples: Vec<u16> = match &self.data {
+            PixelData::U8(data) => data.iter().map(|&sample| sample as u16).collect(),
+            PixelData::U16(data) => data.clone(),
+        };
+        let expected_len = self.samples_per_row() * self.height as usize;
+        if samples.len() != expected_len {
+            return invalid(format!(
+                "Expected {} samples of image data, got {}",
+                expected_len,
+                samples.len()
+            ));
+        }
+
+        let palette = match (&self.palette, color_type) {
+            (Some(palette), _) => &palette.entries[..],
+            (None, ColorType::Indexed) => {
+                return invalid("Indexed-colour images need a palette".to_string())
+            }
+            (None, _) => &[],
+        };
+        let transparency = self.transparency.as_ref();
+        let grey_key = transparency.and_then(Transparency::grey_value);
+        let rgb_key = transparency.and_then(Transparency::rgb_value);
+
+        let scale = |sample| to_16(sample, self.bit_depth);
+        let opaque = u16::MAX;
+
+        samples
+            .chunks_exact(color_type.samples())
+            .map(|pixel| {
+                Ok(match (color_type, pixel) {
+                    (ColorType::Greyscale, &[grey]) => {
+                        let alpha = if grey_key == Some(grey) { 0 } else { opaque };
+                        [scale(grey), scale(grey), scale(grey), alpha]
+                    }
+                    (ColorType::Truecolour, &[r, g, b]) => {
+                        let alpha = if rgb_key == Some([r, g, b]) {
+                            0
+                        } else {
+                            opaque
+                        };
+                        [scale(r), scale(g), scale(b), alpha]
+                    }
+                    (ColorType::Indexed, &[index]) => {
+                        let Some(&[r, g, b]) = palette.get(index as usize) else {
+                            return invalid(format!("Palette index {} out of range", index));
+                        };
+                        let alpha = transparency.map_or(255, |t| t.palette_alpha(index as usize));
+                        [r, g, b, alpha].map(|sample| to_16(sample as u16, 8))
+                    }
+                    (ColorType::GreyscaleAlpha, &[grey, alpha]) => {
+                        [scale(grey), scale(grey), scale(grey), scale(alpha)]
+                    }
+                    (_, &[r, g, b, alpha]) => [scale(r), scale(g), scale(b), scale(alpha)],
+                    _ => unreachable!(),
+                })
+            })
+            .collect()
+    }
+
+    /// The given background colour (see `Background`) of this image, as RGB with 16-bit samples.
+    pub fn background_rgb_16(&self, background: &Background) -> io::Result<[u16; 3]> {
+        let scale = |sample| to_16(sample, self.bit_depth);
+
+        let rgb = match self.color() {
+            Some(ColorType::Indexed) => background
+                .palette_index()
+                .and_then(|index| self.palette.as_ref()?.entries.get(index as usize))
+                .map(|&[r, g, b]| [r, g, b].map(|sample| to_16(sample as u16, 8))),
+            Some(ColorType::Greyscale | ColorType::GreyscaleAlpha) => {
+                background.grey_value().map(|grey| [scale(grey); 3])
+            }
+            Some(ColorType::Truecolour | ColorType::TruecolourAlpha) => {
+                background.rgb_value().map(|rgb| rgb.map(scale))
+            }
+            None => None,
+        };
+
+        match rgb {
+            Some(rgb) => Ok(rgb),
+            None => invalid(format!("Invalid bKGD for color type {}", self.color_type)),
+        }
+    }
+
+    /// Converts the image to another color type and bit depth, as described in the module
+    /// documentation.
+    ///
+    /// Converting to indexed colour fails if there are more different colours than palette entries
+    /// (2 to the power of the bit depth). Converting to a color type without alpha channel removes
+    /// the transparency, tRNS included.
+    pub fn convert(
+        &self,
+        color_type: ColorType,
+        bit_depth: u8,
+        options: &ConvertOptions,
+    ) -> io::Result<Image> {
+        if !color_type.bit_depths().contains(&bit_depth) {
+            return invalid(format!(
+                "Bit depth {} is not allowed for color type {}",
+                bit_depth,
+                color_type.code()
+            ));
+        }
+
+        let mut pixels = self.to_rgba_16()?;
+
+        if let (Some(background), false) = (&options.background, color_type.has_alpha()) {
+            let background = self.background_rgb_16(background)?;
+            for pixel in &mut pixels {
+                let alpha = pixel[3] as u32;
+                for (sample, background) in pixel[..3].iter_mut().zip(background) {
+                    let mixed = *sample as u32 * alpha + background as u32 * (65535 - alpha);
+                    *sample = ((mixed + 32767) / 65535) as u16;
+                }
+                pixel[3] = u16::MAX;
+            }
+        }
+
+        if options.premultiply && color_type.has_alpha() {
+            for pixel in &mut pixels {
+                let alpha = pixel[3];
+                for sample in &mut pixel[..3] {
+                    *sample = multiply(*sample, alpha);
+                }
+            }
+        }
+
+        let grey = |[r, g, b, _]: [u16; 4]| {
+            if r == g && g == b {
+                r
+            } else {
+                options.luminance.of([r, g, b])
+            }
+        };
+
+        let mut image = Image {
+            width: self.width,
+            height: self.height,
+            color_type: color_type.code(),
+            bit_depth,
+            ..Default::default()
+        };
+
+        let samples: Vec<u16> = match color_type {
+            ColorType::Indexed => {
+                let (indices, palette, transparency) = build_palette(&pixels, bit_depth)?;
+                image.palette = Some(palette);
+                image.transparency = transparency;
+                image.data = PixelData::U8(indices);
+                return Ok(image);
+            }
+            ColorType::Greyscale => pixels.iter().map(|&pixel| grey(pixel)).collect(),
+            ColorType::GreyscaleAlpha => pixels
+                .iter()
+                .flat_map(|&pixel| [grey(pixel), pixel[3]])
+                .collect(),
+            ColorType::Truecolour => pixels
+                .iter()
+                .flat_map(|pixel| &pixel[..3])
+                .copied()
+                .collect(),
+            ColorType::TruecolourAlpha => pixels.into_iter().flatten().collect(),
+        };
+
+        image.data = match bit_depth {
+            16 => PixelData::U16(samples),
+            8 => PixelData::U8(samples.into_iter().map(samples::round_16_to_8).collect()),
+            _ => PixelData::U8(
+                samples
+                    .into_iter()
+                    .map(|sample| from_16(sample, bit_depth) as u8)
+                    .collect(),
+            ),
+        };
+
+        Ok(image)
+    }
+}
+
+/// Builds a palette with the different colours of the image (reduced to 8 bits), in order of
+/// appearance, returning the index of each pixel, the palette and the tRNS if any colour is not
+/// opaque.
+fn build_palette(
+    pixels: &[[u16; 4]],
+    bit_depth: u8,
+) -> io::Result<(Vec<u8>, Palette, Option<Transparency>)> {
+    let max_entries = 1 << bit_depth;
+    let mut colours: Vec<[u8; 4]> = Vec::new();
+    let mut lookup = HashMap::new();
+
+    let mut indices = Vec::with_capacity(pixels.len());
+    for pixel in pixels {
+        let colour = pixel.map(samples::round_16_to_8);
+        let index = *lookup.entry(colour).or_insert_with(|| {
+            colours.push(colour);
+            colours.len() - 1
+        });
+
+        if index >= max_entries {
+            return invalid(format!(
+                "The image has more than {} colours, they do not fit in a palette",
+                max_entries
+            ));
+        }
+        indices.push(index as u8);
+    }
+
+    let palette = Palette::new(colours.iter().map(|&[r, g, b, _]| [r, g, b]).collect());
+
+    // The alphas after the last transparent colour can be omitted
+    let mut alphas: Vec<u8> = colours.iter().map(|colour| colour[3]).collect();
+    while alphas.last() == Some(&255) {
+        alphas.pop();
+    }
+    let transparency = (!alphas.is_empty()).then(|| Transparency::palette(alphas));
+
+    Ok((indices, palette, transparency))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_type_test() {
+        for code in 0..8 {
+            let color_type = ColorType::from_code(code);
+            assert_eq!(color_type.is_some(), matches!(code, 0 | 2 | 3 | 4 | 6));
+            assert!(color_type.is_none_or(|color_type| color_type.code() == code));
+        }
+
+        assert_eq!(ColorType::TruecolourAlpha.samples(), 4);
+        assert!(ColorType::GreyscaleAlpha.has_alpha() && !ColorType::Indexed.has_alpha());
+        assert!(ColorType::Indexed.has_colour() && !ColorType::GreyscaleAlpha.has_colour());
+    }
+
+    #[test]
+    fn convert_test() {
+        let options = ConvertOptions::default();
+
+        // Grey levels survive a round trip through RGB exactly
+        let grey = Image::new(4, 1, ColorType::Greyscale.code(), 2, vec![0, 1, 2, 3]);
+        let rgb = grey.convert(ColorType::Truecolour, 8, &options).unwrap();
+        assert_eq!(rgb.as_u8().unwrap()[3..6], [85, 85, 85]);
+        assert_eq!(
+            rgb.convert(ColorType::Greyscale, 2, &options).unwrap(),
+            grey
+        );
+
+        // Luminance of pure red and 16 bits
+        let red = Image::rgb(1, 1, vec![255, 0, 0]);
+        let luminance = |luminance| {
+            let options = ConvertOptions {
+                luminance,
+                ..Default::default()
+            };
+            let grey = red.convert(ColorType::Greyscale, 16, &options).unwrap();
+            grey.as_u16().unwrap()[0]
+        };
+        assert_eq!(luminance(Luminance::Rec601), 19595);
+        assert_eq!(luminance(Luminance::Rec709), 13933);
+
+        // Indexed with tRNS to RGBA and back
+        let palette = Palette::new(vec![[10, 20, 30], [40, 50, 60]]);
+        let mut indexed = Image::indexed(3, 1, vec![1, 0, 1], palette);
+        indexed.transparency = Some(Transparency::palette(vec![255, 0]));
+        let rgba = indexed
+            .convert(ColorType::TruecolourAlpha, 8, &options)
+            .unwrap();
+        assert_eq!(
+            rgba.as_u8().unwrap(),
+            [40, 50, 60, 0, 10, 20, 30, 255, 40, 50, 60, 0]
+        );
+        let back = rgba.convert(ColorType::Indexed, 1, &options).unwrap();
+        assert_eq!(back.as_u8().unwrap(), [0, 1, 0]);
+        assert_eq!(back.transparency, Some(Transparency::palette(vec![0])));
+
+        // Too many colours for 1 bit
+        let colours = Image::grey(3, 1, vec![0, 1, 2]);
+        assert!(colours.convert(ColorType::Indexed, 1, &options).is_err());
+        assert!(colours.convert(ColorType::Truecolour, 4, &options).is_err());
+    }
+
+    #[test]
+    fn alpha_test() {
+        let image = Image::grey_alpha(2, 1, vec![200, 255, 200, 51]);
+
+        let premultiplied = ConvertOptions {
+            premultiply: true,
+            ..Default::default()
+        };
+        let converted = image
+            .convert(ColorType::TruecolourAlpha, 8, &premultiplied)
+            .unwrap();
+        assert_eq!(converted.as_u8().unwrap()[4..], [40, 40, 40, 51]);
+
+        // Composited over the bKGD (20% of 200 and 80% of 100), or the alpha just dropped
+        let background = ConvertOptions {
+            background: Some(Background::grey(100)),
+            ..Default::default()
+        };
+        let composited = image.convert(ColorType::Greyscale, 8, &background).unwrap();
+        assert_eq!(composited.as_u8().unwrap(), [200, 120]);
+        let dropped = image.convert(ColorType::Greyscale, 8, &Default::default());
+        assert_eq!(dropped.unwrap().as_u8().unwrap(), [200, 200]);
+
+        // tRNS colour over a red background
+        let mut rgb = Image::rgb(2, 1, vec![1, 2, 3, 4, 5, 6]);
+        rgb.transparency = Some(Transparency::rgb(4, 5, 6));
+        let background = ConvertOptions {
+            background: Some(Background::rgb(255, 0, 0)),
+            ..Default::default()
+        };
+        let composited = rgb.convert(ColorType::Truecolour, 8, &background).unwrap();
+        assert_eq!(composited.as_u8().unwrap(), [1, 2, 3, 255, 0, 0]);
+        assert_eq!(composited.transparency, None);
+    }
+}
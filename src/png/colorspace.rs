@@ -0,0 +1,415 @@
+//! The samples of an image are not proportional to the light they represent: they are gamma
+//! encoded, so that more values are spent on dark tones, where the eye is more sensitive. Also, the
+//! exact colour of the red, green and blue primaries depends on the colour space. Some chunks
+//! describe how to interpret the samples, in order of preference:
+//!
+//! 1. iCCP: an embedded ICC profile (see module `icc`)
+//! 2. sRGB: the samples are in the sRGB colour space
+//! 3. gAMA and cHRM: the exponent of the transfer function and the chromaticities of the primaries
+//!
+//! Without any of them, the image is assumed to be sRGB.
+//!
+//! A `ColorProfile` puts all of them in the same form: a curve for each channel that turns the
+//! samples into linear light, and a matrix that converts the linear RGB of the image to linear sRGB
+//! (going through CIE XYZ, with chromatic adaptation if the white points are different):
+//!
+//! ```text
+//! linear sRGB = XYZ_TO_SRGB * adaptation * RGB_TO_XYZ * [curve(R), curve(G), curve(B)]
+//! ```
+//!
+//! Linear light is what blending and compositing need, since there adding samples means adding
+//! light. The result can be encoded back to sRGB for display or to save it as a normal PNG.
+
+use super::{
+    chunks::{Chromaticities, Gamma, IccProfile, StandardRgb},
+    color::ColorType,
+    icc,
+    image::Image,
+    Png,
+};
+use std::io;
+
+/// A 3x3 matrix, as a list of rows.
+pub type Matrix = [[f64; 3]; 3];
+
+const IDENTITY: Matrix = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+/// Chromaticities of the sRGB white point (D65) and primaries, in the order of `Chromaticities`.
+const SRGB_POINTS: [[f64; 2]; 4] = [[0.3127, 0.329], [0.64, 0.33], [0.3, 0.6], [0.15, 0.06]];
+
+/// Bradford matrix: converts XYZ to a space similar to the response of the cones of the eye, where
+/// the adaptation to a different white is a simple scale.
+const BRADFORD: Matrix = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut result = [[0.0; 3]; 3];
+    for (i, row) in result.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    result
+}
+
+fn apply(matrix: &Matrix, vector: [f64; 3]) -> [f64; 3] {
+    matrix.map(|row| row[0] * vector[0] + row[1] * vector[1] + row[2] * vector[2])
+}
+
+fn invert(m: &Matrix) -> Option<Matrix> {
+    let cofactor = |i: usize, j: usize| {
+        let (r0, r1) = ((i + 1) % 3, (i + 2) % 3);
+        let (c0, c1) = ((j + 1) % 3, (j + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+
+    let determinant: f64 = (0..3).map(|j| m[0][j] * cofactor(0, j)).sum();
+    if determinant.abs() < 1e-12 {
+        return None;
+    }
+
+    // The inverse is the transposed matrix of cofactors divided by the determinant
+    let mut inverse = [[0.0; 3]; 3];
+    for (i, row) in inverse.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = cofactor(j, i) / determinant;
+        }
+    }
+    Some(inverse)
+}
+
+/// XYZ of a chromaticity `[x, y]` with luminance 1.
+fn xy_to_xyz([x, y]: [f64; 2]) -> [f64; 3] {
+    [x / y, 1.0, (1.0 - x - y) / y]
+}
+
+fn singular() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Invalid chromaticities")
+}
+
+/// Matrix that converts linear RGB to XYZ, given the chromaticities of the white point and of the
+/// primaries (as in `Chromaticities::values`). Its columns are the XYZ of each primary, scaled so
+/// that white (1, 1, 1) becomes the white point.
+pub fn rgb_to_xyz(points: [[f64; 2]; 4]) -> io::Result<Matrix> {
+    if points.iter().any(|&[_, y]| y <= 0.0) {
+        return Err(singular());
+    }
+
+    let [white, red, green, blue] = points.map(xy_to_xyz);
+    let primaries = [0, 1, 2].map(|i| [red[i], green[i], blue[i]]);
+    let scale = apply(&invert(&primaries).ok_or_else(singular)?, white);
+
+    Ok(primaries.map(|row| [0, 1, 2].map(|j| row[j] * scale[j])))
+}
+
+/// Bradford chromatic adaptation from one white point to another, both as XYZ.
+pub fn adaptation(from: [f64; 3], to: [f64; 3]) -> Matrix {
+    let [from, to] = [from, to].map(|white| apply(&BRADFORD, white));
+    let scale = [0, 1, 2].map(|i| [0, 1, 2].map(|j| if i == j { to[i] / from[i] } else { 0.0 }));
+
+    let inverse = invert(&BRADFORD).unwrap();
+    multiply(&inverse, &multiply(&scale, &BRADFORD))
+}
+
+/// Converts XYZ (with the D65 white point) to linear sRGB.
+fn xyz_to_srgb() -> Matrix {
+    invert(&rgb_to_xyz(SRGB_POINTS).unwrap()).unwrap()
+}
+
+/// Transfer function: turns a sample in the range 0 to 1 into linear light.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Curve {
+    Identity,
+    /// `linear = sample ^ gamma`
+    Gamma(f64),
+    /// Values at evenly spaced samples, linearly interpolated
+    Table(Vec<f64>),
+    /// ICC parametric function `[g, a, b, c, d, e, f]`:
+    ///
+    /// ```text
+    /// linear = (a * sample + b) ^ g + e   if sample >= d
+    /// linear = c * sample + f             otherwise
+    /// ```
+    Parametric([f64; 7]),
+}
+
+impl Curve {
+    /// The sRGB transfer function: a linear segment near black and a 2.4 exponent elsewhere (which
+    /// is close to a gamma of 2.2 overall).
+    pub const SRGB: Curve = Curve::Parametric([
+        2.4,
+        1.0 / 1.055,
+        0.055 / 1.055,
+        1.0 / 12.92,
+        0.04045,
+        0.0,
+        0.0,
+    ]);
+
+    pub fn to_linear(&self, sample: f64) -> f64 {
+        match self {
+            Curve::Identity => sample,
+            Curve::Gamma(gamma) => sample.powf(*gamma),
+            Curve::Table(table) => {
+                let position = sample.clamp(0.0, 1.0) * (table.len() - 1) as f64;
+                let i = (position as usize).min(table.len() - 2);
+                let fraction = position - i as f64;
+                table[i] + (table[i + 1] - table[i]) * fraction
+            }
+            &Curve::Parametric([g, a, b, c, d, e, f]) => {
+                if sample >= d {
+                    (a * sample + b).max(0.0).powf(g) + e
+                } else {
+                    c * sample + f
+                }
+            }
+        }
+    }
+}
+
+/// Inverse of `Curve::SRGB`: encodes linear light in the sRGB transfer function.
+pub fn srgb_encode(linear: f64) -> f64 {
+    if linear <= 0.0031308 {
+        12.92 * linear
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// How to get linear sRGB from the samples of an image, see the module documentation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColorProfile {
+    /// Transfer functions of red, green and blue (greyscale images have the same three)
+    pub curves: [Curve; 3],
+    /// Converts the linear RGB of the image to linear sRGB
+    pub matrix: Matrix,
+}
+
+impl ColorProfile {
+    pub fn srgb() -> Self {
+        Self {
+            curves: [Curve::SRGB, Curve::SRGB, Curve::SRGB],
+            matrix: IDENTITY,
+        }
+    }
+
+    /// The profile described by gAMA and cHRM. If one of them is missing, the sRGB values are
+    /// used instead.
+    pub fn from_chunks(
+        gamma: Option<&Gamma>,
+        chromaticities: Option<&Chromaticities>,
+    ) -> io::Result<Self> {
+        let curve = match gamma {
+            Some(gamma) if gamma.gamma == 0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Invalid gamma 0",
+                ))
+            }
+            // gAMA is the exponent of the encoding
+            Some(gamma) => Curve::Gamma(1.0 / gamma.value()),
+            None => Curve::SRGB,
+        };
+
+        let matrix = match chromaticities {
+            Some(chromaticities) => {
+                let points = chromaticities.values();
+                let to_xyz = rgb_to_xyz(points)?;
+                let white = xy_to_xyz(points[0]);
+                let adapt = adaptation(white, xy_to_xyz(SRGB_POINTS[0]));
+                multiply(&xyz_to_srgb(), &multiply(&adapt, &to_xyz))
+            }
+            None => IDENTITY,
+        };
+
+        Ok(Self {
+            curves: [curve.clone(), curve.clone(), curve],
+            matrix,
+        })
+    }
+
+    /// The profile described by an ICC profile (see module `icc`).
+    pub fn from_icc(data: &[u8]) -> io::Result<Self> {
+        let profile = icc::parse(data)?;
+
+        // The PCS uses the D50 white point
+        let matrix = match profile.colorants {
+            Some(colorants) => {
+                let adapt = adaptation(profile.illuminant, xy_to_xyz(SRGB_POINTS[0]));
+                multiply(&xyz_to_srgb(), &multiply(&adapt, &colorants))
+            }
+            None => IDENTITY,
+        };
+
+        Ok(Self {
+            curves: profile.curves,
+            matrix,
+        })
+    }
+}
+
+/// An image with linear sRGB samples, in the range 0 to 1 (although colours outside of the sRGB
+/// gamut can go beyond it). The alpha is not premultiplied.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LinearImage {
+    pub width: u32,
+    pub height: u32,
+    /// RGBA of each pixel
+    pub pixels: Vec<[f32; 4]>,
+}
+
+impl LinearImage {
+    /// Encodes the image as sRGB, as an RGBA image of 8 or 16 bits.
+    pub fn to_srgb(&self, bit_depth: u8) -> Image {
+        let max = if bit_depth == 16 { 65535.0 } else { 255.0 };
+        let samples = self.pixels.iter().flat_map(|&[r, g, b, alpha]| {
+            let encode = |linear: f32| srgb_encode(linear as f64);
+            [encode(r), encode(g), encode(b), alpha as f64]
+                .map(|sample| (sample.clamp(0.0, 1.0) * max).round() as u16)
+        });
+
+        let (width, height) = (self.width, self.height);
+        match bit_depth {
+            16 => Image::new_16(
+                width,
+                height,
+                ColorType::TruecolourAlpha.code(),
+                samples.collect(),
+            ),
+            _ => Image::rgba(width, height, samples.map(|sample| sample as u8).collect()),
+        }
+    }
+}
+
+impl Image {
+    /// Converts the pixels to linear sRGB with the given profile.
+    pub fn to_linear(&self, profile: &ColorProfile) -> io::Result<LinearImage> {
+        let pixels = self.to_rgba_16()?;
+
+        // Every possible sample goes through the curves only once. Samples of up to 8 bits (and
+        // palette entries) are multiples of 257 once scaled to 16 bits.
+        let (size, step) = if self.bit_depth == 16 {
+            (65536, 1)
+        } else {
+            (256, 257)
+        };
+        let tables = profile.curves.clone().map(|curve| {
+            (0..size)
+                .map(|i| curve.to_linear(i as f64 / (size - 1) as f64))
+                .collect::<Vec<_>>()
+        });
+
+        let matrix = (profile.matrix != IDENTITY).then_some(&profile.matrix);
+        let pixels = pixels
+            .into_iter()
+            .map(|[r, g, b, alpha]| {
+                let rgb = [r, g, b];
+                let mut linear = [0, 1, 2].map(|i| tables[i][(rgb[i] / step) as usize]);
+                if let Some(matrix) = matrix {
+                    linear = apply(matrix, linear);
+                }
+                let [r, g, b] = linear.map(|sample| sample as f32);
+                [r, g, b, alpha as f32 / 65535.0]
+            })
+            .collect();
+
+        Ok(LinearImage {
+            width: self.width,
+            height: self.height,
+            pixels,
+        })
+    }
+}
+
+impl Png {
+    /// How to interpret the samples of the image, from the iCCP, sRGB, gAMA and cHRM chunks.
+    pub fn color_profile(&self) -> io::Result<ColorProfile> {
+        if let Some(icc) = self.chunk_of::<IccProfile>() {
+            return ColorProfile::from_icc(&icc.profile()?);
+        }
+        if self.chunk_of::<StandardRgb>().is_some() {
+            return Ok(ColorProfile::srgb());
+        }
+        ColorProfile::from_chunks(self.chunk_of::<Gamma>(), self.chunk_of::<Chromaticities>())
+    }
+
+    /// Decodes the image and converts it to linear sRGB, see `color_profile`.
+    pub fn decode_linear(&self) -> io::Result<LinearImage> {
+        self.decode()?.to_linear(&self.color_profile()?)
+    }
+
+    /// Decodes the image and converts it to sRGB, as an RGBA image of 8 bits (or 16 bits if the
+    /// image has 16 bits).
+    pub fn decode_srgb(&self) -> io::Result<Image> {
+        let bit_depth = if self.header().bit_depth == 16 { 16 } else { 8 };
+        Ok(self.decode_linear()?.to_srgb(bit_depth))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::encoder::EncodeOptions;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-3, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn matrix_test() {
+        // The middle row of RGB to XYZ gives the luminance of each primary
+        let to_xyz = rgb_to_xyz(SRGB_POINTS).unwrap();
+        for (value, expected) in to_xyz[1].iter().zip([0.2126, 0.7152, 0.0722]) {
+            assert_close(*value, expected);
+        }
+
+        // sRGB chromaticities do not change anything
+        let points = SRGB_POINTS.map(|point| point.map(|value| (value * 100000.0) as u32));
+        let chromaticities = Chromaticities { points };
+        let profile = ColorProfile::from_chunks(None, Some(&chromaticities)).unwrap();
+        for (row, expected) in profile.matrix.iter().zip(IDENTITY) {
+            for (value, expected) in row.iter().zip(expected) {
+                assert_close(*value, expected);
+            }
+        }
+
+        // The adaptation keeps the white white
+        let d50 = [0.9642, 1.0, 0.8249];
+        let adapted = apply(&adaptation(d50, xy_to_xyz(SRGB_POINTS[0])), d50);
+        for (value, expected) in adapted.iter().zip(xy_to_xyz(SRGB_POINTS[0])) {
+            assert_close(*value, expected);
+        }
+    }
+
+    #[test]
+    fn curve_test() {
+        assert_close(Curve::SRGB.to_linear(0.0), 0.0);
+        assert_close(Curve::SRGB.to_linear(1.0), 1.0);
+        assert_close(Curve::SRGB.to_linear(0.5), 0.214);
+        for i in 0..=10 {
+            let sample = i as f64 / 10.0;
+            assert_close(srgb_encode(Curve::SRGB.to_linear(sample)), sample);
+        }
+    }
+
+    #[test]
+    fn decode_test() {
+        let image = Image::grey_alpha(3, 1, vec![0, 255, 128, 128, 255, 0]);
+        let mut png = Png::from_image(&image, &EncodeOptions::default()).unwrap();
+
+        // sRGB by default, and back to the same samples
+        let linear = png.decode_linear().unwrap();
+        assert_close(linear.pixels[1][0] as f64, 0.2158);
+        assert_close(linear.pixels[1][3] as f64, 128.0 / 255.0);
+        let srgb = png.decode_srgb().unwrap();
+        assert_eq!(srgb.as_u8().unwrap()[4..8], [128, 128, 128, 128]);
+
+        // With a gamma of 1 the samples are already linear
+        png.chunks.insert(0, Box::new(Gamma::new(1.0)));
+        let linear = png.decode_linear().unwrap();
+        assert_close(linear.pixels[1][0] as f64, 128.0 / 255.0);
+    }
+}
@@ -26,11 +26,11 @@
 //!
 //! # Example
 //!
-//! In this example, we shall encode 14 bits of message with a 3-bit CRC, with a polynomial `x^3 + x
-//! + 1` (coefficients 1011). Start with the message to be encoded: `11 0100 1110 1100` and execute
-//! a bitwise XOR:
+//! In this example, we shall encode 14 bits of message with a 3-bit CRC, with a polynomial
+//! `x^3 + x + 1` (coefficients 1011). Start with the message to be encoded: `11 0100 1110 1100`
+//! and execute a bitwise XOR:
 //!
-//! ```
+//! ```text
 //! 11010011101100 000 <--- input right padded by 3 bits
 //! 1011               <--- divisor
 //! 01100011101100 000 <--- result (note the first four bits are the XOR with the divisor beneath, the rest of the bits are unchanged)
@@ -56,7 +56,7 @@
 //! Now, to check the validity of the message, the operation will be repeated with the remainder
 //! instead of zeroes. It should equal zero if there are no detectable errors.
 //!
-//! ```
+//! ```text
 //! 11010011101100 100 <--- input with check value
 //! 1011               <--- divisor
 //! 01100011101100 100 <--- result
@@ -72,43 +72,463 @@
 //! each byte of the message.
 //!
 //! Source (modified): https://en.wikipedia.org/wiki/Cyclic_redundancy_check
+//!
+//! # Faster implementations
+//!
+//! Going byte by byte, each lookup depends on the previous one. There are two faster ways, and
+//! `Crc::update` picks the fastest one available:
+//!
+//! - Slicing-by-16: table `k` has the CRC of each byte followed by `k` zero bytes, so 16 bytes can
+//!   be processed at once with 16 independent lookups, XORing the results. The tables are computed
+//!   at compile time.
+//! - Folding with carry-less multiplication (`PCLMULQDQ` on x86_64, `PMULL` on aarch64): the data
+//!   is processed in 128-bit blocks, and each block is "folded" into the next one multiplying it by
+//!   `x^n mod P(x)`, which moves it `n` bits forward without changing the remainder. The last 128
+//!   bits are then reduced to 32 with a Barrett reduction. See "Fast CRC Computation for Generic
+//!   Polynomials Using PCLMULQDQ Instruction", by Intel.
+//!
+//! # Combining CRCs
+//!
+//! The CRC is linear: the CRC of `A` followed by `B` is the CRC of `A` followed by as many zeros as
+//! `B` has bytes, XORed with the CRC of `B`. Appending `n` zero bytes multiplies the remainder by
+//! `x^(8n) mod P(x)`, and that power can be calculated with `log(n)` multiplications by squaring,
+//! so `crc32_combine` does not need the data of `B`, just its length. This allows hashing pieces
+//! of a message separately (in parallel, for example) and joining the results.
+//!
+//! Other CRCs (CRC-32C, CRC-16, CRC-64...) are available in the generic engine of `crate::crc`.
+
+use std::hash::Hasher;
 
 const CRC_MASK: u32 = 0xEDB88320;
 const CRC_TABLE_SZ: usize = u8::MAX as usize + 1;
 
-#[derive(Debug, Clone)]
-pub struct Crc([u32; CRC_TABLE_SZ]);
+/// Tables for slicing-by-16: `TABLES[0]` is the usual CRC table, and `TABLES[k][i]` is the CRC of
+/// byte `i` followed by `k` zeros.
+static TABLES: [[u32; CRC_TABLE_SZ]; 16] = tables();
 
-impl Crc {
-    pub fn new() -> Self {
-        let mut table = [0; CRC_TABLE_SZ];
-
-        for (i, table_byte) in table.iter_mut().enumerate() {
-            let mut byte = i as u32;
-            for _ in 0..8 {
-                if (byte & 1) == 1 {
-                    byte = CRC_MASK ^ (byte >> 1);
-                } else {
-                    byte >>= 1;
-                }
+const fn tables() -> [[u32; CRC_TABLE_SZ]; 16] {
+    let mut tables = [[0; CRC_TABLE_SZ]; 16];
+
+    let mut i = 0;
+    while i < CRC_TABLE_SZ {
+        let mut byte = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            if (byte & 1) == 1 {
+                byte = CRC_MASK ^ (byte >> 1);
+            } else {
+                byte >>= 1;
             }
+            bit += 1;
+        }
 
-            *table_byte = byte;
+        tables[0][i] = byte;
+        i += 1;
+    }
+
+    let mut k = 1;
+    while k < 16 {
+        let mut i = 0;
+        while i < CRC_TABLE_SZ {
+            let previous = tables[k - 1][i];
+            tables[k][i] = (previous >> 8) ^ tables[0][(previous & 0xFF) as usize];
+            i += 1;
         }
+        k += 1;
+    }
+
+    tables
+}
+
+/// Below this length the setup of the folding is not worth it.
+const MIN_FOLD_LEN: usize = 128;
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Crc;
 
-        Crc(table)
+impl Crc {
+    pub fn new() -> Self {
+        Crc
     }
 
     /// Returns the CRC of the bytes on buffer.
     pub fn calculate(&self, buffer: &[u8]) -> u32 {
-        let mut crc = 0xFFFF_FFFF_u32;
+        self.update(0, buffer)
+    }
+
+    /// Continues a previous CRC with more bytes, so that the CRC of a message can be calculated in
+    /// pieces: `update(calculate(a), b)` is the CRC of `a` followed by `b`.
+    pub fn update(&self, crc: u32, buffer: &[u8]) -> u32 {
+        if buffer.len() >= MIN_FOLD_LEN {
+            if let Some(crc) = clmul(crc, buffer) {
+                return crc;
+            }
+        }
+
+        slicing_by_16(crc, buffer)
+    }
+}
+
+/// Incremental CRC-32: the data can be given in pieces of any size with `update`. It also
+/// implements `Hasher`, with the CRC as the hash.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CrcHasher {
+    crc: u32,
+    bytes: u64,
+}
+
+impl CrcHasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        for byte in buffer {
-            let index = crc as u8 ^ byte;
-            crc = (crc >> 8) ^ self.0[index as usize];
+    pub fn update(&mut self, data: &[u8]) {
+        self.crc = Crc.update(self.crc, data);
+        self.bytes += data.len() as u64;
+    }
+
+    /// Returns the CRC of all the data given so far.
+    pub fn finalize(&self) -> u32 {
+        self.crc
+    }
+
+    /// Number of bytes given so far.
+    pub fn bytes_hashed(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Continues with the data hashed by `other`, as if it had been given to `update`.
+    pub fn combine(&mut self, other: &CrcHasher) {
+        self.crc = crc32_combine(self.crc, other.crc, other.bytes);
+        self.bytes += other.bytes;
+    }
+}
+
+impl Hasher for CrcHasher {
+    fn finish(&self) -> u64 {
+        self.finalize() as u64
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.update(bytes);
+    }
+}
+
+/// Returns the CRC of `A` followed by `B` given the CRC of each part and the length of `B`.
+pub fn crc32_combine(crc_a: u32, crc_b: u32, len_b: u64) -> u32 {
+    // x^(8 * len_b): the product of x^(2^k) for each bit k of the number of bits
+    let mut power = 1 << 31;
+    let mut bits = len_b;
+    let mut k = 3;
+    while bits != 0 {
+        if bits & 1 == 1 {
+            power = multiply_mod(power, X_POWERS[k % 32]);
+        }
+        bits >>= 1;
+        k += 1;
+    }
+
+    multiply_mod(power, crc_a) ^ crc_b
+}
+
+/// `X_POWERS[k]` is `x^(2^k) mod P(x)`. They repeat after 32.
+static X_POWERS: [u32; 32] = x_powers();
+
+const fn x_powers() -> [u32; 32] {
+    // Bit reflected: the most significant bit is x^0
+    let mut powers = [0; 32];
+    powers[0] = 1 << 30;
+
+    let mut k = 1;
+    while k < 32 {
+        powers[k] = multiply_mod(powers[k - 1], powers[k - 1]);
+        k += 1;
+    }
+
+    powers
+}
+
+/// Multiplies two bit reflected polynomials modulo P(x).
+const fn multiply_mod(a: u32, mut b: u32) -> u32 {
+    let mut product = 0;
+    let mut bit = 1 << 31;
+
+    while bit != 0 {
+        if a & bit != 0 {
+            product ^= b;
         }
+        // b * x
+        b = if b & 1 == 1 {
+            (b >> 1) ^ CRC_MASK
+        } else {
+            b >> 1
+        };
+        bit >>= 1;
+    }
+
+    product
+}
+
+/// One byte at a time with a single table. Like `Crc::update`, it continues the CRC `crc`.
+pub fn bytewise(crc: u32, buffer: &[u8]) -> u32 {
+    // Undo the final inversion of the previous CRC
+    let mut crc = crc ^ 0xFFFF_FFFF_u32;
+
+    for byte in buffer {
+        let index = crc as u8 ^ byte;
+        crc = (crc >> 8) ^ TABLES[0][index as usize];
+    }
+
+    // Invert the bits (1's complement)
+    crc ^ 0xFFFF_FFFF_u32
+}
 
-        // Invert the bits (1's complement)
-        crc ^ 0xFFFF_FFFF_u32
+/// Sixteen bytes at a time, see the module documentation.
+pub fn slicing_by_16(crc: u32, buffer: &[u8]) -> u32 {
+    let mut crc = crc ^ 0xFFFF_FFFF_u32;
+
+    let mut blocks = buffer.chunks_exact(16);
+    for block in &mut blocks {
+        // The CRC so far is XORed with the first four bytes, that are then the furthest away from
+        // the end of the block
+        let first = crc ^ u32::from_le_bytes(block[..4].try_into().unwrap());
+        crc = first
+            .to_le_bytes()
+            .iter()
+            .chain(&block[4..])
+            .enumerate()
+            .fold(0, |value, (i, &byte)| value ^ TABLES[15 - i][byte as usize]);
+    }
+
+    bytewise(crc ^ 0xFFFF_FFFF_u32, blocks.remainder())
+}
+
+/// Folding with carry-less multiplication, or `None` if the CPU does not support it.
+pub fn clmul(crc: u32, buffer: &[u8]) -> Option<u32> {
+    if buffer.len() < 64 {
+        return Some(slicing_by_16(crc, buffer));
+    }
+
+    arch::update(crc, buffer)
+}
+
+// Constants for the folding: powers of x modulo P(x) (bit reflected) for the distance between the
+// blocks being folded (512 and 128 bits), and for the Barrett reduction: P(x) and
+// `x^64 / P(x)`.
+const K1: u64 = 0x1_5444_2bd4;
+const K2: u64 = 0x1_c6e4_1596;
+const K3: u64 = 0x1_7519_97d0;
+const K4: u64 = 0x0_ccaa_009e;
+const K5: u64 = 0x1_63cd_6124;
+const P_X: u64 = 0x1_DB71_0641;
+const U_PRIME: u64 = 0x1_F701_1641;
+
+/// Folding algorithm, with the multiplication of two 64-bit polynomials given by the architecture.
+/// `buffer` must have 64 bytes at least.
+#[inline(always)]
+fn fold(crc: u32, buffer: &[u8], clmul: impl Fn(u64, u64) -> u128) -> u32 {
+    let reduce = |x: u128, next: u128, low: u64, high: u64| {
+        next ^ clmul(x as u64, low) ^ clmul((x >> 64) as u64, high)
+    };
+
+    let block = |i: usize| u128::from_le_bytes(buffer[16 * i..16 * (i + 1)].try_into().unwrap());
+    let blocks = buffer.len() / 16;
+
+    // Four blocks at a time, so the multiplications are independent
+    let mut x = [block(0) ^ (!crc) as u128, block(1), block(2), block(3)];
+    let mut i = 4;
+    while i + 4 <= blocks {
+        for (j, x) in x.iter_mut().enumerate() {
+            *x = reduce(*x, block(i + j), K1, K2);
+        }
+        i += 4;
+    }
+
+    let mut x = reduce(
+        reduce(reduce(x[0], x[1], K3, K4), x[2], K3, K4),
+        x[3],
+        K3,
+        K4,
+    );
+    while i < blocks {
+        x = reduce(x, block(i), K3, K4);
+        i += 1;
+    }
+
+    // From 128 to 64 bits
+    let x = clmul(x as u64, K4) ^ (x >> 64);
+    let x = clmul(x as u32 as u64, K5) ^ (x >> 32);
+
+    // Barrett reduction to 32 bits
+    let t1 = clmul(x as u32 as u64, U_PRIME);
+    let t2 = clmul(t1 as u32 as u64, P_X);
+    // Bit reflected, so the result is in the upper half of the 64 bits
+    let crc = ((x ^ t2) >> 32) as u32;
+
+    slicing_by_16(!crc, &buffer[16 * blocks..])
+}
+
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    use std::arch::x86_64::{__m128i, _mm_clmulepi64_si128, _mm_set_epi64x};
+
+    pub fn update(crc: u32, buffer: &[u8]) -> Option<u32> {
+        if is_x86_feature_detected!("pclmulqdq") {
+            // SAFETY: the CPU supports the instructions
+            Some(unsafe { fold(crc, buffer) })
+        } else {
+            None
+        }
+    }
+
+    #[target_feature(enable = "pclmulqdq", enable = "sse2")]
+    unsafe fn fold(crc: u32, buffer: &[u8]) -> u32 {
+        super::fold(crc, buffer, |a, b| {
+            #[allow(unused_unsafe)]
+            // SAFETY: both vectors have the same size
+            unsafe {
+                let product = _mm_clmulepi64_si128(
+                    _mm_set_epi64x(0, a as i64),
+                    _mm_set_epi64x(0, b as i64),
+                    0x00,
+                );
+                std::mem::transmute::<__m128i, u128>(product)
+            }
+        })
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    use std::arch::{aarch64::vmull_p64, is_aarch64_feature_detected};
+
+    pub fn update(crc: u32, buffer: &[u8]) -> Option<u32> {
+        if is_aarch64_feature_detected!("aes") {
+            // SAFETY: the CPU supports the instructions
+            Some(unsafe { fold(crc, buffer) })
+        } else {
+            None
+        }
+    }
+
+    #[target_feature(enable = "neon", enable = "aes")]
+    unsafe fn fold(crc: u32, buffer: &[u8]) -> u32 {
+        super::fold(crc, buffer, |a, b| {
+            #[allow(unused_unsafe)]
+            // SAFETY: the CPU supports the instruction
+            unsafe {
+                vmull_p64(a, b)
+            }
+        })
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod arch {
+    pub fn update(_crc: u32, _buffer: &[u8]) -> Option<u32> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Carry-less multiplication in software, to check the folding everywhere.
+    fn software_clmul(a: u64, b: u64) -> u128 {
+        (0..64)
+            .filter(|i| b >> i & 1 == 1)
+            .fold(0, |product, i| product ^ (a as u128) << i)
+    }
+
+    #[test]
+    fn implementations_test() {
+        assert_eq!(Crc::new().calculate(b"123456789"), 0xCBF4_3926);
+
+        let data: Vec<u8> = (0..1000_u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+
+        for len in (0..200).chain([511, 512, 1000]) {
+            let data = &data[..len];
+            let expected = bytewise(0, data);
+
+            assert_eq!(slicing_by_16(0, data), expected, "{}", len);
+            assert_eq!(Crc::new().calculate(data), expected, "{}", len);
+            if let Some(crc) = clmul(0, data) {
+                assert_eq!(crc, expected, "{}", len);
+            }
+            if len >= 64 {
+                assert_eq!(fold(0, data, software_clmul), expected, "{}", len);
+            }
+
+            // In two pieces
+            let crc = Crc::new().update(bytewise(0, &data[..len / 3]), &data[len / 3..]);
+            assert_eq!(crc, expected, "{}", len);
+        }
+    }
+
+    #[test]
+    fn hasher_test() {
+        let data = b"The quick brown fox jumps over the lazy dog";
+        let expected = Crc::new().calculate(data);
+
+        let mut hasher = CrcHasher::new();
+        for piece in data.chunks(5) {
+            hasher.update(piece);
+        }
+        assert_eq!(hasher.finalize(), expected);
+        assert_eq!(hasher.bytes_hashed(), data.len() as u64);
+
+        for split in [0, 1, 20, data.len()] {
+            let (a, b) = data.split_at(split);
+            let (crc_a, crc_b) = (Crc::new().calculate(a), Crc::new().calculate(b));
+            assert_eq!(crc32_combine(crc_a, crc_b, b.len() as u64), expected);
+
+            let mut hasher = CrcHasher::new();
+            hasher.write(a);
+            let mut rest = CrcHasher::new();
+            rest.write(b);
+            hasher.combine(&rest);
+            assert_eq!(hasher.finish(), expected as u64);
+        }
+
+        let long: Vec<u8> = (0..100_000_u32).map(|i| (i * 7 % 251) as u8).collect();
+        let (a, b) = long.split_at(33_333);
+        let crc = crc32_combine(bytewise(0, a), bytewise(0, b), b.len() as u64);
+        assert_eq!(crc, bytewise(0, &long));
+    }
+
+    #[test]
+    fn corruption_test() {
+        assert_eq!(Crc::new().calculate(b""), 0);
+
+        let data: Vec<u8> = (0..300_u32).map(|i| (i * 89 % 256) as u8).collect();
+        let expected = bytewise(0, &data);
+
+        // Every single-bit error is detected, wherever it lands relative to the blocks
+        let mut corrupted = data.clone();
+        for bit in 0..8 * data.len() {
+            corrupted[bit / 8] ^= 1 << (bit % 8);
+            let crc = bytewise(0, &corrupted);
+            assert_ne!(crc, expected, "{}", bit);
+            assert_eq!(slicing_by_16(0, &corrupted), crc, "{}", bit);
+            assert_eq!(fold(0, &corrupted, software_clmul), crc, "{}", bit);
+            if let Some(fast) = clmul(0, &corrupted) {
+                assert_eq!(fast, crc, "{}", bit);
+            }
+            corrupted[bit / 8] ^= 1 << (bit % 8);
+        }
+
+        // Slices that do not start at a block boundary, continuing a previous CRC
+        for offset in 1..17 {
+            let (head, tail) = data.split_at(offset);
+            let crc = bytewise(0, head);
+            assert_eq!(slicing_by_16(crc, tail), expected, "{}", offset);
+            assert_eq!(fold(crc, tail, software_clmul), expected, "{}", offset);
+            if let Some(fast) = clmul(crc, tail) {
+                assert_eq!(fast, expected, "{}", offset);
+            }
+        }
     }
 }
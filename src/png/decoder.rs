@@ -0,0 +1,668 @@
+//! Gets the pixels back from a PNG, reversing the steps of the encoder:
+//!
+//! 1. The data of all the IDAT chunks is concatenated and decompressed with zlib (module
+//!    `compression`)
+//! 2. The decompressed data is split in scanlines, each with its filter-type byte, and the filters
+//!    are reversed from top to bottom (module `filter`)
+//! 3. For interlaced images, the scanlines of each pass are placed in the complete image (module
+//!    `interlace`)
+//!
+//! `StreamingDecoder` does the same one scanline at a time while reading the file, so that only the
+//! previous scanline and the decompressor window are kept in memory.
+
+use super::{
+    chunks::{Chunk, ImageData, ImageHeader, Palette, Transparency, IDAT, IEND, IHDR},
+    filter,
+    image::{Image, PixelData},
+    interlace::{self, Pass},
+    read_header,
+    reader::ChunkReader,
+    samples, unfilter, Png,
+};
+use crate::compression::zlib::ZlibDecoder;
+use std::{
+    borrow::Cow,
+    io::{self, Read},
+};
+
+/// Size of the pieces in which the image data is read from the file.
+const INPUT_SIZE: usize = 8192;
+
+impl Png {
+    /// Decompresses and unfilters the image data, returning the scanlines of the complete image.
+    /// Samples of bit depths 1, 2 and 4 are unpacked to a byte each, and 16-bit samples are
+    /// converted to native byte order.
+    pub fn decode(&self) -> io::Result<Image> {
+        let header = self.header;
+        header.validate()?;
+
+        let compressed = self
+            .chunks_of::<ImageData>()
+            .map(|idat| idat.data.len())
+            .sum();
+        let size = image_size(&header, compressed)?;
+
+        let samples_per_pixel = header.samples_per_pixel();
+        let mut image = Image {
+            palette: self.palette().cloned(),
+            transparency: self.chunk_of::<Transparency>().cloned(),
+            ..Image::new(
+                header.width,
+                header.height,
+                header.color_type,
+                header.bit_depth,
+                Vec::new(),
+            )
+        };
+        image.data = match header.bit_depth {
+            16 => PixelData::U16(vec![0; size]),
+            _ => PixelData::U8(vec![0; size]),
+        };
+        check_palette(&header, image.palette.is_some())?;
+
+        let mut rows = RowDecoder::new(&header);
+        for idat in self.chunks_of::<ImageData>() {
+            rows.feed(&idat.data)?;
+        }
+
+        while let Some(row) = rows.next_row()? {
+            let pass = match row.pass {
+                0 => interlace::FULL,
+                number => interlace::ADAM7[number as usize - 1],
+            };
+            let (y, width) = (row.y, header.width);
+
+            match &mut image.data {
+                PixelData::U16(data) => {
+                    let samples: Vec<u16> = row
+                        .data
+                        .chunks_exact(2)
+                        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+                        .collect();
+                    interlace::scatter_row(&pass, y, width, samples_per_pixel, &samples, data);
+                }
+
+                PixelData::U8(data) => {
+                    let count = pass.width(header.width) as usize * samples_per_pixel;
+                    let samples = match header.bit_depth {
+                        1 | 2 | 4 => Cow::Owned(samples::unpack(row.data, header.bit_depth, count)),
+                        _ => Cow::Borrowed(row.data),
+                    };
+                    interlace::scatter_row(&pass, y, width, samples_per_pixel, &samples, data);
+                }
+            }
+        }
+
+        if !rows.rows_done() || !rows.finish()? {
+            return Err(unexpected_end());
+        }
+
+        Ok(image)
+    }
+}
+
+/// Size in bytes of the decompressed image data of the header: the filtered scanlines of every
+/// pass, each one with its filter-type byte.
+pub(crate) fn filtered_size(header: &ImageHeader) -> u64 {
+    interlace::passes(header)
+        .iter()
+        .map(|(_, pass)| {
+            let row_size = header.row_size(pass.width(header.width)) as u64 + 1;
+            row_size * pass.height(header.height) as u64
+        })
+        .sum()
+}
+
+/// Largest expansion of DEFLATE: each match of 258 bytes takes at least 2 bits (1 for the length
+/// and 1 for the distance).
+const MAX_DEFLATE_RATIO: u64 = 1032;
+
+/// Number of samples of the image, checking that they fit in memory and that the compressed data
+/// is big enough to contain them, since the IHDR alone could ask for a huge allocation.
+fn image_size(header: &ImageHeader, compressed: usize) -> io::Result<usize> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let size_name = format!("{}x{}", header.width, header.height);
+
+    let size = (header.width as usize)
+        .checked_mul(header.samples_per_pixel())
+        .and_then(|row| row.checked_mul(header.height as usize))
+        .ok_or_else(|| invalid(format!("Image of {} pixels is too big", size_name)))?;
+
+    // The filtered data has at least all the packed scanlines
+    let packed = (header.row_size(header.width) as u64).saturating_mul(header.height as u64);
+    if packed > (compressed as u64).saturating_mul(MAX_DEFLATE_RATIO) {
+        return Err(invalid(format!(
+            "{} bytes of image data cannot hold an image of {} pixels",
+            compressed, size_name
+        )));
+    }
+
+    Ok(size)
+}
+
+pub(crate) fn check_palette(header: &ImageHeader, has_palette: bool) -> io::Result<()> {
+    if header.color_type == ImageHeader::INDEXED && !has_palette {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Indexed-colour images need a palette",
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn unexpected_end() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "The image data ended unexpectedly",
+    )
+}
+
+/// Decompresses and unfilters the image data as it arrives, scanline by scanline, keeping track of
+/// the pass and row the next scanline belongs to.
+pub(crate) struct RowDecoder {
+    header: ImageHeader,
+    bpp: u8,
+    passes: Vec<(u8, Pass)>,
+    /// Index in `passes` and row of the reduced image of the next scanline
+    pass: usize,
+    row: u32,
+    /// Last unfiltered scanline
+    prior: Vec<u8>,
+    decompressor: ZlibDecoder,
+    /// Decompressed data not unfiltered yet
+    decompressed: Vec<u8>,
+}
+
+impl RowDecoder {
+    pub fn new(header: &ImageHeader) -> Self {
+        Self {
+            header: *header,
+            bpp: filter::bytes_per_pixel(header.color_type, header.bit_depth),
+            passes: interlace::passes(header),
+            pass: 0,
+            row: 0,
+            prior: Vec::new(),
+            decompressor: ZlibDecoder::new(),
+            decompressed: Vec::new(),
+        }
+    }
+
+    /// Adds more image data (the content of the IDAT chunks).
+    pub fn feed(&mut self, data: &[u8]) -> io::Result<()> {
+        self.decompressor.feed(data)
+    }
+
+    /// Returns `true` once every scanline has been decoded.
+    pub fn rows_done(&self) -> bool {
+        self.pass == self.passes.len()
+    }
+
+    /// Decompresses until the next scanline is complete. Returns `false` if more data is needed or
+    /// there are no scanlines left.
+    pub fn fill(&mut self) -> io::Result<bool> {
+        let Some(size) = self.next_size() else {
+            return Ok(false);
+        };
+
+        while self.decompressed.len() < size {
+            let before = self.decompressed.len();
+            self.decompressor
+                .decompress(&mut self.decompressed, size - before)?;
+
+            if self.decompressed.len() == before {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Decodes the next scanline, or returns `None` if it is not complete yet (see `fill`).
+    pub fn next_row(&mut self) -> io::Result<Option<Row<'_>>> {
+        if !self.fill()? {
+            return Ok(None);
+        }
+
+        let size = self.next_size().unwrap();
+        let (number, pass) = self.passes[self.pass];
+
+        // The first scanline of each pass has no prior scanline
+        let prior: &[u8] = if self.row == 0 { &[] } else { &self.prior };
+        let filter_type = filter::parse_type(self.decompressed.first().copied())?;
+        let scanline = &mut self.decompressed[1..size];
+        unfilter::unfilter(filter_type, scanline, prior, self.bpp);
+
+        self.prior.clear();
+        self.prior.extend_from_slice(scanline);
+        self.decompressed.drain(..size);
+
+        let y = pass.image_row(self.row);
+        self.row += 1;
+        if self.row == pass.height(self.header.height) {
+            self.pass += 1;
+            self.row = 0;
+        }
+
+        Ok(Some(Row {
+            pass: number,
+            y,
+            data: &self.prior,
+        }))
+    }
+
+    /// Checks the end of the compressed data once all the scanlines have been decoded. Returns
+    /// `false` if more data is needed (the checksum may still be missing).
+    pub fn finish(&mut self) -> io::Result<bool> {
+        while !self.decompressor.is_done() {
+            let before = self.decompressed.len();
+            self.decompressor.decompress(&mut self.decompressed, 1)?;
+
+            if !self.decompressed.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "There is more image data than needed",
+                ));
+            }
+            if self.decompressed.len() == before && !self.decompressor.is_done() {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Size of the next filtered scanline, including the filter-type byte.
+    fn next_size(&self) -> Option<usize> {
+        let (_, pass) = self.passes.get(self.pass)?;
+        Some(self.header.row_size(pass.width(self.header.width)) + 1)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A scanline returned by `StreamingDecoder`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Row<'a> {
+    /// Adam7 pass (1 to 7), or 0 for non-interlaced images
+    pub pass: u8,
+    /// Row of the complete image
+    pub y: u32,
+    /// Packed pixels in the format described by the header (see `samples::unpack`). For
+    /// interlaced images, only the pixels of the pass (see `interlace::Pass`).
+    pub data: &'a [u8],
+}
+
+/// Decodes a PNG scanline by scanline while it is being read, so memory usage does not depend on
+/// the image size.
+///
+/// The chunks before the image data are read when the decoder is created, and the rest of them
+/// when calling `finish`.
+///
+/// ```no_run
+/// # use png::StreamingDecoder;
+/// # fn main() -> std::io::Result<()> {
+/// let file = std::io::BufReader::new(std::fs::File::open("huge.png")?);
+/// let mut decoder = StreamingDecoder::new(file)?;
+///
+/// let mut sum = 0_u64;
+/// while let Some(row) = decoder.next_row()? {
+///     sum += row.data.iter().map(|&byte| byte as u64).sum::<u64>();
+/// }
+/// decoder.finish()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct StreamingDecoder<R: Read> {
+    reader: ChunkReader<R>,
+    header: ImageHeader,
+    /// Chunks other than IHDR, IDAT and IEND
+    chunks: Vec<Box<dyn Chunk>>,
+    rows: RowDecoder,
+    input: Vec<u8>,
+    /// Whether a chunk other than IDAT has been found after the image data
+    idat_over: bool,
+    ended: bool,
+}
+
+impl<R: Read> StreamingDecoder<R> {
+    /// Reads the signature, the IHDR and the chunks up to the first IDAT.
+    pub fn new(reader: R) -> io::Result<Self> {
+        let mut reader = ChunkReader::new(reader);
+        reader.read_signature()?;
+
+        let header = read_header(&mut reader)?;
+        header.validate()?;
+
+        let mut chunks = Vec::new();
+        loop {
+            match reader.begin_chunk()? {
+                (IDAT, _) => break,
+                (IEND, _) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "There is no image data",
+                    ))
+                }
+                (IHDR, _) => return Err(duplicated_header()),
+                _ => chunks.push(reader.finish_chunk()?),
+            }
+        }
+
+        check_palette(&header, chunks.iter().any(|chunk| chunk.is::<Palette>()))?;
+
+        Ok(Self {
+            reader,
+            header,
+            chunks,
+            rows: RowDecoder::new(&header),
+            input: vec![0; INPUT_SIZE],
+            idat_over: false,
+            ended: false,
+        })
+    }
+
+    pub fn header(&self) -> &ImageHeader {
+        &self.header
+    }
+
+    /// Chunks read so far, excluding IHDR, IDAT and IEND.
+    pub fn chunks(&self) -> &[Box<dyn Chunk>] {
+        &self.chunks
+    }
+
+    pub fn palette(&self) -> Option<&Palette> {
+        self.chunks
+            .iter()
+            .find_map(|chunk| chunk.downcast_ref::<Palette>())
+    }
+
+    pub fn transparency(&self) -> Option<&Transparency> {
+        self.chunks
+            .iter()
+            .find_map(|chunk| chunk.downcast_ref::<Transparency>())
+    }
+
+    /// Decodes the next scanline, or returns `None` once all of them have been decoded. For
+    /// interlaced images, the scanlines of each pass are returned in order.
+    pub fn next_row(&mut self) -> io::Result<Option<Row<'_>>> {
+        loop {
+            if self.rows.rows_done() {
+                return Ok(None);
+            }
+            if self.rows.fill()? {
+                return self.rows.next_row();
+            }
+            self.read_image_data()?;
+        }
+    }
+
+    /// Decodes the remaining scanlines, checks the end of the image data and reads the rest of the
+    /// file. Returns all the chunks other than IHDR, IDAT and IEND.
+    pub fn finish(mut self) -> io::Result<Vec<Box<dyn Chunk>>> {
+        while self.next_row()?.is_some() {}
+
+        // The checksum may still be pending
+        while !self.rows.finish()? {
+            self.read_image_data()?;
+        }
+
+        while !self.idat_over {
+            self.read_image_data()?;
+        }
+
+        while !self.ended {
+            let chunk = self.reader.read_chunk()?;
+            self.add_chunk(chunk)?;
+        }
+
+        Ok(self.chunks)
+    }
+
+    /// Gives more compressed data to the decompressor, moving on to the next IDAT chunk if needed.
+    fn read_image_data(&mut self) -> io::Result<()> {
+        if self.idat_over {
+            return Err(unexpected_end());
+        }
+
+        let read = self.reader.read_data(&mut self.input)?;
+        if read > 0 {
+            return self.rows.feed(&self.input[..read]);
+        }
+
+        // The current IDAT is over: the image data continues if the next chunk is another IDAT
+        if self.reader.begin_chunk()?.0 != IDAT {
+            self.idat_over = true;
+            let chunk = self.reader.finish_chunk()?;
+            self.add_chunk(chunk)?;
+        }
+
+        Ok(())
+    }
+
+    /// Stores a chunk found after the image data.
+    fn add_chunk(&mut self, chunk: Box<dyn Chunk>) -> io::Result<()> {
+        match chunk.get_type() {
+            IEND => self.ended = true,
+            IHDR => return Err(duplicated_header()),
+            IDAT => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "IDAT chunks must be consecutive",
+                ))
+            }
+            _ => self.chunks.push(chunk),
+        }
+        Ok(())
+    }
+}
+
+fn duplicated_header() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Found more than one IHDR chunk")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compression::{zlib, Compression},
+        png::{
+            chunks::{ChunkType, GenericChunk, MAX_SIZE},
+            encoder::EncodeOptions,
+        },
+    };
+
+    fn test_image() -> Image {
+        let (width, height) = (61, 37);
+        let data = (0..width * height * 4)
+            .map(|i| (i * 7 % 251 + i / 200) as u8)
+            .collect();
+        Image::rgba(width, height, data)
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let image = test_image();
+        let png = Png::from_image(&image, &EncodeOptions::default()).unwrap();
+        assert_eq!(png.decode().unwrap(), image);
+
+        let palette = Palette::new(vec![[0, 0, 0], [255, 0, 0], [0, 255, 0]]);
+        let mut indexed = Image::indexed(5, 2, vec![0, 1, 2, 1, 0, 2, 2, 1, 0, 0], palette);
+        indexed.transparency = Some(Transparency::palette(vec![0]));
+        let png = Png::from_image(&indexed, &EncodeOptions::default()).unwrap();
+        assert_eq!(
+            Png::read_from(&png.to_vec()[..]).unwrap().decode().unwrap(),
+            indexed
+        );
+    }
+
+    #[test]
+    fn huge_header_test() {
+        let image = Image::grey(2, 2, vec![1, 2, 3, 4]);
+        let mut png = Png::from_image(&image, &EncodeOptions::default()).unwrap();
+
+        // The size overflows on 32-bit targets, and a few bytes of image data cannot decompress
+        // to it on any
+        *png.header_mut() = ImageHeader::new(
+            (MAX_SIZE, MAX_SIZE),
+            16,
+            ImageHeader::TRUECOLOUR_ALPHA,
+            false,
+        );
+        let error = png.decode().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // 4 GB that would be allocated before noticing that the data is too short
+        *png.header_mut() = ImageHeader::new((65536, 65536), 8, ImageHeader::GREYSCALE, false);
+        let error = png.decode().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("cannot hold"));
+    }
+
+    #[test]
+    fn low_bit_depth_test() {
+        // Widths that leave padding bits at the end of each scanline
+        for bit_depth in [1, 2, 4] {
+            let (width, height) = (13, 5);
+            let max = (1 << bit_depth) - 1;
+            let data = (0..width * height)
+                .map(|i| (i * 7 % 17) as u8 & max)
+                .collect();
+
+            let grey = Image::new(width, height, ImageHeader::GREYSCALE, bit_depth, data);
+            let png = Png::from_image(&grey, &EncodeOptions::default()).unwrap();
+            assert_eq!(
+                png.header().row_size(width),
+                (13 * bit_depth as usize).div_ceil(8)
+            );
+            assert_eq!(png.decode().unwrap(), grey);
+
+            let palette = Palette::new(vec![[10, 20, 30]; 1 << bit_depth]);
+            let indexed = Image {
+                palette: Some(palette),
+                color_type: ImageHeader::INDEXED,
+                ..grey.clone()
+            };
+            let png = Png::from_image(&indexed, &EncodeOptions::default()).unwrap();
+            assert_eq!(png.decode().unwrap(), indexed);
+
+            // White stays white
+            let samples = grey.as_u8().unwrap();
+            let white = samples.iter().position(|&sample| sample == max).unwrap();
+            assert_eq!(grey.to_8_bit().as_u8().unwrap()[white], 255);
+        }
+
+        // Samples that do not fit in the bit depth
+        let image = Image::new(2, 1, ImageHeader::GREYSCALE, 2, vec![1, 4]);
+        assert!(Png::from_image(&image, &EncodeOptions::default()).is_err());
+    }
+
+    #[test]
+    fn sixteen_bit_test() {
+        let (width, height) = (19, 7);
+        let data: Vec<u16> = (0..width * height * 4)
+            .map(|i| (i * 40503 % 65536) as u16)
+            .collect();
+        let mut image = Image::new_16(width, height, ImageHeader::TRUECOLOUR_ALPHA, data);
+
+        // Lossless
+        let png = Png::from_image(&image, &EncodeOptions::default()).unwrap();
+        assert_eq!(png.decode().unwrap(), image);
+
+        // Big-endian in the file
+        image.width = 1;
+        image.height = 1;
+        image.data = PixelData::U16(vec![0x1234, 0, 0xFFFF, 0x00FF]);
+        let png = Png::from_image(&image, &EncodeOptions::default()).unwrap();
+        let filtered = zlib::decompress(&png.chunk_of::<ImageData>().unwrap().data).unwrap();
+        assert_eq!(filtered[1..3], [0x12, 0x34]);
+
+        // Rounded when reducing to 8 bits
+        let reduced = image.to_8_bit();
+        assert_eq!(reduced.as_u8(), Some(&[18, 0, 255, 1][..]));
+
+        // The samples must be u16
+        let wrong = Image::new(1, 1, ImageHeader::GREYSCALE, 16, vec![0, 0]);
+        assert!(Png::from_image(&wrong, &EncodeOptions::default()).is_err());
+    }
+
+    #[test]
+    fn streaming_test() {
+        let image = test_image();
+        let options = EncodeOptions {
+            idat_size: 100,
+            ..Default::default()
+        };
+
+        let mut png = Png::from_image(&image, &options).unwrap();
+        png.chunks.push(Box::new(GenericChunk::from_bytes(
+            ChunkType::from_code("tEXt").unwrap(),
+            b"a\0b",
+        )));
+        let bytes = png.to_vec();
+
+        let mut decoder = StreamingDecoder::new(&bytes[..]).unwrap();
+        assert_eq!(decoder.header(), png.header());
+
+        let mut rows = Vec::new();
+        while let Some(row) = decoder.next_row().unwrap() {
+            assert_eq!((row.pass, row.y), (0, rows.len() as u32));
+            rows.push(row.data.to_vec());
+        }
+        assert_eq!(rows.concat(), image.as_u8().unwrap());
+
+        // The text chunk goes after the image data
+        assert!(decoder.chunks().is_empty());
+        let chunks = decoder.finish().unwrap();
+        assert_eq!(chunks.len(), 1);
+
+        // Truncated file
+        let mut decoder = StreamingDecoder::new(&bytes[..bytes.len() / 2]).unwrap();
+        assert!(decoder.next_row().is_ok());
+        assert!(decoder.finish().is_err());
+    }
+
+    #[test]
+    fn interlaced_test() {
+        // 1-bit greyscale, to also check the pixels that are not aligned to bytes
+        let (width, height) = (11, 9);
+        let pixel = |x: u32, y: u32| ((x * 3 + y * 5) % 7 < 3) as u8;
+
+        // Serialize each pass by hand, with filter type None
+        let mut filtered = Vec::new();
+        for pass in interlace::ADAM7 {
+            for row in 0..pass.height(height) {
+                let mut bits = vec![0; pass.width(width).div_ceil(8) as usize];
+                for i in 0..pass.width(width) {
+                    let value = pixel(pass.x + i * pass.dx, pass.image_row(row));
+                    bits[i as usize / 8] |= value << (7 - i % 8);
+                }
+                filtered.push(0);
+                filtered.extend_from_slice(&bits);
+            }
+        }
+
+        let mut png = Png::new(ImageHeader::new((width, height), 1, 0, true));
+        let compressed = zlib::compress(&filtered, Compression::Default);
+        png.chunks
+            .push(Box::new(ImageData::from_bytes(&compressed)));
+
+        let image = png.decode().unwrap();
+        for y in 0..height {
+            for x in 0..width {
+                let sample = image.as_u8().unwrap()[(y * width + x) as usize];
+                assert_eq!(sample, pixel(x, y), "({}, {})", x, y);
+            }
+        }
+
+        // The streaming decoder returns the rows of each pass
+        let bytes = png.to_vec();
+        let mut decoder = StreamingDecoder::new(&bytes[..]).unwrap();
+        let mut rows = Vec::new();
+        while let Some(row) = decoder.next_row().unwrap() {
+            rows.push((row.pass, row.y));
+        }
+        assert_eq!(rows.len(), 2 + 2 + 1 + 3 + 2 + 5 + 4);
+        assert_eq!(rows[..3], [(1, 0), (1, 8), (2, 0)]);
+        assert_eq!(rows.last(), Some(&(7, 7)));
+    }
+}
@@ -0,0 +1,308 @@
+//! Comparison of two PNG files, at two levels:
+//!
+//! - Chunks: changes in the IHDR fields, and chunks added, removed or with different data. Chunks
+//!   are matched by type and occurrence (the second tEXt of one file with the second tEXt of the
+//!   other), so a reordering alone is not reported. The IDAT chunks are compared as a single
+//!   stream, since how the data is split does not matter.
+//! - Pixels: both images are converted to RGBA with the same bit depth (16 if any of them is
+//!   16-bit, 8 otherwise) and compared sample by sample. Besides the number of different pixels
+//!   and the maximum difference of each channel, the PSNR summarizes how big the differences are:
+//!
+//! ```text
+//! MSE  = sum((a - b)^2) / number of samples
+//! PSNR = 10 * log10(MAX^2 / MSE)        (MAX = 2^bit_depth - 1)
+//! ```
+//!
+//! The PSNR is infinite for identical images, and usually above 40 dB when the differences are
+//! hard to notice.
+
+use super::{
+    chunks::{ChunkType, ImageHeader, IDAT},
+    color::{ColorType, ConvertOptions},
+    image::{Image, PixelData},
+    Png,
+};
+use std::{fmt, io};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// A field of the IHDR, with the old and new values
+    Header {
+        field: &'static str,
+        old: u32,
+        new: u32,
+    },
+    Added(ChunkType),
+    Removed(ChunkType),
+    /// Both files have the chunk, but its data is different
+    Changed(ChunkType),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = |chunk_type: &ChunkType| chunk_type.get_char_code().unwrap_or("????").to_owned();
+        match self {
+            Change::Header { field, old, new } => write!(f, "~ IHDR {}: {} -> {}", field, old, new),
+            Change::Added(chunk_type) => write!(f, "+ {}", code(chunk_type)),
+            Change::Removed(chunk_type) => write!(f, "- {}", code(chunk_type)),
+            Change::Changed(chunk_type) => write!(f, "~ {}", code(chunk_type)),
+        }
+    }
+}
+
+/// Chunk-level differences from `a` to `b`: first the IHDR fields, and then the rest of chunks in
+/// the order they appear in `a` (and then `b`).
+pub fn diff_chunks(a: &Png, b: &Png) -> Vec<Change> {
+    let fields = |header: &ImageHeader| {
+        [
+            ("width", header.width),
+            ("height", header.height),
+            ("bit_depth", header.bit_depth as u32),
+            ("color_type", header.color_type as u32),
+            ("compression", header.compression as u32),
+            ("filter", header.filter as u32),
+            ("interlace", header.interlace as u32),
+        ]
+    };
+    let mut changes: Vec<_> = fields(a.header())
+        .into_iter()
+        .zip(fields(b.header()))
+        .filter(|((_, old), (_, new))| old != new)
+        .map(|((field, old), (_, new))| Change::Header { field, old, new })
+        .collect();
+
+    let mut types: Vec<ChunkType> = Vec::new();
+    for chunk in a.chunks().iter().chain(b.chunks()) {
+        if !types.contains(&chunk.get_type()) {
+            types.push(chunk.get_type());
+        }
+    }
+
+    for chunk_type in types {
+        let (old, new) = (payloads(a, chunk_type), payloads(b, chunk_type));
+
+        for i in 0..old.len().max(new.len()) {
+            match (old.get(i), new.get(i)) {
+                (Some(old), Some(new)) if old != new => changes.push(Change::Changed(chunk_type)),
+                (Some(_), None) => changes.push(Change::Removed(chunk_type)),
+                (None, Some(_)) => changes.push(Change::Added(chunk_type)),
+                _ => {}
+            }
+        }
+    }
+
+    changes
+}
+
+/// Data of every chunk of the type, with the IDAT chunks merged into one.
+fn payloads(png: &Png, chunk_type: ChunkType) -> Vec<Vec<u8>> {
+    let payloads = png
+        .chunks_by_type(chunk_type)
+        .map(|chunk| chunk.data_to_bytes());
+
+    if chunk_type == IDAT {
+        vec![payloads.flatten().collect()]
+    } else {
+        payloads.collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PixelDiff {
+    /// Bit depth of the compared samples
+    pub bit_depth: u8,
+    pub pixels: u64,
+    /// Pixels with any sample different
+    pub different: u64,
+    /// Maximum absolute difference of the red, green, blue and alpha samples
+    pub max_delta: [u16; 4],
+    /// Peak signal-to-noise ratio in decibels, infinite if the images are equal
+    pub psnr: f64,
+}
+
+/// Compares the pixels of two images of the same size.
+pub fn diff_pixels(a: &Image, b: &Image) -> io::Result<PixelDiff> {
+    let (bit_depth, old, new) = rgba_pair(a, b)?;
+
+    let mut diff = PixelDiff {
+        bit_depth,
+        pixels: a.width as u64 * a.height as u64,
+        different: 0,
+        max_delta: [0; 4],
+        psnr: f64::INFINITY,
+    };
+    let mut squares = 0.0;
+
+    for (old, new) in old.chunks_exact(4).zip(new.chunks_exact(4)) {
+        if old != new {
+            diff.different += 1;
+        }
+        for (channel, (&old, &new)) in old.iter().zip(new).enumerate() {
+            let delta = old.abs_diff(new);
+            diff.max_delta[channel] = diff.max_delta[channel].max(delta);
+            squares += delta as f64 * delta as f64;
+        }
+    }
+
+    if squares > 0.0 {
+        let max = ((1 << bit_depth) - 1) as f64;
+        let mse = squares / (4 * diff.pixels) as f64;
+        diff.psnr = 10.0 * (max * max / mse).log10();
+    }
+
+    Ok(diff)
+}
+
+/// An 8-bit RGB image that shows the pixels that differ in red over a faded greyscale version of
+/// `a`, to see where the differences are at a glance.
+pub fn highlight(a: &Image, b: &Image) -> io::Result<Image> {
+    let (bit_depth, old, new) = rgba_pair(a, b)?;
+    let shift = bit_depth - 8;
+
+    let data = old
+        .chunks_exact(4)
+        .zip(new.chunks_exact(4))
+        .flat_map(|(old, new)| {
+            if old != new {
+                return [255, 0, 0];
+            }
+            // Average of the colour over white, faded to a quarter of its contrast
+            let [r, g, b, alpha] = [old[0], old[1], old[2], old[3]].map(|s| (s >> shift) as u32);
+            let grey = ((r + g + b) / 3 * alpha + 255 * (255 - alpha)) / 255;
+            [(255 - (255 - grey) / 4) as u8; 3]
+        })
+        .collect();
+
+    Ok(Image::rgb(a.width, a.height, data))
+}
+
+/// Both images as RGBA samples with the same bit depth.
+fn rgba_pair(a: &Image, b: &Image) -> io::Result<(u8, Vec<u16>, Vec<u16>)> {
+    if (a.width, a.height) != (b.width, b.height) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "The images have different sizes: {}x{} and {}x{}",
+                a.width, a.height, b.width, b.height
+            ),
+        ));
+    }
+
+    let bit_depth = if a.bit_depth == 16 || b.bit_depth == 16 {
+        16
+    } else {
+        8
+    };
+    let rgba = |image: &Image| -> io::Result<Vec<u16>> {
+        let converted = image.convert(
+            ColorType::TruecolourAlpha,
+            bit_depth,
+            &ConvertOptions::default(),
+        )?;
+        Ok(match converted.data {
+            PixelData::U8(data) => data.into_iter().map(u16::from).collect(),
+            PixelData::U16(data) => data,
+        })
+    };
+
+    Ok((bit_depth, rgba(a)?, rgba(b)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::{
+        chunks::{Gamma, GAMA},
+        encoder::EncodeOptions,
+    };
+
+    #[test]
+    fn diff_test() {
+        let a = Image::rgb(2, 2, vec![0, 0, 0, 10, 20, 30, 40, 50, 60, 255, 255, 255]);
+        let mut b = a.clone();
+        b.data = PixelData::U8(vec![0, 0, 0, 10, 20, 33, 40, 50, 60, 255, 255, 255]);
+
+        let mut old = Png::from_image(&a, &EncodeOptions::default()).unwrap();
+        old.insert(Box::new(Gamma::new(0.45455))).unwrap();
+        let options = EncodeOptions {
+            interlace: true,
+            ..Default::default()
+        };
+        let new = Png::from_image(&b, &options).unwrap();
+
+        assert_eq!(diff_chunks(&old, &old), vec![]);
+        assert_eq!(
+            diff_chunks(&old, &new),
+            vec![
+                Change::Header {
+                    field: "interlace",
+                    old: 0,
+                    new: 1
+                },
+                Change::Removed(GAMA),
+                Change::Changed(IDAT),
+            ]
+        );
+
+        let diff = diff_pixels(&a, &b).unwrap();
+        assert_eq!((diff.pixels, diff.different, diff.bit_depth), (4, 1, 8));
+        assert_eq!(diff.max_delta, [0, 0, 3, 0]);
+        // MSE = 9 / 16
+        assert!((diff.psnr - 10.0 * (255.0 * 255.0 * 16.0 / 9.0_f64).log10()).abs() < 1e-9);
+        assert_eq!(diff_pixels(&a, &a).unwrap().psnr, f64::INFINITY);
+
+        let highlighted = highlight(&a, &b).unwrap();
+        assert_eq!(highlighted.as_u8().unwrap()[3..6], [255, 0, 0]);
+        assert_eq!(highlighted.as_u8().unwrap()[..3], [192; 3]);
+        assert_eq!(highlighted.as_u8().unwrap()[9..], [255; 3]);
+
+        assert!(diff_pixels(&a, &Image::grey(1, 1, vec![0])).is_err());
+    }
+
+    #[test]
+    fn mismatch_test() {
+        let grey = Image::grey(2, 2, vec![0, 100, 200, 255]);
+
+        // Different sizes are only compared at the chunk level
+        let tall = Image::grey(2, 3, vec![0; 6]);
+        let error = diff_pixels(&grey, &tall).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(highlight(&grey, &tall).is_err());
+        let options = EncodeOptions::default();
+        let (a, b) = (
+            Png::from_image(&grey, &options).unwrap(),
+            Png::from_image(&tall, &options).unwrap(),
+        );
+        assert_eq!(
+            diff_chunks(&a, &b)[..],
+            [
+                Change::Header {
+                    field: "height",
+                    old: 2,
+                    new: 3
+                },
+                Change::Changed(IDAT)
+            ]
+        );
+
+        // The same image with 16 bits, or in colour, has the same pixels
+        let deep = Image::grey_16(2, 2, vec![0, 100 * 257, 200 * 257, 65535]);
+        let diff = diff_pixels(&grey, &deep).unwrap();
+        assert_eq!((diff.bit_depth, diff.different), (16, 0));
+        assert_eq!(diff.psnr, f64::INFINITY);
+        let colour = Image::rgb(
+            2,
+            2,
+            vec![0, 0, 0, 100, 100, 100, 200, 200, 200, 255, 255, 255],
+        );
+        assert_eq!(diff_pixels(&grey, &colour).unwrap().different, 0);
+
+        // Differences below 8 bits are counted at 16 bits, and highlighted
+        let close = Image::grey_16(2, 2, vec![0, 100 * 257 + 1, 200 * 257, 65535]);
+        let diff = diff_pixels(&grey, &close).unwrap();
+        assert_eq!((diff.different, diff.max_delta), (1, [1, 1, 1, 0]));
+        assert!(diff.psnr > 100.0);
+        let highlighted = highlight(&close, &grey).unwrap();
+        assert_eq!(highlighted.as_u8().unwrap()[3..6], [255, 0, 0]);
+    }
+}
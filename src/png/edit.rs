@@ -0,0 +1,418 @@
+//! Editing of the list of chunks, keeping the order required by the spec:
+//!
+//! ```text
+//! IHDR | cHRM gAMA iCCP sBIT sRGB ... | PLTE | bKGD hIST tRNS pHYs sPLT eXIf | IDAT ... IDAT | IEND
+//! ```
+//!
+//! The chunks of each group must come before the ones of the following groups, and the IDAT
+//! chunks must be consecutive. The rest of the chunks (text, time and unknown ones) may be anywhere
+//! between IHDR and IEND. Most ancillary chunks, and PLTE, can only appear once.
+//!
+//! IHDR and IEND are not part of `Png::chunks`, so they are always in place, and they cannot be
+//! inserted. IDAT chunks can only be added with `insert`, which keeps them consecutive.
+//!
+//! Every edit is checked with `check_order` and undone if it is not valid. Files read from disk
+//! may not follow these rules, so they should be fixed with `normalize_order` before editing.
+
+use super::{
+    chunks::{Chunk, ChunkType, ImageData, IDAT, IEND, IHDR, PLTE},
+    color::ColorType,
+    Png,
+};
+use std::io;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Group {
+    BeforePalette,
+    Palette,
+    BeforeData,
+    Data,
+}
+
+/// Group of the chunk type, or `None` if it can be anywhere.
+fn group(chunk_type: ChunkType) -> Option<Group> {
+    match chunk_type.as_bytes() {
+        b"cHRM" | b"gAMA" | b"iCCP" | b"sBIT" | b"sRGB" | b"cICP" | b"mDCv" | b"cLLi" => {
+            Some(Group::BeforePalette)
+        }
+        b"PLTE" => Some(Group::Palette),
+        b"bKGD" | b"hIST" | b"tRNS" | b"pHYs" | b"sPLT" | b"eXIf" => Some(Group::BeforeData),
+        b"IDAT" => Some(Group::Data),
+        _ => None,
+    }
+}
+
+/// Chunks that cannot appear more than once.
+fn is_unique(chunk_type: ChunkType) -> bool {
+    matches!(
+        chunk_type.as_bytes(),
+        b"PLTE"
+            | b"cHRM"
+            | b"gAMA"
+            | b"iCCP"
+            | b"sBIT"
+            | b"sRGB"
+            | b"cICP"
+            | b"mDCv"
+            | b"cLLi"
+            | b"bKGD"
+            | b"hIST"
+            | b"tRNS"
+            | b"pHYs"
+            | b"eXIf"
+            | b"tIME"
+    )
+}
+
+fn invalid_input<T>(message: String) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidInput, message))
+}
+
+/// Fails for IHDR and IEND, which are not stored with the rest of the chunks.
+fn check_not_header(chunk_type: ChunkType) -> io::Result<()> {
+    if chunk_type == IHDR || chunk_type == IEND {
+        return invalid_input(format!("{:?} cannot be inserted", chunk_type));
+    }
+
+    Ok(())
+}
+
+impl Png {
+    /// Checks that the chunks are in the order required by the spec, that the IDAT chunks are
+    /// consecutive and that there are no repeated chunks that must be unique.
+    pub fn check_order(&self) -> io::Result<()> {
+        let mut last = Group::BeforePalette;
+        let mut data_over = false;
+
+        for (i, chunk) in self.chunks.iter().enumerate() {
+            let chunk_type = chunk.get_type();
+
+            if is_unique(chunk_type)
+                && self.chunks[..i]
+                    .iter()
+                    .any(|other| other.get_type() == chunk_type)
+            {
+                return invalid_input(format!("Repeated {:?} chunk", chunk_type));
+            }
+
+            match group(chunk_type) {
+                Some(Group::Data) if data_over => {
+                    return invalid_input("The IDAT chunks must be consecutive".to_string())
+                }
+                Some(group) if group < last => {
+                    return invalid_input(format!("Chunk {:?} out of order", chunk_type))
+                }
+                Some(group) => last = group,
+                None => data_over = last == Group::Data,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves the chunks to the order required by the spec. Chunks that can be anywhere stay in
+    /// the same place relative to PLTE and IDAT.
+    pub fn normalize_order(&mut self) {
+        let mut last = Group::BeforePalette;
+        let mut keys = Vec::with_capacity(self.chunks.len());
+
+        for chunk in &self.chunks {
+            let key = match group(chunk.get_type()) {
+                Some(group) => {
+                    last = last.max(group);
+                    group as u8
+                }
+                // After the image data they sort after the IDAT chunks
+                None if last == Group::Data => Group::Data as u8 + 1,
+                None => last as u8,
+            };
+            keys.push(key);
+        }
+
+        let mut chunks: Vec<_> = keys.into_iter().zip(self.chunks.drain(..)).collect();
+        chunks.sort_by_key(|&(key, _)| key);
+        self.chunks = chunks.into_iter().map(|(_, chunk)| chunk).collect();
+    }
+
+    /// Inserts the chunk at the place the spec requires: after the chunks of its group, or at the
+    /// end for chunks that can be anywhere.
+    pub fn insert(&mut self, chunk: Box<dyn Chunk>) -> io::Result<()> {
+        let index = match group(chunk.get_type()) {
+            Some(new) => self
+                .chunks
+                .iter()
+                .rposition(|other| group(other.get_type()).is_some_and(|group| group <= new))
+                .map_or(0, |i| i + 1),
+            None => self.chunks.len(),
+        };
+
+        self.insert_at(index, chunk)
+    }
+
+    /// Inserts the chunk just before the first chunk of type `before`. IDAT chunks cannot be
+    /// inserted this way.
+    pub fn insert_before(&mut self, before: ChunkType, chunk: Box<dyn Chunk>) -> io::Result<()> {
+        if chunk.get_type() == IDAT {
+            return invalid_input("IDAT chunks can only be inserted after the others".to_string());
+        }
+
+        match self.position(before) {
+            Some(index) => self.insert_at(index, chunk),
+            None => invalid_input(format!("There is no {:?} chunk", before)),
+        }
+    }
+
+    /// Replaces the first chunk of type `chunk_type`, returning it. If there is none, `chunk` is
+    /// inserted like in `insert`. IDAT chunks cannot be replaced, since the image data is only
+    /// valid as a whole.
+    pub fn replace_first(
+        &mut self,
+        chunk_type: ChunkType,
+        chunk: Box<dyn Chunk>,
+    ) -> io::Result<Option<Box<dyn Chunk>>> {
+        if chunk_type == IDAT || chunk.get_type() == IDAT {
+            return invalid_input("IDAT chunks cannot be replaced".to_string());
+        }
+        check_not_header(chunk.get_type())?;
+
+        let Some(index) = self.position(chunk_type) else {
+            return self.insert(chunk).map(|_| None);
+        };
+
+        let old = std::mem::replace(&mut self.chunks[index], chunk);
+        if let Err(error) = self.check_order() {
+            self.chunks[index] = old;
+            return Err(error);
+        }
+
+        Ok(Some(old))
+    }
+
+    /// Removes every chunk of type `chunk_type`, returning them. The image data, and the palette
+    /// of indexed images, cannot be removed.
+    pub fn remove_all(&mut self, chunk_type: ChunkType) -> io::Result<Vec<Box<dyn Chunk>>> {
+        if chunk_type == IDAT
+            || (chunk_type == PLTE
+                && ColorType::from_code(self.header.color_type) == Some(ColorType::Indexed))
+        {
+            return invalid_input(format!("The {:?} chunks are required", chunk_type));
+        }
+
+        let (removed, kept) = self
+            .chunks
+            .drain(..)
+            .partition(|chunk| chunk.get_type() == chunk_type);
+        self.chunks = kept;
+
+        Ok(removed)
+    }
+
+    /// Merges the data of all IDAT chunks and splits it again in chunks of `max_size` bytes (only
+    /// the last one may be smaller).
+    pub fn set_idat_size(&mut self, max_size: usize) {
+        let Some(first) = self.position(IDAT) else {
+            return;
+        };
+
+        let mut data = Vec::new();
+        self.chunks.retain(|chunk| {
+            let is_data = chunk.get_type() == IDAT;
+            if is_data {
+                data.extend_from_slice(&chunk.data_to_bytes());
+            }
+            !is_data
+        });
+
+        let mut pieces: Vec<Box<dyn Chunk>> = data
+            .chunks(max_size.max(1))
+            .map(|piece| Box::new(ImageData::from_bytes(piece)) as Box<dyn Chunk>)
+            .collect();
+        if pieces.is_empty() {
+            pieces.push(Box::new(ImageData::from_bytes(&[])));
+        }
+
+        self.chunks.splice(first..first, pieces);
+    }
+
+    fn position(&self, chunk_type: ChunkType) -> Option<usize> {
+        self.chunks
+            .iter()
+            .position(|chunk| chunk.get_type() == chunk_type)
+    }
+
+    /// Inserts the chunk, undoing it if the order is not valid anymore.
+    fn insert_at(&mut self, index: usize, chunk: Box<dyn Chunk>) -> io::Result<()> {
+        check_not_header(chunk.get_type())?;
+        self.chunks.insert(index, chunk);
+
+        if let Err(error) = self.check_order() {
+            self.chunks.remove(index);
+            return Err(error);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::{
+        chunks::{
+            Background, Gamma, GenericChunk, ImageTrailer, Palette, StandardRgb, Transparency,
+        },
+        encoder::EncodeOptions,
+        image::Image,
+    };
+
+    fn types(png: &Png) -> Vec<String> {
+        png.chunks()
+            .iter()
+            .map(|chunk| chunk.get_type().get_char_code().unwrap().to_string())
+            .collect()
+    }
+
+    fn text() -> Box<dyn Chunk> {
+        Box::new(GenericChunk::from_bytes(
+            ChunkType::from_code("tEXt").unwrap(),
+            b"Comment\0Hi",
+        ))
+    }
+
+    #[test]
+    fn edit_test() {
+        let palette = Palette::new(vec![[0, 0, 0], [255, 255, 255]]);
+        let image = Image::indexed(64, 64, vec![1; 64 * 64], palette);
+        let options = EncodeOptions {
+            idat_size: 16,
+            ..Default::default()
+        };
+        let mut png = Png::from_image(&image, &options).unwrap();
+        let pixels = png.decode().unwrap().data;
+
+        png.insert(text()).unwrap();
+        png.insert(Box::new(Transparency::from_bytes(&[0])))
+            .unwrap();
+        png.insert(Box::new(Gamma::new(1.0))).unwrap();
+        png.insert_before(ChunkType::from_code("tRNS").unwrap(), text())
+            .unwrap();
+        png.set_idat_size(1 << 20);
+        assert_eq!(
+            types(&png),
+            ["gAMA", "PLTE", "tEXt", "tRNS", "IDAT", "tEXt"]
+        );
+
+        // Out of order, not consecutive, repeated or required
+        assert!(png
+            .insert_before(PLTE, Box::new(Background::palette(0)))
+            .is_err());
+        assert!(png
+            .insert_before(
+                IDAT,
+                Box::new(StandardRgb {
+                    rendering_intent: 0
+                })
+            )
+            .is_err());
+        assert!(png.insert(Box::new(Gamma::new(0.5))).is_err());
+        assert!(png.remove_all(PLTE).is_err());
+        assert_eq!(types(&png).len(), 6);
+
+        let srgb = Box::new(StandardRgb {
+            rendering_intent: 0,
+        });
+        let gamma = png.replace_first(ChunkType::from_code("gAMA").unwrap(), srgb);
+        assert!(gamma.unwrap().unwrap().downcast_ref::<Gamma>().is_some());
+        assert_eq!(
+            png.remove_all(ChunkType::from_code("tEXt").unwrap())
+                .unwrap()
+                .len(),
+            2
+        );
+
+        // Anything goes when editing directly, but it can be fixed
+        png.chunks.swap(0, 2);
+        png.chunks.insert(2, text());
+        assert!(png.check_order().is_err());
+        png.normalize_order();
+        assert_eq!(types(&png), ["sRGB", "PLTE", "tRNS", "tEXt", "IDAT"]);
+
+        png.set_idat_size(10);
+        assert!(png
+            .chunks_by_type(IDAT)
+            .all(|chunk| chunk.data_size() <= 10));
+        assert!(png.check_order().is_ok());
+        assert_eq!(png.decode().unwrap().data, pixels);
+    }
+
+    #[test]
+    fn order_errors_test() {
+        let options = EncodeOptions {
+            idat_size: 4,
+            ..Default::default()
+        };
+        let build = || {
+            let mut png = Png::from_image(&Image::grey(8, 8, vec![7; 64]), &options).unwrap();
+            png.insert(Box::new(Gamma::new(1.0))).unwrap();
+            png.insert(Box::new(Transparency::from_bytes(&[0, 7])))
+                .unwrap();
+            png
+        };
+        let reordered = |edit: fn(&mut Png)| {
+            let mut png = build();
+            edit(&mut png);
+            png
+        };
+        let message = |png: &Png| png.check_order().unwrap_err().to_string();
+
+        // gAMA, tRNS and several IDAT chunks
+        let mut png = build();
+        let original = types(&png);
+        assert!(original.len() > 4);
+
+        let repeated = reordered(|png| png.chunks.insert(0, Box::new(Gamma::new(0.5))));
+        assert!(message(&repeated).starts_with("Repeated"));
+        let split = reordered(|png| png.chunks.insert(3, text()));
+        assert_eq!(message(&split), "The IDAT chunks must be consecutive");
+        let swapped = reordered(|png| png.chunks.swap(0, 1));
+        assert!(message(&swapped).contains("out of order"));
+        let late = reordered(|png| {
+            let gamma = png.chunks.remove(0);
+            png.chunks.push(gamma)
+        });
+        assert!(message(&late).contains("out of order"));
+        assert_eq!(
+            swapped.check_order().unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        // Failed edits leave the chunks as they were
+        assert!(png.insert_before(PLTE, text()).is_err());
+        assert!(png
+            .insert(Box::new(Transparency::from_bytes(&[0, 1])))
+            .is_err());
+        assert!(png.insert_before(IDAT, Box::new(Gamma::new(0.5))).is_err());
+        let gamma = png.replace_first(
+            ChunkType::from_code("tRNS").unwrap(),
+            Box::new(Gamma::new(0.5)),
+        );
+        assert!(gamma.is_err());
+
+        // IHDR and IEND are never inserted, and IDAT only at the end of the image data
+        let header = *png.header();
+        assert!(png.insert(Box::new(header)).is_err());
+        assert!(png.insert(Box::new(ImageTrailer)).is_err());
+        assert!(png.insert_before(IDAT, Box::new(ImageTrailer)).is_err());
+        let data = || Box::new(ImageData::from_bytes(&[]));
+        assert!(png.insert_before(IDAT, data()).is_err());
+        assert!(png.replace_first(IDAT, text()).is_err());
+        assert!(png
+            .replace_first(ChunkType::from_code("gAMA").unwrap(), data())
+            .is_err());
+        assert!(png
+            .replace_first(ChunkType::from_code("gAMA").unwrap(), Box::new(header))
+            .is_err());
+        assert_eq!(types(&png), original);
+        assert!(png.check_order().is_ok());
+    }
+}
@@ -0,0 +1,593 @@
+//! Builds a complete PNG from an `Image`:
+//!
+//! 1. IHDR from the image size and pixel format
+//! 2. PLTE and tRNS if the image has them
+//! 3. The scanlines are filtered (module `filter`), compressed with zlib (module `compression`),
+//!    and the compressed stream is split in IDAT chunks
+//! 4. IEND
+//!
+//! With `EncodeOptions::threads`, step 3 is done in parallel: the image is split in horizontal
+//! strips, and each thread filters and compresses one of them (see `zlib::compress_piece`). The
+//! strips are then joined into a single zlib stream.
+//!
+//! With `EncodeOptions::interlace`, each Adam7 pass is extracted from the image and filtered as a
+//! separate image before compressing all of them together (see module `interlace`).
+//!
+//! `StreamingEncoder` does the same one scanline at a time, so the whole image never needs to be in
+//! memory: only the previous scanline (for the filters) and the compressor window are kept.
+
+use super::{
+    chunks::{Chunk, ImageData, ImageHeader, ImageTrailer, IDAT, PLTE},
+    color::ColorType,
+    filter::{self, FilterType},
+    image::{Image, PixelData},
+    interlace::{self, Pass},
+    writer::ChunkWriter,
+    Png,
+};
+use crate::compression::{
+    zlib::{self, ZlibEncoder},
+    Compression,
+};
+use std::{
+    borrow::Cow,
+    io::{self, Write},
+};
+
+/// How the filter type of each scanline is chosen.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum FilterStrategy {
+    /// Use the same filter type for every scanline
+    Fixed(FilterType),
+    /// `None` for indexed-colour and low bit depth images (filters are rarely useful on them), and
+    /// the minimum sum of absolute differences for the rest
+    #[default]
+    Adaptive,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EncodeOptions {
+    pub filter: FilterStrategy,
+    pub compression: Compression,
+    /// Maximum size of the data of each IDAT chunk
+    pub idat_size: usize,
+    /// Number of threads that filter and compress the image data. With more than one the output
+    /// is slightly bigger, and `StreamingEncoder` ignores it.
+    pub threads: usize,
+    /// Stores the image with Adam7 interlacing (see module `interlace`). It is always encoded in a
+    /// single thread, and `StreamingEncoder` does not support it.
+    pub interlace: bool,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        Self {
+            filter: FilterStrategy::default(),
+            compression: Compression::default(),
+            idat_size: 8192,
+            threads: 1,
+            interlace: false,
+        }
+    }
+}
+
+impl Png {
+    /// Builds all the chunks needed to store `image`.
+    pub fn from_image(image: &Image, options: &EncodeOptions) -> io::Result<Self> {
+        let header = image.header(options.interlace);
+        check_image(image, &header)?;
+
+        let mut png = Png::new(header);
+
+        if let Some(palette) = &image.palette {
+            png.chunks.push(Box::new(palette.clone()));
+        }
+        if let Some(transparency) = &image.transparency {
+            png.chunks.push(Box::new(transparency.clone()));
+        }
+
+        let compressed = if options.interlace {
+            zlib::compress(
+                &filter_interlaced(image, options.filter),
+                options.compression,
+            )
+        } else if options.threads > 1 {
+            compress_strips(image, options)
+        } else {
+            zlib::compress(&filter_image(image, options.filter), options.compression)
+        };
+        for data in compressed.chunks(options.idat_size.max(1)) {
+            png.chunks.push(Box::new(ImageData::from_bytes(data)));
+        }
+
+        Ok(png)
+    }
+
+    /// Encodes `image` as a PNG file into `writer`, returning the number of bytes written.
+    pub fn encode<W: Write>(image: &Image, options: &EncodeOptions, writer: W) -> io::Result<u64> {
+        // The strips and the Adam7 passes need the whole image anyway
+        if options.threads > 1 || options.interlace {
+            return Png::from_image(image, options)?.write_to(writer);
+        }
+
+        let header = image.header(false);
+        check_image(image, &header)?;
+
+        let mut encoder = StreamingEncoder::new(writer, header, *options)?;
+
+        if let Some(palette) = &image.palette {
+            encoder.write_chunk(palette)?;
+        }
+        if let Some(transparency) = &image.transparency {
+            encoder.write_chunk(transparency)?;
+        }
+
+        for row in image.packed_rows() {
+            encoder.write_row(&row)?;
+        }
+
+        encoder.finish()
+    }
+}
+
+impl FilterStrategy {
+    /// Filters a scanline of an image described by `header`, adding the filter-type byte.
+    fn apply(self, header: &ImageHeader, row: &[u8], prior: &[u8]) -> Vec<u8> {
+        let bpp = filter::bytes_per_pixel(header.color_type, header.bit_depth);
+        let indexed = ColorType::from_code(header.color_type) == Some(ColorType::Indexed);
+        let use_filters = !indexed && header.bit_depth >= 8;
+
+        match self {
+            FilterStrategy::Fixed(filter_type) => filter::filter(filter_type, row, prior, bpp),
+            FilterStrategy::Adaptive if use_filters => filter::min_sum(row, prior, bpp),
+            FilterStrategy::Adaptive => filter::none(row),
+        }
+    }
+}
+
+/// Filters every scanline, adding the filter-type bytes.
+fn filter_image(image: &Image, strategy: FilterStrategy) -> Vec<u8> {
+    let header = image.header(false);
+
+    let row_size = header.row_size(image.width) + 1;
+    let mut filtered = Vec::with_capacity(row_size * image.height as usize);
+    let mut prior = Cow::Borrowed(&[][..]);
+
+    for row in image.packed_rows() {
+        filtered.extend_from_slice(&strategy.apply(&header, &row, &prior));
+        prior = row;
+    }
+
+    filtered
+}
+
+/// Filters every Adam7 pass as a separate image, one after the other.
+fn filter_interlaced(image: &Image, strategy: FilterStrategy) -> Vec<u8> {
+    let pixel_size = image.samples_per_pixel();
+    let mut filtered = Vec::new();
+
+    for (_, pass) in interlace::passes(&image.header(true)) {
+        let height = pass.height(image.height);
+        let data = match &image.data {
+            PixelData::U8(data) => PixelData::U8(gather_pass(&pass, image, pixel_size, data)),
+            PixelData::U16(data) => PixelData::U16(gather_pass(&pass, image, pixel_size, data)),
+        };
+
+        let reduced = Image {
+            width: pass.width(image.width),
+            height,
+            data,
+            palette: None,
+            transparency: None,
+            ..*image
+        };
+        filtered.extend_from_slice(&filter_image(&reduced, strategy));
+    }
+
+    filtered
+}
+
+/// The samples of the reduced image of `pass`.
+fn gather_pass<T: Copy>(pass: &Pass, image: &Image, pixel_size: usize, data: &[T]) -> Vec<T> {
+    (0..pass.height(image.height))
+        .flat_map(|row| {
+            interlace::gather_row(pass, pass.image_row(row), image.width, pixel_size, data)
+        })
+        .collect()
+}
+
+/// Smallest strip worth a thread, in bytes of filtered data.
+const MIN_STRIP_SIZE: usize = 1 << 16;
+
+/// Filters and compresses the image in horizontal strips, each one on its own thread, returning
+/// the zlib stream.
+fn compress_strips(image: &Image, options: &EncodeOptions) -> Vec<u8> {
+    let header = image.header(false);
+    let height = image.height as usize;
+    let row_size = header.row_size(image.width) + 1;
+
+    let strips = options
+        .threads
+        .min(row_size * height / MIN_STRIP_SIZE)
+        .max(1);
+    let strip_height = height.div_ceil(strips);
+    let rows: Vec<_> = image.packed_rows().collect();
+
+    let pieces: Vec<_> = std::thread::scope(|scope| {
+        let threads: Vec<_> = (0..height)
+            .step_by(strip_height)
+            .map(|start| {
+                let (rows, header) = (&rows, &header);
+                let end = (start + strip_height).min(height);
+
+                scope.spawn(move || {
+                    // The first row is filtered with the last one of the previous strip
+                    let mut prior = if start == 0 {
+                        &[][..]
+                    } else {
+                        &rows[start - 1]
+                    };
+                    let mut filtered = Vec::with_capacity(row_size * (end - start));
+                    for row in &rows[start..end] {
+                        filtered.extend_from_slice(&options.filter.apply(header, row, prior));
+                        prior = row;
+                    }
+
+                    zlib::compress_piece(&filtered, options.compression, end == height)
+                })
+            })
+            .collect();
+
+        threads
+            .into_iter()
+            .map(|thread| thread.join().expect("Encoder thread panicked"))
+            .collect()
+    });
+
+    zlib::join_pieces(pieces, options.compression)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Encodes a PNG scanline by scanline, writing IDAT chunks of `EncodeOptions::idat_size` bytes as
+/// soon as there is enough compressed data.
+///
+/// The rows must be given already packed in the format described by the header (see
+/// `ImageHeader::row_size`), from top to bottom. Interlaced images are not supported, since each
+/// Adam7 pass needs pixels from all over the image.
+///
+/// ```no_run
+/// # use png::{EncodeOptions, ImageHeader, StreamingEncoder};
+/// # fn main() -> std::io::Result<()> {
+/// let file = std::fs::File::create("gradient.png")?;
+/// let header = ImageHeader::new((256, 100_000), 8, ImageHeader::GREYSCALE, false);
+///
+/// let mut encoder = StreamingEncoder::new(file, header, EncodeOptions::default())?;
+/// let row: Vec<u8> = (0..=255).collect();
+/// for _ in 0..100_000 {
+///     encoder.write_row(&row)?;
+/// }
+/// encoder.finish()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct StreamingEncoder<W: Write> {
+    writer: ChunkWriter<W>,
+    header: ImageHeader,
+    options: EncodeOptions,
+    compressor: ZlibEncoder,
+    /// Previous unfiltered scanline
+    prior: Vec<u8>,
+    /// Compressed data not written yet, always shorter than an IDAT
+    compressed: Vec<u8>,
+    rows_written: u32,
+}
+
+impl<W: Write> StreamingEncoder<W> {
+    /// Writes the signature and the IHDR.
+    pub fn new(writer: W, header: ImageHeader, options: EncodeOptions) -> io::Result<Self> {
+        header.validate()?;
+        if header.interlace != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Interlaced images cannot be encoded row by row",
+            ));
+        }
+
+        let mut writer = ChunkWriter::new(writer);
+        writer.write_signature()?;
+        writer.write_chunk(&header)?;
+
+        Ok(Self {
+            writer,
+            header,
+            options,
+            compressor: ZlibEncoder::new(options.compression),
+            prior: Vec::new(),
+            compressed: Vec::new(),
+            rows_written: 0,
+        })
+    }
+
+    /// Writes a chunk that must go before the image data, such as PLTE or tRNS. The rest of the
+    /// critical chunks (IHDR, IDAT and IEND) are only written by the encoder.
+    pub fn write_chunk(&mut self, chunk: &dyn Chunk) -> io::Result<()> {
+        let chunk_type = chunk.get_type();
+        if chunk_type.is_critical() && chunk_type != PLTE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is a critical chunk and cannot be written", chunk_type),
+            ));
+        }
+        if self.rows_written > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Chunk {:?} must be written before the image data",
+                    chunk.get_type()
+                ),
+            ));
+        }
+
+        self.writer.write_chunk(chunk)?;
+        Ok(())
+    }
+
+    /// Filters and compresses the next scanline.
+    pub fn write_row(&mut self, row: &[u8]) -> io::Result<()> {
+        let row_size = self.header.row_size(self.header.width);
+        if row.len() != row_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Expected a row of {} bytes, got {}", row_size, row.len()),
+            ));
+        }
+        if self.rows_written == self.header.height {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("The image only has {} rows", self.header.height),
+            ));
+        }
+
+        let filtered = self.options.filter.apply(&self.header, row, &self.prior);
+        self.compressor.write(&filtered, &mut self.compressed);
+        self.write_idats(false)?;
+
+        self.prior.clear();
+        self.prior.extend_from_slice(row);
+        self.rows_written += 1;
+
+        Ok(())
+    }
+
+    /// Writes the rest of the image data and the IEND, returning the total number of bytes
+    /// written.
+    pub fn finish(mut self) -> io::Result<u64> {
+        if self.rows_written != self.header.height {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Only {} of {} rows were written",
+                    self.rows_written, self.header.height
+                ),
+            ));
+        }
+
+        let compressor = std::mem::replace(
+            &mut self.compressor,
+            ZlibEncoder::new(self.options.compression),
+        );
+        compressor.finish(&mut self.compressed);
+        self.write_idats(true)?;
+
+        self.writer.write_chunk(&ImageTrailer)?;
+        self.writer.flush()?;
+
+        Ok(self.writer.bytes_written())
+    }
+
+    /// Writes the compressed data as IDAT chunks of the configured size. The last one may be
+    /// smaller only if `all` is set.
+    fn write_idats(&mut self, all: bool) -> io::Result<()> {
+        let idat_size = self.options.idat_size.max(1);
+
+        let mut written = 0;
+        while self.compressed.len() - written >= idat_size
+            || (all && written < self.compressed.len())
+        {
+            let end = (written + idat_size).min(self.compressed.len());
+            self.writer
+                .write_raw_chunk(IDAT, &self.compressed[written..end])?;
+            written = end;
+        }
+
+        self.compressed.drain(..written);
+        Ok(())
+    }
+}
+
+fn check_image(image: &Image, header: &ImageHeader) -> io::Result<()> {
+    let invalid = |message: String| Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+
+    header.validate()?;
+
+    let len = match (&image.data, image.bit_depth) {
+        (PixelData::U16(data), 16) => data.len(),
+        (PixelData::U8(data), 1..=8) => data.len(),
+        _ => {
+            return invalid(format!(
+                "The samples of {}-bit images must be {}",
+                image.bit_depth,
+                if image.bit_depth == 16 { "u16" } else { "u8" }
+            ))
+        }
+    };
+
+    let expected_len = image.samples_per_row() * image.height as usize;
+    if len != expected_len {
+        return invalid(format!(
+            "Expected {} samples of image data, got {}",
+            expected_len, len
+        ));
+    }
+
+    // Samples smaller than a byte are packed, so extra bits would be lost
+    if let (PixelData::U8(data), 1 | 2 | 4) = (&image.data, image.bit_depth) {
+        let max_sample = (1 << image.bit_depth) - 1;
+        if let Some(sample) = data.iter().find(|&&sample| sample > max_sample) {
+            return invalid(format!(
+                "Sample {} does not fit in {} bits",
+                sample, image.bit_depth
+            ));
+        }
+    }
+
+    match (&image.palette, image.color()) {
+        (None, Some(ColorType::Indexed)) => {
+            return invalid("Indexed-colour images need a palette".to_string())
+        }
+        (Some(_), Some(ColorType::Greyscale | ColorType::GreyscaleAlpha)) => {
+            return invalid("Greyscale images cannot have a palette".to_string())
+        }
+        (Some(palette), _) => {
+            let max_entries = 1 << image.bit_depth.min(8);
+            if palette.entries.is_empty() || palette.entries.len() > max_entries {
+                return invalid(format!(
+                    "The palette must have between 1 and {} entries, got {}",
+                    max_entries,
+                    palette.entries.len()
+                ));
+            }
+        }
+        (None, _) => {}
+    }
+
+    if let Some(transparency) = &image.transparency {
+        let valid = match image.color() {
+            Some(ColorType::Greyscale) => transparency.grey_value().is_some(),
+            Some(ColorType::Truecolour) => transparency.rgb_value().is_some(),
+            Some(ColorType::Indexed) => image
+                .palette
+                .as_ref()
+                .is_some_and(|palette| transparency.data.len() <= palette.entries.len()),
+            _ => false,
+        };
+
+        if !valid {
+            return invalid(format!("Invalid tRNS for color type {}", image.color_type));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::chunks::Gamma;
+
+    #[test]
+    fn streaming_test() {
+        let (width, height) = (97, 211);
+        let data = (0..width * height * 3).map(|i| (i * i / 7) as u8).collect();
+        let image = Image::rgb(width, height, data);
+
+        let options = EncodeOptions {
+            idat_size: 1000,
+            ..Default::default()
+        };
+
+        // Row by row must give the same file as encoding everything at once
+        let mut streamed = Vec::new();
+        let size = Png::encode(&image, &options, &mut streamed).unwrap();
+        let png = Png::from_image(&image, &options).unwrap();
+
+        assert_eq!(size, streamed.len() as u64);
+        assert_eq!(streamed, png.to_vec());
+        assert!(png
+            .chunks_of::<ImageData>()
+            .all(|idat| idat.data.len() <= 1000));
+    }
+
+    #[test]
+    fn parallel_test() {
+        let (width, height) = (300, 301);
+        let data = (0..width * height * 3)
+            .map(|i| (i % 251 + i / 900) as u8)
+            .collect();
+        let image = Image::rgb(width, height, data);
+
+        let options = EncodeOptions {
+            threads: 4,
+            ..Default::default()
+        };
+        let png = Png::from_image(&image, &options).unwrap();
+        assert_eq!(png.decode().unwrap(), image);
+
+        let mut encoded = Vec::new();
+        Png::encode(&image, &options, &mut encoded).unwrap();
+        assert_eq!(encoded, png.to_vec());
+
+        // Too small to be split
+        let small = Image::rgb(2, 2, vec![7; 12]);
+        let png = Png::from_image(&small, &options).unwrap();
+        assert_eq!(
+            png.to_vec(),
+            Png::from_image(&small, &Default::default())
+                .unwrap()
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn interlace_test() {
+        let options = EncodeOptions {
+            interlace: true,
+            ..Default::default()
+        };
+
+        let grey = Image::new(
+            13,
+            7,
+            ImageHeader::GREYSCALE,
+            2,
+            [0, 1, 2, 3].repeat(13 * 7 / 4 + 1)[..13 * 7].to_vec(),
+        );
+        let rgb = Image::new_16(
+            9,
+            10,
+            ImageHeader::TRUECOLOUR,
+            (0..9 * 10 * 3_u16).map(|i| i.wrapping_mul(701)).collect(),
+        );
+        let tiny = Image::grey(1, 1, vec![42]);
+
+        for image in [grey, rgb, tiny] {
+            let png = Png::from_image(&image, &options).unwrap();
+            assert_eq!(png.header().interlace, 1);
+            assert_eq!(png.decode().unwrap(), image);
+
+            let mut encoded = Vec::new();
+            Png::encode(&image, &options, &mut encoded).unwrap();
+            assert_eq!(encoded, png.to_vec());
+        }
+    }
+
+    #[test]
+    fn streaming_rows_test() {
+        let header = ImageHeader::new((4, 2), 8, ImageHeader::GREYSCALE, false);
+        let mut encoder = StreamingEncoder::new(Vec::new(), header, Default::default()).unwrap();
+
+        // Only the encoder writes IHDR, IDAT and IEND
+        assert!(encoder.write_chunk(&header).is_err());
+        assert!(encoder.write_chunk(&ImageData::from_bytes(&[])).is_err());
+        assert!(encoder.write_chunk(&ImageTrailer).is_err());
+        encoder.write_chunk(&Gamma::new(0.5)).unwrap();
+
+        assert!(encoder.write_row(&[1, 2, 3]).is_err());
+        encoder.write_row(&[1, 2, 3, 4]).unwrap();
+        assert!(encoder.write_chunk(&ImageTrailer).is_err());
+        encoder.write_row(&[1, 2, 3, 4]).unwrap();
+        assert!(encoder.write_row(&[1, 2, 3, 4]).is_err());
+        assert!(encoder.finish().is_ok());
+    }
+}
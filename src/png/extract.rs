@@ -0,0 +1,184 @@
+//! Extraction and injection of raw chunk payloads, to move data like ICC profiles, Exif blocks or
+//! private metadata in and out of a file without touching the image.
+//!
+//! When extracting, compressed payloads are decompressed unless the raw data is requested:
+//!
+//! ```text
+//! | Chunk | Extracted data                                               |
+//! |-------|--------------------------------------------------------------|
+//! | IDAT  | All the image data, decompressed (filtered scanlines)        |
+//! | iCCP  | The ICC profile                                              |
+//! | zTXt  | The text, in Latin-1                                         |
+//! | iTXt  | The text, in UTF-8 (only decompressed if the flag is set)    |
+//! | Other | The data of the first chunk of the type, as it is            |
+//! ```
+//!
+//! The decompressed IDAT data is limited to the size of the filtered scanlines given by IHDR, and
+//! the rest to `MAX_DECOMPRESSED` bytes, since a few bytes can decompress to gigabytes.
+//!
+//! Injected chunks are inserted at the place required by the spec (see module `edit`) with their
+//! data as given, and the CRC is calculated when the file is written. Only ancillary chunks can be
+//! injected, since critical ones change how the image is decoded.
+
+use super::{
+    chunks::{self, split_null, ChunkType, IccProfile, IDAT},
+    decoder, Png,
+};
+use crate::compression::zlib;
+use std::io;
+
+/// Largest decompressed zTXt or iTXt text extracted.
+const MAX_DECOMPRESSED: usize = 1 << 24;
+
+fn invalid_input<T>(message: String) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidInput, message))
+}
+
+fn invalid_data<T>(message: String) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+impl Png {
+    /// Returns the payload of the chunks of type `chunk_type`, decompressed as described in the
+    /// module documentation unless `raw` is set. With `raw`, the data of all IDAT chunks is still
+    /// concatenated.
+    pub fn extract(&self, chunk_type: ChunkType, raw: bool) -> io::Result<Vec<u8>> {
+        if chunk_type == IDAT {
+            let data: Vec<u8> = self
+                .chunks_by_type(IDAT)
+                .flat_map(|chunk| chunk.data_to_bytes())
+                .collect();
+            if raw {
+                return Ok(data);
+            }
+
+            self.header.validate()?;
+            let size = decoder::filtered_size(&self.header);
+            return zlib::decompress_limited(&data, size.try_into().unwrap_or(usize::MAX));
+        }
+
+        let Some(chunk) = self.chunks_by_type(chunk_type).next() else {
+            return invalid_input(format!("There is no {:?} chunk", chunk_type));
+        };
+        let data = chunk.data_to_bytes();
+        if raw {
+            return Ok(data);
+        }
+
+        match chunk_type.as_bytes() {
+            b"iCCP" => IccProfile::from_bytes(&data).profile(),
+            b"zTXt" => {
+                let (_, rest) = split_null(&data)?;
+                match rest.split_first() {
+                    Some((0, compressed)) => zlib::decompress_limited(compressed, MAX_DECOMPRESSED),
+                    _ => invalid_data("Unknown zTXt compression method".to_string()),
+                }
+            }
+            b"iTXt" => {
+                let (_, rest) = split_null(&data)?;
+                let [flag, method, rest @ ..] = rest else {
+                    return invalid_data("Missing compression flag and method".to_string());
+                };
+                let (_, rest) = split_null(rest)?;
+                let (_, text) = split_null(rest)?;
+                match (flag, method) {
+                    (0, _) => Ok(text.to_vec()),
+                    (1, 0) => zlib::decompress_limited(text, MAX_DECOMPRESSED),
+                    _ => invalid_data("Unknown iTXt compression method".to_string()),
+                }
+            }
+            _ => Ok(data),
+        }
+    }
+
+    /// Inserts an ancillary chunk with the given data at the place required by the spec. Fails if
+    /// the data is not valid for a known chunk type, or if the chunk can only appear once and
+    /// there is already one.
+    pub fn inject(&mut self, chunk_type: ChunkType, data: &[u8]) -> io::Result<()> {
+        if chunk_type.is_critical() {
+            return invalid_input(format!(
+                "{:?} is a critical chunk and cannot be injected",
+                chunk_type
+            ));
+        }
+        if !chunk_type.is_reserved_bit_valid() {
+            return invalid_input(format!(
+                "{:?} has the reserved bit set and cannot be injected",
+                chunk_type
+            ));
+        }
+
+        let chunk = chunks::parse(chunk_type, data)?;
+        self.insert(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::{
+        chunks::{ImageHeader, ICCP},
+        encoder::EncodeOptions,
+        image::Image,
+    };
+
+    #[test]
+    fn extract_inject_test() {
+        let image = Image::grey(3, 2, vec![1, 2, 3, 4, 5, 6]);
+        let mut png = Png::from_image(&image, &EncodeOptions::default()).unwrap();
+
+        let profile = b"not really an ICC profile";
+        png.insert(Box::new(IccProfile::new("test", profile)))
+            .unwrap();
+        assert_eq!(png.extract(ICCP, false).unwrap(), profile);
+
+        let mut itxt = b"key\0\x01\x00en\0\0".to_vec();
+        itxt.extend(zlib::compress(b"text", Default::default()));
+        png.inject(ChunkType::from_code("iTXt").unwrap(), &itxt)
+            .unwrap();
+        assert_eq!(
+            png.extract(ChunkType::from_code("iTXt").unwrap(), false)
+                .unwrap(),
+            b"text"
+        );
+        assert_eq!(
+            png.extract(ChunkType::from_code("iTXt").unwrap(), true)
+                .unwrap(),
+            itxt
+        );
+
+        // Private chunks go at the end, and are written with their CRC
+        let preview = ChunkType::from_code("prVw").unwrap();
+        png.inject(preview, b"build 42").unwrap();
+        assert_eq!(png.chunks().last().unwrap().get_type(), preview);
+        let read = Png::read_from(&png.to_vec()[..]).unwrap();
+        assert_eq!(read.extract(preview, false).unwrap(), b"build 42");
+
+        // The filtered scanlines, with a filter-type byte each
+        assert_eq!(read.extract(IDAT, false).unwrap().len(), 2 * 4);
+
+        // More image data than IHDR describes
+        let mut small = Png::read_from(&png.to_vec()[..]).unwrap();
+        *small.header_mut() = ImageHeader::new((1, 1), 8, ImageHeader::GREYSCALE, false);
+        assert!(small.extract(IDAT, false).is_err());
+        assert_eq!(
+            small.extract(IDAT, true).unwrap(),
+            png.extract(IDAT, true).unwrap()
+        );
+
+        assert!(png.inject(ICCP, &png.extract(ICCP, true).unwrap()).is_err());
+        assert!(png
+            .inject(ChunkType::from_code("IDAT").unwrap(), b"")
+            .is_err());
+        assert!(png
+            .inject(ChunkType::from_code("gAMA").unwrap(), b"12")
+            .is_err());
+        assert!(png
+            .extract(ChunkType::from_code("tEXt").unwrap(), false)
+            .is_err());
+        assert!(png
+            .inject(ChunkType::from_code("prvw").unwrap(), b"")
+            .is_err());
+        assert!(ChunkType::from_code("pr1w").is_err());
+    }
+}
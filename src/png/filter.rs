@@ -22,6 +22,36 @@
 //! top to bottom.
 //!
 //! Unsigned arithmetic modulo 256 is used, so both inputs and outputs fit into into bytes.
+//!
+//! The inverse functions here are the simple reference versions. Decoding uses the faster kernels
+//! of module `unfilter`.
+
+use super::{color::ColorType, unfilter};
+use std::io;
+
+/// Filter-type byte that precedes each filtered scanline.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FilterType {
+    None = 0,
+    Sub = 1,
+    Up = 2,
+    Average = 3,
+    Paeth = 4,
+}
+
+impl FilterType {
+    pub const ALL: [FilterType; 5] = [
+        FilterType::None,
+        FilterType::Sub,
+        FilterType::Up,
+        FilterType::Average,
+        FilterType::Paeth,
+    ];
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Self::ALL.get(byte as usize).copied()
+    }
+}
 
 /// bpp stands for bytes per complete pixel, rounding up to 1. It depends on the bit depth and
 /// color type set on the IHDR chunk.
@@ -32,13 +62,66 @@
 /// - Color type 0, bit depth 2  => `bpp` is 1 (rounding up)
 /// - Color type 4, bit depth 16 => `bpp` is 4 (two-byte greyscale sample, plus two-byte alpha sample).
 pub fn bytes_per_pixel(color_type: u8, bit_depth: u8) -> u8 {
-    let mut n_samples = 1; // Greyscale or index: 1 sample
-    n_samples += color_type & (1 << 1); // RGB: +2 samples (not shift back, it is multiplied by 2)
-    n_samples += (color_type & (1 << 2)) >> 2; // Add 1 sample for alpha
+    let samples = ColorType::from_code(color_type).map_or(1, ColorType::samples) as u8;
+    samples * bit_depth.div_ceil(8)
+}
+
+/// Applies the given filter type to the scanline.
+pub fn filter(filter_type: FilterType, scanline: &[u8], prior_scanline: &[u8], bpp: u8) -> Vec<u8> {
+    match filter_type {
+        FilterType::None => none(scanline),
+        FilterType::Sub => sub(scanline, bpp),
+        FilterType::Up => up(scanline, prior_scanline),
+        FilterType::Average => average(scanline, prior_scanline, bpp),
+        FilterType::Paeth => paeth(scanline, prior_scanline, bpp),
+    }
+}
+
+/// Tries every filter type and keeps the one with the minimum sum of absolute differences: the
+/// filtered bytes are treated as signed values (any value over 127 is negative) and the filter
+/// whose absolute values add up to the smallest sum is chosen.
+///
+/// This is the heuristic recommended by libPNG for greyscale and truecolor images of 8 or 16 bits
+/// per sample. It is biased against `None`, since it prefers sequences of values close to zero.
+pub fn min_sum(scanline: &[u8], prior_scanline: &[u8], bpp: u8) -> Vec<u8> {
+    FilterType::ALL
+        .iter()
+        .map(|&filter_type| filter(filter_type, scanline, prior_scanline, bpp))
+        .min_by_key(|filtered| {
+            filtered[1..]
+                .iter()
+                .map(|&byte| (byte as i8).unsigned_abs() as u64)
+                .sum::<u64>()
+        })
+        .unwrap()
+}
+
+/// Reverses the filter of a scanline (that starts with its filter-type byte), given the prior
+/// scanline already unfiltered. See module `unfilter` to do it in place.
+pub fn unfilter(filtered: &[u8], prior_scanline: &[u8], bpp: u8) -> io::Result<Vec<u8>> {
+    let filter_type = parse_type(filtered.first().copied())?;
+
+    let mut original = filtered[1..].to_vec();
+    unfilter::unfilter(filter_type, &mut original, prior_scanline, bpp);
+    Ok(original)
+}
 
-    // Bytes per sample
-    let bps = ((bit_depth & (1 << 4)) >> 4) + 1; // If 16, 2 bytes. 1 byte otherwise.
-    n_samples * bps
+/// Reads the filter-type byte of a scanline, if there is one.
+pub fn parse_type(byte: Option<u8>) -> io::Result<FilterType> {
+    byte.and_then(FilterType::from_byte).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid filter type {:?}", byte),
+        )
+    })
+}
+
+/// Filter type 0: the scanline is transmitted unmodified, only the filter-type byte is added.
+pub fn none(scanline: &[u8]) -> Vec<u8> {
+    let mut filtered = Vec::with_capacity(scanline.len() + 1);
+    filtered.push(0);
+    filtered.extend_from_slice(scanline);
+    filtered
 }
 
 /// Transmits the difference between each byte and the value of the corresponding byte of the prior
@@ -46,7 +129,7 @@ pub fn bytes_per_pixel(color_type: u8, bit_depth: u8) -> u8 {
 ///
 /// Formula for each byte (being x a byte):
 ///
-/// ```
+/// ```text
 /// Sub(x) = Raw(x) - Raw(x - bpp)
 /// ```
 pub fn sub(scanline: &[u8], bpp: u8) -> Vec<u8> {
@@ -64,7 +147,7 @@ pub fn sub(scanline: &[u8], bpp: u8) -> Vec<u8> {
 
 /// The inverse of the `sub` filter:
 ///
-/// ```
+/// ```text
 /// Sub(x) + Raw(x - bpp)
 /// ```
 pub fn sub_inv(filtered: &[u8], bpp: u8) -> Vec<u8> {
@@ -84,7 +167,7 @@ pub fn sub_inv(filtered: &[u8], bpp: u8) -> Vec<u8> {
 ///
 /// Formula for each byte (being x a byte):
 ///
-/// ```
+/// ```text
 /// Up(x) = Raw(x) - Prior(x)
 /// ```
 ///
@@ -116,7 +199,7 @@ pub fn up_inv(filtered: &[u8], prior_scanline: &[u8]) -> Vec<u8> {
 
 /// Mix of the methods `Sub()` and `Up()`: takes the average of the left and above pixel.
 ///
-/// ```
+/// ```text
 /// Average(x) = Raw(x) - floor( (Raw(x - bpp) + Prior(x)) / 2)
 /// ```
 pub fn average(scanline: &[u8], prior_scanline: &[u8], bpp: u8) -> Vec<u8> {
@@ -126,7 +209,7 @@ pub fn average(scanline: &[u8], prior_scanline: &[u8], bpp: u8) -> Vec<u8> {
     for (i, byte) in scanline.iter().enumerate() {
         let left_byte = if i < bpp { 0 } else { scanline[i - bpp] };
         let top_byte = prior_scanline.get(i).unwrap_or(&0);
-        let floor = (left_byte as u16 + *top_byte as u16) >> 2;
+        let floor = (left_byte as u16 + *top_byte as u16) >> 1;
 
         filtered[i] = byte.wrapping_sub(floor as u8);
     }
@@ -137,7 +220,7 @@ pub fn average(scanline: &[u8], prior_scanline: &[u8], bpp: u8) -> Vec<u8> {
 
 /// Inverse of the `Average()` filter:
 ///
-/// ```
+/// ```text
 /// Average(x) + floor((Raw(x-bpp)+Prior(x))/2)
 /// ```
 pub fn average_inv(filtered: &[u8], prior_scanline: &[u8], bpp: u8) -> Vec<u8> {
@@ -147,7 +230,7 @@ pub fn average_inv(filtered: &[u8], prior_scanline: &[u8], bpp: u8) -> Vec<u8> {
     for (i, byte) in filtered.iter().skip(1).enumerate() {
         let left_byte = if i < bpp { 0 } else { original[i - bpp] };
         let top_byte = prior_scanline.get(i).unwrap_or(&0);
-        let floor = (left_byte as u16 + *top_byte as u16) >> 2;
+        let floor = (left_byte as u16 + *top_byte as u16) >> 1;
 
         original[i] = byte.wrapping_add(floor as u8);
     }
@@ -158,7 +241,7 @@ pub fn average_inv(filtered: &[u8], prior_scanline: &[u8], bpp: u8) -> Vec<u8> {
 /// The Paeth filter computes a simple linear function of the three neighbouring pixels, and then
 /// chooses the pixel closest to the computed value.
 ///
-/// ```
+/// ```text
 /// Paeth(x) = Raw(x) - PaethPredictor(Raw(x-bpp), Prior(x), Prior(x-bpp))
 /// ```
 pub fn paeth(scanline: &[u8], prior_scanline: &[u8], bpp: u8) -> Vec<u8> {
@@ -183,7 +266,7 @@ pub fn paeth(scanline: &[u8], prior_scanline: &[u8], bpp: u8) -> Vec<u8> {
 
 /// Inverse of the `Paeth()` filter
 ///
-/// ```
+/// ```text
 /// Paeth(x) + PaethPredictor(Raw(x-bpp), Prior(x), Prior(x-bpp))
 /// ```
 pub fn paeth_inv(filtered: &[u8], prior_scanline: &[u8], bpp: u8) -> Vec<u8> {
@@ -205,14 +288,14 @@ pub fn paeth_inv(filtered: &[u8], prior_scanline: &[u8], bpp: u8) -> Vec<u8> {
     original
 }
 
-fn paeth_predictor(left: u8, top: u8, upleft: u8) -> u8 {
+pub(crate) fn paeth_predictor(left: u8, top: u8, upleft: u8) -> u8 {
     let p = left as i16 + top as i16 - upleft as i16;
 
     let dist_left = i16::abs_diff(p, left as i16);
     let dist_top = i16::abs_diff(p, top as i16);
     let dist_upleft = i16::abs_diff(p, upleft as i16);
 
-    if dist_left <= dist_top {
+    if dist_left <= dist_top && dist_left <= dist_upleft {
         left
     } else if dist_top <= dist_upleft {
         top
@@ -233,6 +316,35 @@ mod tests {
         assert_eq!(bytes_per_pixel(0, 2), 1);
         // Greyscale with alpha => 2 samples, 2 bytes per sample
         assert_eq!(bytes_per_pixel(4, 16), 4);
+        // Indexed-colour => 1 sample (the palette index), even though the color bit is set
+        assert_eq!(bytes_per_pixel(3, 8), 1);
+    }
+
+    #[test]
+    fn predictor_test() {
+        // Average uses the floor of the mean of the left and top bytes
+        assert_eq!(average(&[10, 10], &[3, 4], 1), vec![3, 9, 3]);
+
+        // Paeth predictor: p = 10 + 20 - 15 = 15, so the upper left byte is the nearest
+        assert_eq!(paeth_predictor(10, 20, 15), 15);
+        // p = 30 + 12 - 10 = 32, closer to the left byte than to the rest
+        assert_eq!(paeth_predictor(30, 12, 10), 30);
+        // p = 5 + 100 - 90 = 15: left is at 10, top at 85 and upleft at 75
+        assert_eq!(paeth_predictor(5, 100, 90), 5);
+        // Ties between left and upleft prefer left, ties between top and upleft prefer top
+        assert_eq!(paeth_predictor(10, 10, 10), 10);
+        assert_eq!(paeth_predictor(0, 30, 10), 30);
+    }
+
+    #[test]
+    fn min_sum_test() {
+        // A ramp is turned into a run of ones by Sub
+        let scanline = [10, 20, 30, 40, 50, 60];
+        assert_eq!(min_sum(&scanline, &[], 1)[0], FilterType::Sub as u8);
+
+        // A copy of the row above is all zeros using Up
+        let prior = [200, 3, 99, 42, 17, 250];
+        assert_eq!(min_sum(&prior, &prior, 1), vec![2, 0, 0, 0, 0, 0, 0]);
     }
 
     #[test]
@@ -0,0 +1,319 @@
+//! A minimal reader of ICC profiles (versions 2 and 4), the colour profiles embedded in iCCP
+//! chunks. A profile has a 128-byte header followed by a table of tags:
+//!
+//! ```text
+//! | Header (128 bytes) | Tag count (4 bytes) | Tag table (12 bytes per tag) | Tag data ... |
+//! ```
+//!
+//! Each entry of the tag table has the signature of the tag, and the offset (from the start of the
+//! profile) and size of its data. The data of each tag starts with a four-byte type signature and
+//! four reserved bytes.
+//!
+//! Only matrix/TRC profiles are supported, which are the ones used by most images. They describe
+//! how to go from the samples to the Profile Connection Space (PCS, CIE XYZ under D50 light):
+//!
+//! - RGB: each sample goes through its Tone Reproduction Curve (tags `rTRC`, `gTRC` and `bTRC`),
+//!   which gives linear values, and then they are multiplied by a matrix whose columns are the
+//!   XYZ of each primary (tags `rXYZ`, `gXYZ` and `bXYZ`).
+//! - Greyscale: the sample goes through a single curve (tag `kTRC`) and gives the Y of the PCS.
+//!
+//! Profiles based on lookup tables (tags `A2B0` and similar) are not supported.
+
+use super::colorspace::{Curve, Matrix};
+use std::io;
+
+/// The parts of an ICC profile needed to convert the samples to the PCS.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    /// Major and minor version
+    pub version: (u8, u8),
+    /// Colour space of the samples: `RGB ` or `GRAY`
+    pub color_space: [u8; 4],
+    /// XYZ of the PCS white point, which should be D50
+    pub illuminant: [f64; 3],
+    /// Converts linear RGB to PCS XYZ: its columns are the XYZ of each primary. `None` for
+    /// greyscale profiles.
+    pub colorants: Option<Matrix>,
+    /// Curves of red, green and blue, or the grey curve three times for greyscale profiles
+    pub curves: [Curve; 3],
+}
+
+fn invalid<T>(message: &str) -> io::Result<T> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid ICC profile: {}", message),
+    ))
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Signed fixed point number with 16 fractional bits.
+fn s15_fixed16_at(data: &[u8], offset: usize) -> f64 {
+    u32_at(data, offset) as i32 as f64 / 65536.0
+}
+
+/// Reads the three numbers of an XYZ type.
+fn xyz_at(data: &[u8], offset: usize) -> [f64; 3] {
+    [0, 4, 8].map(|i| s15_fixed16_at(data, offset + i))
+}
+
+/// Reads only the data colour space signature of the header (like `GRAY` or `RGB `), which is
+/// enough to know what images the profile can describe, even if `parse` does not support it.
+pub fn color_space(data: &[u8]) -> Option<[u8; 4]> {
+    if data.get(36..40) != Some(b"acsp") {
+        return None;
+    }
+    data[16..20].try_into().ok()
+}
+
+pub fn parse(data: &[u8]) -> io::Result<Profile> {
+    if data.len() < 132 || &data[36..40] != b"acsp" {
+        return invalid("wrong header");
+    }
+    if (u32_at(data, 0) as usize) > data.len() {
+        return invalid("truncated profile");
+    }
+
+    let version = (data[8], data[9] >> 4);
+    let color_space: [u8; 4] = data[16..20].try_into().unwrap();
+    if &data[20..24] != b"XYZ " {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Only ICC profiles with XYZ as PCS are supported",
+        ));
+    }
+    let illuminant = xyz_at(data, 68);
+
+    let tag_count = u32_at(data, 128) as usize;
+    if data.len() < 132 + 12 * tag_count {
+        return invalid("truncated tag table");
+    }
+
+    let tag = |signature: &[u8; 4]| -> io::Result<Option<&[u8]>> {
+        for entry in data[132..132 + 12 * tag_count].chunks_exact(12) {
+            if &entry[..4] != signature {
+                continue;
+            }
+
+            let offset = u32_at(entry, 4) as usize;
+            let size = u32_at(entry, 8) as usize;
+            return match data.get(offset..offset.saturating_add(size)) {
+                Some(tag) if size >= 8 => Ok(Some(tag)),
+                _ => invalid("tag out of bounds"),
+            };
+        }
+        Ok(None)
+    };
+
+    let unsupported = || {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Only matrix/TRC ICC profiles are supported",
+        ))
+    };
+
+    match &color_space {
+        b"RGB " => {
+            let mut colorants = [[0.0; 3]; 3];
+            let mut curves = [Curve::Identity, Curve::Identity, Curve::Identity];
+
+            for (i, (xyz, trc)) in [(b"rXYZ", b"rTRC"), (b"gXYZ", b"gTRC"), (b"bXYZ", b"bTRC")]
+                .into_iter()
+                .enumerate()
+            {
+                let (Some(xyz), Some(trc)) = (tag(xyz)?, tag(trc)?) else {
+                    return unsupported();
+                };
+                if &xyz[..4] != b"XYZ " || xyz.len() < 20 {
+                    return invalid("wrong XYZ tag");
+                }
+
+                let [x, y, z] = xyz_at(xyz, 8);
+                colorants[0][i] = x;
+                colorants[1][i] = y;
+                colorants[2][i] = z;
+                curves[i] = parse_curve(trc)?;
+            }
+
+            Ok(Profile {
+                version,
+                color_space,
+                illuminant,
+                colorants: Some(colorants),
+                curves,
+            })
+        }
+
+        b"GRAY" => {
+            let Some(trc) = tag(b"kTRC")? else {
+                return unsupported();
+            };
+            let curve = parse_curve(trc)?;
+
+            Ok(Profile {
+                version,
+                color_space,
+                illuminant,
+                colorants: None,
+                curves: [curve.clone(), curve.clone(), curve],
+            })
+        }
+
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "Unsupported ICC colour space {:?}",
+                String::from_utf8_lossy(&color_space)
+            ),
+        )),
+    }
+}
+
+/// Reads a curve of type `curv` (version 2 and 4) or `para` (version 4).
+fn parse_curve(data: &[u8]) -> io::Result<Curve> {
+    match &data[..4] {
+        // Count, and then the gamma as an unsigned fixed point number with 8 fractional bits (if
+        // there is one value) or a table of values that are linearly interpolated
+        b"curv" if data.len() >= 12 => {
+            let count = u32_at(data, 8) as usize;
+            if data.len() < 12 + 2 * count {
+                return invalid("truncated curve");
+            }
+
+            Ok(match count {
+                0 => Curve::Identity,
+                1 => Curve::Gamma(u16_at(data, 12) as f64 / 256.0),
+                _ => Curve::Table(
+                    (0..count)
+                        .map(|i| u16_at(data, 12 + 2 * i) as f64 / 65535.0)
+                        .collect(),
+                ),
+            })
+        }
+
+        // Function type, two reserved bytes and its parameters (see `Curve::Parametric`)
+        b"para" if data.len() >= 12 => {
+            let function = u16_at(data, 8);
+            let count = match function {
+                0 => 1,
+                1 => 3,
+                2 => 4,
+                3 => 5,
+                4 => 7,
+                _ => return invalid("unknown parametric curve"),
+            };
+            if data.len() < 12 + 4 * count {
+                return invalid("truncated curve");
+            }
+
+            let p: Vec<f64> = (0..count)
+                .map(|i| s15_fixed16_at(data, 12 + 4 * i))
+                .collect();
+            // All of them are special cases of function type 4
+            let [g, a, b, c, d, e, f] = match function {
+                0 => [p[0], 1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+                1 => [p[0], p[1], p[2], 0.0, -p[2] / p[1], 0.0, 0.0],
+                2 => [p[0], p[1], p[2], 0.0, -p[2] / p[1], p[3], p[3]],
+                3 => [p[0], p[1], p[2], p[3], p[4], 0.0, 0.0],
+                _ => [p[0], p[1], p[2], p[3], p[4], p[5], p[6]],
+            };
+            Ok(Curve::Parametric([g, a, b, c, d, e, f]))
+        }
+
+        _ => invalid("unknown curve type"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::colorspace::ColorProfile;
+
+    /// Writes a profile with the given colour space and tags.
+    fn build(color_space: &[u8; 4], tags: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let fixed = |value: f64| ((value * 65536.0).round() as i32).to_be_bytes();
+
+        let mut header = vec![0; 128];
+        header[8] = 4;
+        header[16..20].copy_from_slice(color_space);
+        header[20..24].copy_from_slice(b"XYZ ");
+        header[36..40].copy_from_slice(b"acsp");
+        for (i, value) in [0.9642, 1.0, 0.8249].into_iter().enumerate() {
+            header[68 + 4 * i..72 + 4 * i].copy_from_slice(&fixed(value));
+        }
+
+        let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+        let mut data = Vec::new();
+        let start = 132 + 12 * tags.len();
+        for (signature, tag) in tags {
+            table.extend_from_slice(*signature);
+            table.extend_from_slice(&((start + data.len()) as u32).to_be_bytes());
+            table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+            data.extend_from_slice(tag);
+        }
+
+        let mut profile = [header, table, data].concat();
+        let size = profile.len() as u32;
+        profile[..4].copy_from_slice(&size.to_be_bytes());
+        profile
+    }
+
+    fn xyz_tag(xyz: [f64; 3]) -> Vec<u8> {
+        let mut tag = b"XYZ \0\0\0\0".to_vec();
+        for value in xyz {
+            tag.extend_from_slice(&((value * 65536.0).round() as i32).to_be_bytes());
+        }
+        tag
+    }
+
+    #[test]
+    fn parse_test() {
+        // The sRGB curve as a parametric function
+        let mut para = b"para\0\0\0\0\0\x03\0\0".to_vec();
+        for value in [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045] {
+            para.extend_from_slice(&((value * 65536.0_f64).round() as i32).to_be_bytes());
+        }
+        // A gamma of 1.8, and a table
+        let gamma = b"curv\0\0\0\0\0\0\0\x01\x01\xCD".to_vec();
+        let table = b"curv\0\0\0\0\0\0\0\x03\0\0\x40\0\xFF\xFF".to_vec();
+
+        let profile = build(
+            b"RGB ",
+            &[
+                (b"rXYZ", xyz_tag([0.4361, 0.2225, 0.0139])),
+                (b"gXYZ", xyz_tag([0.3851, 0.7169, 0.0971])),
+                (b"bXYZ", xyz_tag([0.1431, 0.0606, 0.7141])),
+                (b"rTRC", para),
+                (b"gTRC", gamma),
+                (b"bTRC", table),
+            ],
+        );
+
+        let parsed = parse(&profile).unwrap();
+        assert_eq!(parsed.version.0, 4);
+        assert!((parsed.colorants.unwrap()[1][1] - 0.7169).abs() < 1e-4);
+        assert!((parsed.curves[0].to_linear(0.5) - 0.214).abs() < 1e-3);
+        assert!((parsed.curves[1].to_linear(0.5) - 0.5_f64.powf(1.8)).abs() < 1e-3);
+        assert!((parsed.curves[2].to_linear(0.25) - 0.125).abs() < 1e-3);
+
+        // These are the sRGB primaries adapted to D50, so the conversion to sRGB does nothing
+        let matrix = ColorProfile::from_icc(&profile).unwrap().matrix;
+        for (i, row) in matrix.iter().enumerate() {
+            for (j, value) in row.iter().enumerate() {
+                let expected = if i == j { 1.0 } else { 0.0 };
+                assert!((value - expected).abs() < 2e-3, "{:?}", matrix);
+            }
+        }
+
+        // Missing the colorants
+        let grey = build(b"RGB ", &[(b"kTRC", b"curv\0\0\0\0\0\0\0\0".to_vec())]);
+        assert_eq!(parse(&grey).unwrap_err().kind(), io::ErrorKind::Unsupported);
+        assert!(parse(&profile[..100]).is_err());
+    }
+}
@@ -0,0 +1,223 @@
+//! Raw pixels of an image: scanlines from top to bottom, pixels from left to right, and the
+//! samples of each pixel in the PNG order (R, G, B and then alpha).
+//!
+//! Samples smaller than a byte are not packed: each one takes a whole byte (see module `samples`).
+//! 16-bit samples are stored as `u16` in the native byte order, while PNG stores them big-endian,
+//! so they are converted when encoding and decoding.
+
+use super::{
+    chunks::{ImageHeader, Palette, Transparency},
+    samples,
+};
+use std::borrow::Cow;
+
+/// The samples of an image, one element per sample.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PixelData {
+    /// Bit depths 1, 2, 4 and 8
+    U8(Vec<u8>),
+    /// Bit depth 16
+    U16(Vec<u16>),
+}
+
+impl Default for PixelData {
+    fn default() -> Self {
+        PixelData::U8(Vec::new())
+    }
+}
+
+impl PixelData {
+    /// Number of samples.
+    pub fn len(&self) -> usize {
+        match self {
+            PixelData::U8(data) => data.len(),
+            PixelData::U16(data) => data.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    /// Same meaning as in `ImageHeader`
+    pub color_type: u8,
+    pub bit_depth: u8,
+    /// Scanlines without filter-type bytes. `U16` if and only if the bit depth is 16
+    pub data: PixelData,
+    /// Required for indexed-colour images
+    pub palette: Option<Palette>,
+    pub transparency: Option<Transparency>,
+}
+
+impl Image {
+    /// Image of bit depth 1, 2, 4 or 8, one byte per sample.
+    pub fn new(width: u32, height: u32, color_type: u8, bit_depth: u8, data: Vec<u8>) -> Self {
+        Self {
+            width,
+            height,
+            color_type,
+            bit_depth,
+            data: PixelData::U8(data),
+            palette: None,
+            transparency: None,
+        }
+    }
+
+    /// Image of bit depth 16.
+    pub fn new_16(width: u32, height: u32, color_type: u8, data: Vec<u16>) -> Self {
+        Self {
+            data: PixelData::U16(data),
+            ..Self::new(width, height, color_type, 16, Vec::new())
+        }
+    }
+
+    /// 8-bit greyscale image, one byte per pixel.
+    pub fn grey(width: u32, height: u32, data: Vec<u8>) -> Self {
+        Self::new(width, height, ImageHeader::GREYSCALE, 8, data)
+    }
+
+    /// 16-bit greyscale image, one `u16` per pixel.
+    pub fn grey_16(width: u32, height: u32, data: Vec<u16>) -> Self {
+        Self::new_16(width, height, ImageHeader::GREYSCALE, data)
+    }
+
+    /// 8-bit greyscale with alpha image, two bytes per pixel.
+    pub fn grey_alpha(width: u32, height: u32, data: Vec<u8>) -> Self {
+        Self::new(width, height, ImageHeader::GREYSCALE_ALPHA, 8, data)
+    }
+
+    /// 8-bit RGB image, three bytes per pixel.
+    pub fn rgb(width: u32, height: u32, data: Vec<u8>) -> Self {
+        Self::new(width, height, ImageHeader::TRUECOLOUR, 8, data)
+    }
+
+    /// 8-bit RGBA image, four bytes per pixel.
+    pub fn rgba(width: u32, height: u32, data: Vec<u8>) -> Self {
+        Self::new(width, height, ImageHeader::TRUECOLOUR_ALPHA, 8, data)
+    }
+
+    /// 8-bit indexed-colour image, one palette index per pixel.
+    pub fn indexed(width: u32, height: u32, data: Vec<u8>, palette: Palette) -> Self {
+        Self {
+            palette: Some(palette),
+            ..Self::new(width, height, ImageHeader::INDEXED, 8, data)
+        }
+    }
+
+    /// The IHDR that describes this image.
+    pub fn header(&self, adam7_interlace: bool) -> ImageHeader {
+        ImageHeader::new(
+            (self.width, self.height),
+            self.bit_depth,
+            self.color_type,
+            adam7_interlace,
+        )
+    }
+
+    /// The samples of an image of bit depth up to 8.
+    pub fn as_u8(&self) -> Option<&[u8]> {
+        match &self.data {
+            PixelData::U8(data) => Some(data),
+            PixelData::U16(_) => None,
+        }
+    }
+
+    /// The samples of a 16-bit image, in native byte order.
+    pub fn as_u16(&self) -> Option<&[u16]> {
+        match &self.data {
+            PixelData::U16(data) => Some(data),
+            PixelData::U8(_) => None,
+        }
+    }
+
+    pub fn samples_per_pixel(&self) -> usize {
+        self.header(false).samples_per_pixel()
+    }
+
+    /// Number of samples of each scanline.
+    pub fn samples_per_row(&self) -> usize {
+        self.width as usize * self.samples_per_pixel()
+    }
+
+    /// Scanlines of an image of bit depth up to 8 (none for 16-bit images).
+    pub fn rows(&self) -> impl Iterator<Item = &[u8]> {
+        let data = self.as_u8().unwrap_or_default();
+        data.chunks_exact(self.samples_per_row().max(1))
+    }
+
+    /// Scanlines of a 16-bit image (none for other bit depths).
+    pub fn rows_16(&self) -> impl Iterator<Item = &[u16]> {
+        let data = self.as_u16().unwrap_or_default();
+        data.chunks_exact(self.samples_per_row().max(1))
+    }
+
+    /// Scanlines packed as they are stored in the PNG (see `ImageHeader::row_size`).
+    pub fn packed_rows(&self) -> Box<dyn Iterator<Item = Cow<'_, [u8]>> + '_> {
+        match self.bit_depth {
+            1 | 2 | 4 => Box::new(
+                self.rows()
+                    .map(|row| Cow::Owned(samples::pack(row, self.bit_depth))),
+            ),
+            16 => Box::new(self.rows_16().map(|row| {
+                Cow::Owned(row.iter().flat_map(|sample| sample.to_be_bytes()).collect())
+            })),
+            _ => Box::new(self.rows().map(Cow::Borrowed)),
+        }
+    }
+
+    /// Converts the image to bit depth 8:
+    ///
+    /// - Greyscale samples of 1, 2 and 4 bits are scaled with bit replication (so that white
+    ///   stays white), while palette indices are kept.
+    /// - 16-bit samples are rounded to the nearest 8-bit value (`round(x * 255 / 65535)`), instead
+    ///   of just keeping the high byte.
+    ///
+    /// The tRNS colour is converted in the same way, so some colours that were not transparent
+    /// may become so.
+    pub fn to_8_bit(&self) -> Image {
+        let convert: fn(u16, u8) -> u8 = match (self.bit_depth, self.color_type) {
+            (16, _) => |sample, _| samples::round_16_to_8(sample),
+            (1 | 2 | 4, ImageHeader::GREYSCALE) => {
+                |sample, depth| samples::scale_to_8(sample as u8, depth)
+            }
+            (1 | 2 | 4, _) => |sample, _| sample as u8,
+            _ => return self.clone(),
+        };
+
+        let data = match &self.data {
+            PixelData::U8(data) => data
+                .iter()
+                .map(|&sample| convert(sample as u16, self.bit_depth))
+                .collect(),
+            PixelData::U16(data) => data
+                .iter()
+                .map(|&sample| convert(sample, self.bit_depth))
+                .collect(),
+        };
+
+        let transparency = self.transparency.as_ref().map(|transparency| {
+            let convert = |value| convert(value, self.bit_depth) as u16;
+            match (transparency.grey_value(), transparency.rgb_value()) {
+                (Some(grey), _) if self.color_type == ImageHeader::GREYSCALE => {
+                    Transparency::grey(convert(grey))
+                }
+                (_, Some([r, g, b])) if self.color_type == ImageHeader::TRUECOLOUR => {
+                    Transparency::rgb(convert(r), convert(g), convert(b))
+                }
+                _ => transparency.clone(),
+            }
+        });
+
+        Image {
+            bit_depth: 8,
+            data: PixelData::U8(data),
+            transparency,
+            ..self.clone()
+        }
+    }
+}
@@ -0,0 +1,439 @@
+//! Push-based decoding: instead of reading from a source (as `StreamingDecoder` does), the bytes of
+//! the file are given to the decoder as they arrive, in fragments of any size. A chunk (or even its
+//! length, type or CRC) can be split across fragments, so the decoder is a state machine that
+//! remembers where it stopped:
+//!
+//! ```text
+//! Signature -> ChunkHeader -> ChunkData -> ChunkCrc -> ChunkHeader -> ... -> Done
+//! ```
+//!
+//! The data of the IDAT chunks is decompressed as it arrives, so scanlines are returned as soon as
+//! they are complete. Note that this means that they are returned before the CRC of their chunk is
+//! checked.
+
+use super::{
+    chunks::{self, Chunk, ChunkType, ImageHeader, IDAT, IEND, IHDR, PLTE},
+    crc::CrcHasher,
+    decoder::{check_palette, unexpected_end, RowDecoder},
+    SIGN,
+};
+use std::io;
+
+/// Chunks longer than this are not valid.
+const MAX_CHUNK_SIZE: u32 = (1 << 31) - 1;
+
+/// What the decoder has found in the data given to `IncrementalDecoder::push`.
+#[derive(Debug)]
+pub enum Event {
+    /// The IHDR has been read and is valid
+    HeaderParsed(ImageHeader),
+    /// A chunk other than IHDR, IDAT and IEND has been read and its CRC is valid
+    ChunkComplete(Box<dyn Chunk>),
+    /// Some scanlines have been decoded
+    RowsDecoded(Vec<DecodedRow>),
+    /// The IEND has been read: the whole image has been decoded
+    Done,
+}
+
+/// A scanline, see `decoder::Row`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedRow {
+    /// Adam7 pass (1 to 7), or 0 for non-interlaced images
+    pub pass: u8,
+    /// Row of the complete image
+    pub y: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum State {
+    Signature,
+    /// Length and type
+    ChunkHeader,
+    ChunkData {
+        chunk_type: ChunkType,
+        remaining: u32,
+    },
+    ChunkCrc {
+        chunk_type: ChunkType,
+    },
+    Done,
+}
+
+/// Decodes a PNG from fragments of any size, see the module documentation.
+///
+/// ```no_run
+/// # use png::{IncrementalDecoder, Event};
+/// # fn main() -> std::io::Result<()> {
+/// # let packets: Vec<Vec<u8>> = Vec::new();
+/// let mut decoder = IncrementalDecoder::new();
+///
+/// for packet in packets {
+///     for event in decoder.push(&packet)? {
+///         match event {
+///             Event::HeaderParsed(header) => println!("{}x{}", header.width, header.height),
+///             Event::RowsDecoded(rows) => println!("{} rows", rows.len()),
+///             Event::ChunkComplete(chunk) => println!("{:?}", chunk.get_type()),
+///             Event::Done => println!("Done"),
+///         }
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct IncrementalDecoder {
+    state: State,
+    /// Bytes of the current signature, chunk header or CRC received so far
+    buffer: Vec<u8>,
+    /// Data of the current chunk. The data of IDAT chunks does not go through here, it is
+    /// decompressed directly.
+    data: Vec<u8>,
+    /// CRC of the current chunk so far
+    chunk_crc: CrcHasher,
+    header: Option<ImageHeader>,
+    /// Whether a PLTE has been found
+    palette: bool,
+    rows: Option<RowDecoder>,
+    /// Whether the IDAT chunks have started and ended
+    idat_started: bool,
+    idat_over: bool,
+}
+
+impl Default for IncrementalDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IncrementalDecoder {
+    pub fn new() -> Self {
+        Self {
+            state: State::Signature,
+            buffer: Vec::new(),
+            data: Vec::new(),
+            chunk_crc: CrcHasher::new(),
+            header: None,
+            palette: false,
+            rows: None,
+            idat_started: false,
+            idat_over: false,
+        }
+    }
+
+    /// The IHDR, once it has been read.
+    pub fn header(&self) -> Option<&ImageHeader> {
+        self.header.as_ref()
+    }
+
+    /// Returns `true` once the IEND has been read. The rest of the data is ignored.
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Processes the next fragment of the file, returning what has been found in it.
+    pub fn push(&mut self, mut input: &[u8]) -> io::Result<Vec<Event>> {
+        let mut events = Vec::new();
+
+        while !input.is_empty() {
+            match self.state {
+                State::Signature => {
+                    let Some(signature) = self.take(&mut input, SIGN.len()) else {
+                        continue;
+                    };
+                    if signature != SIGN {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "The given file is not a PNG file",
+                        ));
+                    }
+                    self.state = State::ChunkHeader;
+                }
+
+                State::ChunkHeader => {
+                    let Some(bytes) = self.take(&mut input, 8) else {
+                        continue;
+                    };
+                    self.begin_chunk(&bytes)?;
+                }
+
+                State::ChunkData {
+                    chunk_type,
+                    remaining,
+                } => {
+                    let len = input.len().min(remaining as usize);
+                    let (data, rest) = input.split_at(len);
+                    input = rest;
+
+                    self.chunk_crc.update(data);
+                    if chunk_type == IDAT {
+                        self.decode_rows(data, &mut events)?;
+                    } else {
+                        self.data.extend_from_slice(data);
+                    }
+
+                    let remaining = remaining - len as u32;
+                    self.state = if remaining == 0 {
+                        State::ChunkCrc { chunk_type }
+                    } else {
+                        State::ChunkData {
+                            chunk_type,
+                            remaining,
+                        }
+                    };
+                }
+
+                State::ChunkCrc { chunk_type } => {
+                    let Some(read_crc) = self.take(&mut input, 4) else {
+                        continue;
+                    };
+
+                    let read_crc = u32::from_be_bytes(read_crc.try_into().unwrap());
+                    let crc = self.chunk_crc.finalize();
+                    if read_crc != crc {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "The CRCs of chunk {:?} do not match: read {}, calculated {}",
+                                chunk_type, read_crc, crc
+                            ),
+                        ));
+                    }
+
+                    self.state = State::ChunkHeader;
+                    let data = std::mem::take(&mut self.data);
+                    self.end_chunk(chunk_type, &data, &mut events)?;
+                }
+
+                State::Done => break,
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Collects `len` bytes in `buffer`, taking them from `input`. Returns them once all of them
+    /// have arrived.
+    fn take(&mut self, input: &mut &[u8], len: usize) -> Option<Vec<u8>> {
+        let needed = (len - self.buffer.len()).min(input.len());
+        self.buffer.extend_from_slice(&input[..needed]);
+        *input = &input[needed..];
+
+        if self.buffer.len() == len {
+            Some(std::mem::take(&mut self.buffer))
+        } else {
+            None
+        }
+    }
+
+    /// Starts a chunk given its length and type.
+    fn begin_chunk(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let invalid = |message: String| Err(io::Error::new(io::ErrorKind::InvalidData, message));
+
+        let data_size = u32::from_be_bytes(bytes[..4].try_into().unwrap());
+        let chunk_type = ChunkType::from_slice(&bytes[4..]).unwrap();
+
+        if data_size > MAX_CHUNK_SIZE {
+            return invalid(format!("Invalid chunk length {}", data_size));
+        }
+
+        match (chunk_type, self.header.is_some()) {
+            (IHDR, true) => return invalid("Found more than one IHDR chunk".to_string()),
+            (IHDR, false) => {}
+            (other, false) => {
+                return invalid(format!(
+                    "Expected IHDR as the first chunk, found {:?}",
+                    other
+                ))
+            }
+            (IDAT, true) if self.idat_over => {
+                return invalid("IDAT chunks must be consecutive".to_string())
+            }
+            (IDAT, true) => {
+                if !self.idat_started {
+                    check_palette(self.header.as_ref().unwrap(), self.palette)?;
+                }
+                self.idat_started = true;
+            }
+            (_, true) => self.idat_over = self.idat_started,
+        }
+
+        self.chunk_crc = CrcHasher::new();
+        self.chunk_crc.update(&bytes[4..]);
+        self.state = if data_size == 0 {
+            State::ChunkCrc { chunk_type }
+        } else {
+            State::ChunkData {
+                chunk_type,
+                remaining: data_size,
+            }
+        };
+
+        Ok(())
+    }
+
+    /// Handles a chunk whose CRC has been checked.
+    fn end_chunk(
+        &mut self,
+        chunk_type: ChunkType,
+        data: &[u8],
+        events: &mut Vec<Event>,
+    ) -> io::Result<()> {
+        match chunk_type {
+            IDAT => {}
+
+            IHDR => {
+                let chunk = chunks::parse(chunk_type, data)?;
+                let header = *chunk.downcast_ref::<ImageHeader>().unwrap();
+                header.validate()?;
+
+                self.header = Some(header);
+                self.rows = Some(RowDecoder::new(&header));
+                events.push(Event::HeaderParsed(header));
+            }
+
+            IEND => {
+                let rows = self.rows.as_mut().unwrap();
+                if !self.idat_started {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "There is no image data",
+                    ));
+                }
+                if !rows.rows_done() || !rows.finish()? {
+                    return Err(unexpected_end());
+                }
+
+                self.state = State::Done;
+                events.push(Event::Done);
+            }
+
+            _ => {
+                let chunk = chunks::parse(chunk_type, data)?;
+                self.palette |= chunk_type == PLTE;
+                events.push(Event::ChunkComplete(chunk));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decompresses a piece of image data, adding the complete scanlines to `events`.
+    fn decode_rows(&mut self, data: &[u8], events: &mut Vec<Event>) -> io::Result<()> {
+        let rows = self.rows.as_mut().unwrap();
+        rows.feed(data)?;
+
+        let mut decoded = Vec::new();
+        while let Some(row) = rows.next_row()? {
+            decoded.push(DecodedRow {
+                pass: row.pass,
+                y: row.y,
+                data: row.data.to_vec(),
+            });
+        }
+
+        if !decoded.is_empty() {
+            events.push(Event::RowsDecoded(decoded));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::{chunks::GenericChunk, encoder::EncodeOptions, image::Image, Png};
+
+    #[test]
+    fn fragments_test() {
+        let (width, height) = (23, 19);
+        let data: Vec<u8> = (0..width * height * 3)
+            .map(|i| (i * 13 / 5) as u8)
+            .collect();
+        let options = EncodeOptions {
+            idat_size: 64,
+            ..Default::default()
+        };
+        let bytes = Png::from_image(&Image::rgb(width, height, data.clone()), &options)
+            .unwrap()
+            .to_vec();
+
+        // Any fragment size must give the same result
+        for size in [1, 3, 7, 64, 1000, bytes.len()] {
+            let mut decoder = IncrementalDecoder::new();
+            let mut rows = Vec::new();
+            let mut done = false;
+
+            for fragment in bytes.chunks(size) {
+                for event in decoder.push(fragment).unwrap() {
+                    match event {
+                        Event::HeaderParsed(header) => assert_eq!(header.width, width),
+                        Event::RowsDecoded(decoded) => rows.extend(decoded),
+                        Event::ChunkComplete(chunk) => panic!("Unexpected chunk {:?}", chunk),
+                        Event::Done => done = true,
+                    }
+                }
+            }
+
+            assert!(done && decoder.is_done());
+            assert!(rows.iter().enumerate().all(|(y, row)| row.y == y as u32));
+            let decoded: Vec<u8> = rows.into_iter().flat_map(|row| row.data).collect();
+            assert_eq!(decoded, data);
+        }
+
+        // Corrupted CRC of the last IDAT, with the rows already decoded
+        let mut corrupted = bytes.clone();
+        corrupted[bytes.len() - 13] ^= 1;
+        assert!(IncrementalDecoder::new().push(&corrupted).is_err());
+
+        // Missing the end
+        let mut decoder = IncrementalDecoder::new();
+        decoder.push(&bytes[..bytes.len() - 12]).unwrap();
+        assert!(!decoder.is_done());
+    }
+
+    #[test]
+    fn truncated_test() {
+        let data: Vec<u8> = (0..9 * 7 * 3).map(|i| (i * 7) as u8).collect();
+        let png = Png::from_image(&Image::rgb(9, 7, data), &EncodeOptions::default()).unwrap();
+        let bytes = png.to_vec();
+
+        // Any prefix is accepted, without finishing and with the rows in order
+        for cut in 0..bytes.len() {
+            let mut decoder = IncrementalDecoder::new();
+            let events = decoder.push(&bytes[..cut]).unwrap();
+            assert!(!decoder.is_done(), "{}", cut);
+
+            let rows: Vec<u32> = events
+                .iter()
+                .flat_map(|event| match event {
+                    Event::RowsDecoded(rows) => rows.iter().map(|row| row.y).collect(),
+                    Event::Done => panic!("Done after {} bytes", cut),
+                    _ => Vec::new(),
+                })
+                .collect();
+            assert!(rows.iter().enumerate().all(|(y, &row)| row == y as u32));
+        }
+
+        // A zlib stream cut short, in chunks with valid CRCs, fails at the IEND
+        let idat: Vec<u8> = png
+            .chunks_by_type(IDAT)
+            .flat_map(|chunk| chunk.data_to_bytes())
+            .collect();
+        let mut truncated = SIGN.to_vec();
+        truncated.extend(png.header().to_bytes());
+        truncated.extend(GenericChunk::from_bytes(IDAT, &idat[..idat.len() - 8]).to_bytes());
+        truncated.extend(GenericChunk::from_bytes(IEND, b"").to_bytes());
+        let error = IncrementalDecoder::new().push(&truncated).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        // A wrong CRC of the IHDR arriving one byte at a time fails before reporting the header
+        let mut corrupted = bytes.clone();
+        let crc_end = SIGN.len() + 8 + 13 + 4;
+        corrupted[crc_end - 4] ^= 1;
+        let mut decoder = IncrementalDecoder::new();
+        for byte in &corrupted[..crc_end - 1] {
+            assert!(decoder.push(&[*byte]).unwrap().is_empty());
+        }
+        assert!(decoder.push(&corrupted[crc_end - 1..crc_end]).is_err());
+    }
+}
@@ -0,0 +1,617 @@
+//! Lists the structure of a PNG file chunk by chunk, like the PNG file chunk inspector of
+//! nayuki.io: where each chunk is, its flags, whether its CRC is right and the decoded fields of
+//! the chunks this crate knows.
+//!
+//! Unlike `Png::read`, it does not stop at the first problem, since it is meant for looking into
+//! broken files: chunks with a wrong CRC or invalid data are listed along with the problem, and
+//! the scan only stops when the file is truncated.
+
+use super::{
+    chunks::{
+        self, split_null, Background, Chromaticities, ChunkType, Gamma, ImageHeader, Palette, IEND,
+    },
+    color::ColorType,
+    crc::CrcHasher,
+    SIGN,
+};
+use crate::compression::zlib::ZlibDecoder;
+use std::{fmt, io};
+
+/// A decoded field of a chunk.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(u64),
+    Float(f64),
+    Text(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Float(value) => write!(f, "{}", value),
+            Value::Text(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkInfo {
+    /// Position of the length field in the file
+    pub offset: u64,
+    /// Size of the data
+    pub length: u32,
+    pub chunk_type: ChunkType,
+    /// CRC stored in the file
+    pub crc: u32,
+    /// CRC calculated from the type and the data
+    pub calculated_crc: u32,
+    /// Decoded fields, with snake case names
+    pub fields: Vec<(&'static str, Value)>,
+    /// Why the data could not be decoded
+    pub error: Option<String>,
+}
+
+impl ChunkInfo {
+    pub fn crc_valid(&self) -> bool {
+        self.crc == self.calculated_crc
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inspection {
+    pub file_size: u64,
+    pub chunks: Vec<ChunkInfo>,
+    /// Problems with the file structure: truncation, missing IEND, data after IEND...
+    pub errors: Vec<String>,
+}
+
+/// Scans all the chunks of the file. It only fails if `data` does not start with the PNG
+/// signature.
+pub fn inspect(data: &[u8]) -> io::Result<Inspection> {
+    if !data.starts_with(&SIGN) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The given file is not a PNG file",
+        ));
+    }
+
+    let mut inspection = Inspection {
+        file_size: data.len() as u64,
+        chunks: Vec::new(),
+        errors: Vec::new(),
+    };
+    let mut header = None;
+    let mut offset = SIGN.len();
+
+    while offset < data.len() {
+        let rest = &data[offset..];
+        let length = rest
+            .get(..4)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()));
+
+        let Some(length) = length.filter(|&length| 12 + length as usize <= rest.len()) else {
+            inspection.errors.push(format!(
+                "Truncated chunk at offset {}: only {} bytes left",
+                offset,
+                rest.len()
+            ));
+            return Ok(inspection);
+        };
+
+        let chunk_type = ChunkType::from_slice(&rest[4..8]).unwrap();
+        let chunk_data = &rest[8..8 + length as usize];
+        let crc_bytes = &rest[8 + length as usize..12 + length as usize];
+
+        let mut crc = CrcHasher::new();
+        crc.update(&rest[4..8]);
+        crc.update(chunk_data);
+
+        let mut info = ChunkInfo {
+            offset: offset as u64,
+            length,
+            chunk_type,
+            crc: u32::from_be_bytes(crc_bytes.try_into().unwrap()),
+            calculated_crc: crc.finalize(),
+            fields: Vec::new(),
+            error: None,
+        };
+
+        if length > (1 << 31) - 1 {
+            info.error = Some("Length over 2^31 - 1".to_string());
+        } else if !chunk_type.as_bytes().iter().all(u8::is_ascii_alphabetic) {
+            info.error = Some("Invalid chunk type".to_string());
+        } else {
+            match fields(chunk_type, chunk_data, header.as_ref()) {
+                Ok(fields) => info.fields = fields,
+                Err(error) => info.error = Some(error.to_string()),
+            }
+        }
+
+        if chunk_type == chunks::IHDR && info.error.is_none() {
+            header = Some(ImageHeader::from_bytes(chunk_data));
+        }
+
+        inspection.chunks.push(info);
+        offset += 12 + length as usize;
+
+        if chunk_type == IEND {
+            if offset < data.len() {
+                inspection
+                    .errors
+                    .push(format!("{} bytes of data after IEND", data.len() - offset));
+            }
+            return Ok(inspection);
+        }
+    }
+
+    inspection.errors.push("Missing IEND chunk".to_string());
+    Ok(inspection)
+}
+
+fn invalid<T>(message: &str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+/// Largest decompressed size of the iCCP, zTXt and iTXt data that is shown. A few bytes can
+/// decompress to gigabytes, so bigger payloads are only reported by their compressed size.
+const MAX_DECOMPRESSED: usize = 1 << 24;
+
+/// Decompresses a zlib stream, or returns `None` if it is bigger than `MAX_DECOMPRESSED`.
+fn decompress(compressed: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let mut decoder = ZlibDecoder::new();
+    decoder.feed(compressed)?;
+
+    let mut out = Vec::new();
+    decoder.decompress(&mut out, MAX_DECOMPRESSED + 1)?;
+
+    if out.len() > MAX_DECOMPRESSED {
+        Ok(None)
+    } else if decoder.is_done() {
+        Ok(Some(out))
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "The zlib stream ended unexpectedly",
+        ))
+    }
+}
+
+fn latin1(bytes: &[u8]) -> Value {
+    Value::Text(bytes.iter().map(|&byte| byte as char).collect())
+}
+
+fn utf8(bytes: &[u8]) -> Value {
+    Value::Text(String::from_utf8_lossy(bytes).into_owned())
+}
+
+fn u16_at(data: &[u8], i: usize) -> u64 {
+    u16::from_be_bytes([data[2 * i], data[2 * i + 1]]) as u64
+}
+
+fn u32_at(data: &[u8], i: usize) -> u64 {
+    u32::from_be_bytes(data[4 * i..4 * i + 4].try_into().unwrap()) as u64
+}
+
+/// Decodes the fields of the known chunks. `header` is needed for tRNS and sBIT, whose content
+/// depends on the colour type.
+fn fields(
+    chunk_type: ChunkType,
+    data: &[u8],
+    header: Option<&ImageHeader>,
+) -> io::Result<Vec<(&'static str, Value)>> {
+    // Checks the length of the chunks that have a type
+    let chunk = chunks::parse(chunk_type, data)?;
+    let color_type = header.and_then(|header| ColorType::from_code(header.color_type));
+
+    let fields = match chunk_type.as_bytes() {
+        b"IHDR" => {
+            let header = chunk.downcast_ref::<ImageHeader>().unwrap();
+            let color = ColorType::from_code(header.color_type)
+                .map_or("Unknown".to_string(), |color| format!("{:?}", color));
+            vec![
+                ("width", Value::Int(header.width as u64)),
+                ("height", Value::Int(header.height as u64)),
+                ("bit_depth", Value::Int(header.bit_depth as u64)),
+                ("color_type", Value::Int(header.color_type as u64)),
+                ("color_type_name", Value::Text(color)),
+                ("compression_method", Value::Int(data[10] as u64)),
+                ("filter_method", Value::Int(data[11] as u64)),
+                ("interlace_method", Value::Int(header.interlace as u64)),
+            ]
+        }
+        b"PLTE" => {
+            let palette = chunk.downcast_ref::<Palette>().unwrap();
+            vec![("entries", Value::Int(palette.entries.len() as u64))]
+        }
+        b"tRNS" => match color_type {
+            Some(ColorType::Greyscale) if data.len() == 2 => {
+                vec![("grey", Value::Int(u16_at(data, 0)))]
+            }
+            Some(ColorType::Truecolour) if data.len() == 6 => vec![
+                ("red", Value::Int(u16_at(data, 0))),
+                ("green", Value::Int(u16_at(data, 1))),
+                ("blue", Value::Int(u16_at(data, 2))),
+            ],
+            Some(ColorType::Indexed) => vec![("entries", Value::Int(data.len() as u64))],
+            _ => return invalid("Invalid tRNS for the colour type"),
+        },
+        b"bKGD" => {
+            let background = chunk.downcast_ref::<Background>().unwrap();
+            if let Some(index) = background.palette_index() {
+                vec![("palette_index", Value::Int(index as u64))]
+            } else if let Some(grey) = background.grey_value() {
+                vec![("grey", Value::Int(grey as u64))]
+            } else {
+                let [red, green, blue] = background.rgb_value().unwrap();
+                vec![
+                    ("red", Value::Int(red as u64)),
+                    ("green", Value::Int(green as u64)),
+                    ("blue", Value::Int(blue as u64)),
+                ]
+            }
+        }
+        b"gAMA" => {
+            let gamma = chunk.downcast_ref::<Gamma>().unwrap();
+            vec![("gamma", Value::Float(gamma.value()))]
+        }
+        b"cHRM" => {
+            let [white, red, green, blue] =
+                chunk.downcast_ref::<Chromaticities>().unwrap().values();
+            vec![
+                ("white_x", Value::Float(white[0])),
+                ("white_y", Value::Float(white[1])),
+                ("red_x", Value::Float(red[0])),
+                ("red_y", Value::Float(red[1])),
+                ("green_x", Value::Float(green[0])),
+                ("green_y", Value::Float(green[1])),
+                ("blue_x", Value::Float(blue[0])),
+                ("blue_y", Value::Float(blue[1])),
+            ]
+        }
+        b"sRGB" => {
+            let intent = match data[0] {
+                0 => "Perceptual",
+                1 => "Relative colorimetric",
+                2 => "Saturation",
+                3 => "Absolute colorimetric",
+                _ => "Unknown",
+            };
+            vec![
+                ("rendering_intent", Value::Int(data[0] as u64)),
+                ("rendering_intent_name", Value::Text(intent.to_string())),
+            ]
+        }
+        b"iCCP" => {
+            let (name, rest) = split_null(data)?;
+            let Some((&method, compressed)) = rest.split_first() else {
+                return invalid("Missing compression method");
+            };
+            vec![
+                ("name", latin1(name)),
+                ("compression_method", Value::Int(method as u64)),
+                match decompress(compressed)? {
+                    Some(profile) => ("profile_size", Value::Int(profile.len() as u64)),
+                    None => ("compressed_size", Value::Int(compressed.len() as u64)),
+                },
+            ]
+        }
+        b"sBIT" => {
+            let names: &[&'static str] = match color_type {
+                Some(ColorType::Greyscale) => &["grey"],
+                Some(ColorType::Truecolour | ColorType::Indexed) => &["red", "green", "blue"],
+                Some(ColorType::GreyscaleAlpha) => &["grey", "alpha"],
+                Some(ColorType::TruecolourAlpha) => &["red", "green", "blue", "alpha"],
+                _ => return invalid("sBIT before a valid IHDR"),
+            };
+            if data.len() != names.len() {
+                return invalid("Invalid sBIT length for the colour type");
+            }
+            names
+                .iter()
+                .zip(data)
+                .map(|(&name, &bits)| (name, Value::Int(bits as u64)))
+                .collect()
+        }
+        b"pHYs" => {
+            if data.len() != 9 {
+                return invalid("pHYs must have 9 bytes");
+            }
+            let unit = match data[8] {
+                0 => "Unknown",
+                1 => "Metre",
+                _ => "Invalid",
+            };
+            vec![
+                ("pixels_per_unit_x", Value::Int(u32_at(data, 0))),
+                ("pixels_per_unit_y", Value::Int(u32_at(data, 1))),
+                ("unit", Value::Text(unit.to_string())),
+            ]
+        }
+        b"tIME" => {
+            if data.len() != 7 {
+                return invalid("tIME must have 7 bytes");
+            }
+            let time = format!(
+                "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+                u16_at(data, 0),
+                data[2],
+                data[3],
+                data[4],
+                data[5],
+                data[6]
+            );
+            vec![("time", Value::Text(time))]
+        }
+        b"tEXt" => {
+            let (keyword, text) = split_null(data)?;
+            vec![("keyword", latin1(keyword)), ("text", latin1(text))]
+        }
+        b"zTXt" => {
+            let (keyword, rest) = split_null(data)?;
+            let Some((&method, compressed)) = rest.split_first() else {
+                return invalid("Missing compression method");
+            };
+            vec![
+                ("keyword", latin1(keyword)),
+                ("compression_method", Value::Int(method as u64)),
+                match decompress(compressed)? {
+                    Some(text) => ("text", latin1(&text)),
+                    None => ("compressed_size", Value::Int(compressed.len() as u64)),
+                },
+            ]
+        }
+        b"iTXt" => {
+            let (keyword, rest) = split_null(data)?;
+            if rest.len() < 2 {
+                return invalid("Missing compression flag and method");
+            }
+            let (flag, method) = (rest[0], rest[1]);
+            let (language, rest) = split_null(&rest[2..])?;
+            let (translated, text) = split_null(rest)?;
+            let text = match flag {
+                1 => match decompress(text)? {
+                    Some(text) => ("text", utf8(&text)),
+                    None => ("compressed_size", Value::Int(text.len() as u64)),
+                },
+                _ => ("text", utf8(text)),
+            };
+            vec![
+                ("keyword", latin1(keyword)),
+                ("compressed", Value::Int(flag as u64)),
+                ("compression_method", Value::Int(method as u64)),
+                ("language", utf8(language)),
+                ("translated_keyword", utf8(translated)),
+                text,
+            ]
+        }
+        b"hIST" => vec![("entries", Value::Int(data.len() as u64 / 2))],
+        b"eXIf" => vec![("size", Value::Int(data.len() as u64))],
+        _ => vec![],
+    };
+
+    Ok(fields)
+}
+
+impl Inspection {
+    /// Formats the chunks as a table, with the fields of each chunk below it.
+    pub fn to_table(&self) -> String {
+        let mut out = format!(
+            "{:>10} {:>10}  {:<4}  {:<9}  {:<7}  {:<6}  {}\n",
+            "Offset", "Length", "Type", "Kind", "Scope", "Copy", "CRC"
+        );
+
+        for chunk in &self.chunks {
+            let crc = if chunk.crc_valid() {
+                format!("ok {:08x}", chunk.crc)
+            } else {
+                format!(
+                    "BAD {:08x} (calculated {:08x})",
+                    chunk.crc, chunk.calculated_crc
+                )
+            };
+
+            out += &format!(
+                "{:>10} {:>10}  {:<4}  {:<9}  {:<7}  {:<6}  {}\n",
+                chunk.offset,
+                chunk.length,
+                type_name(chunk.chunk_type),
+                if chunk.chunk_type.is_critical() {
+                    "critical"
+                } else {
+                    "ancillary"
+                },
+                if chunk.chunk_type.is_public() {
+                    "public"
+                } else {
+                    "private"
+                },
+                if chunk.chunk_type.is_safe_to_copy() {
+                    "safe"
+                } else {
+                    "unsafe"
+                },
+                crc
+            );
+
+            for (name, value) in &chunk.fields {
+                out += &format!("{:>23}{}: {}\n", "", name.replace('_', " "), value);
+            }
+            if let Some(error) = &chunk.error {
+                out += &format!("{:>23}error: {}\n", "", error);
+            }
+        }
+
+        out += &format!("\n{} chunks, {} bytes\n", self.chunks.len(), self.file_size);
+        for error in &self.errors {
+            out += &format!("Error: {}\n", error);
+        }
+
+        out
+    }
+
+    /// Formats the inspection as a JSON object.
+    pub fn to_json(&self) -> String {
+        let chunks: Vec<String> = self
+            .chunks
+            .iter()
+            .map(|chunk| {
+                let fields: Vec<String> = chunk
+                    .fields
+                    .iter()
+                    .map(|(name, value)| {
+                        let value = match value {
+                            Value::Int(value) => value.to_string(),
+                            Value::Float(value) if value.is_finite() => value.to_string(),
+                            Value::Float(_) => "null".to_string(),
+                            Value::Text(text) => json_string(text),
+                        };
+                        format!("{}: {}", json_string(name), value)
+                    })
+                    .collect();
+
+                format!(
+                    "{{\"offset\": {}, \"length\": {}, \"type\": {}, \"critical\": {}, \
+                     \"public\": {}, \"safe_to_copy\": {}, \"crc\": {}, \"crc_valid\": {}, \
+                     \"fields\": {{{}}}, \"error\": {}}}",
+                    chunk.offset,
+                    chunk.length,
+                    json_string(&type_name(chunk.chunk_type)),
+                    chunk.chunk_type.is_critical(),
+                    chunk.chunk_type.is_public(),
+                    chunk.chunk_type.is_safe_to_copy(),
+                    chunk.crc,
+                    chunk.crc_valid(),
+                    fields.join(", "),
+                    chunk
+                        .error
+                        .as_deref()
+                        .map_or("null".to_string(), json_string)
+                )
+            })
+            .collect();
+
+        let errors: Vec<String> = self.errors.iter().map(|error| json_string(error)).collect();
+
+        format!(
+            "{{\n  \"file_size\": {},\n  \"chunks\": [\n    {}\n  ],\n  \"errors\": [{}]\n}}\n",
+            self.file_size,
+            chunks.join(",\n    "),
+            errors.join(", ")
+        )
+    }
+}
+
+/// The chunk code, or its bytes in hexadecimal if they are not letters.
+fn type_name(chunk_type: ChunkType) -> String {
+    let bytes = chunk_type.as_bytes();
+    if bytes.iter().all(u8::is_ascii_alphabetic) {
+        bytes.iter().map(|&byte| byte as char).collect()
+    } else {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for char in text.chars() {
+        match char {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            '\r' => out += "\\r",
+            '\t' => out += "\\t",
+            char if (char as u32) < 0x20 => out += &format!("\\u{:04x}", char as u32),
+            char => out.push(char),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::{chunks::GenericChunk, encoder::EncodeOptions, image::Image, Png};
+
+    #[test]
+    fn inspect_test() {
+        let image = Image::rgb(3, 2, vec![200; 18]);
+        let mut png = Png::from_image(&image, &EncodeOptions::default()).unwrap();
+        png.insert(Box::new(Gamma::new(0.45455))).unwrap();
+        png.insert(Box::new(GenericChunk::from_bytes(
+            ChunkType::from_code("tEXt").unwrap(),
+            b"Comment\0Say \"hi\"\n",
+        )))
+        .unwrap();
+
+        let mut data = png.to_vec();
+        // Corrupt the CRC of the gAMA and add some garbage at the end
+        let gama = 8 + 25;
+        data[gama + 12 + 3] ^= 0xFF;
+        data.extend_from_slice(b"junk");
+
+        let inspection = inspect(&data).unwrap();
+        let types: Vec<_> = inspection
+            .chunks
+            .iter()
+            .map(|chunk| type_name(chunk.chunk_type))
+            .collect();
+        assert_eq!(types, ["IHDR", "gAMA", "IDAT", "tEXt", "IEND"]);
+
+        let [ihdr, gamma, idat, text, _] = &inspection.chunks[..] else {
+            unreachable!()
+        };
+        assert!(ihdr.crc_valid() && !gamma.crc_valid());
+        assert_eq!(gamma.offset, gama as u64);
+        assert_eq!(ihdr.fields[0], ("width", Value::Int(3)));
+        assert_eq!(
+            ihdr.fields[4],
+            ("color_type_name", Value::Text("Truecolour".into()))
+        );
+        assert_eq!(gamma.fields, [("gamma", Value::Float(0.45455))]);
+        assert!(idat.fields.is_empty() && idat.error.is_none());
+        assert_eq!(text.fields[1].1, Value::Text("Say \"hi\"\n".into()));
+        assert_eq!(inspection.errors, ["4 bytes of data after IEND"]);
+
+        let table = inspection.to_table();
+        assert!(table.contains("gAMA  ancillary  public   unsafe  BAD"));
+        let json = inspection.to_json();
+        assert!(json.contains("\"text\": \"Say \\\"hi\\\"\\n\""));
+        assert!(json.contains("\"type\": \"gAMA\", \"critical\": false"));
+
+        // A truncated file still lists the complete chunks
+        let inspection = inspect(&data[..gama + 10]).unwrap();
+        assert_eq!(inspection.chunks.len(), 1);
+        assert_eq!(inspection.errors.len(), 1);
+        assert!(inspect(b"GIF89a").is_err());
+    }
+
+    #[test]
+    fn decompression_limit_test() {
+        use crate::compression::{zlib, Compression};
+
+        let text = |compressed: &[u8]| {
+            let mut data = b"Comment\0\0".to_vec();
+            data.extend_from_slice(compressed);
+            fields(ChunkType::from_code("zTXt").unwrap(), &data, None)
+        };
+
+        let small = zlib::compress(b"hello", Compression::Fast);
+        assert_eq!(
+            text(&small).unwrap()[2],
+            ("text", Value::Text("hello".into()))
+        );
+
+        // Only the compressed size of a payload that decompresses to too many bytes
+        let bomb = zlib::compress(&vec![0; MAX_DECOMPRESSED + 1], Compression::Fast);
+        assert_eq!(
+            text(&bomb).unwrap()[2],
+            ("compressed_size", Value::Int(bomb.len() as u64))
+        );
+
+        assert!(text(&small[..small.len() - 2]).is_err());
+        assert!(fields(ChunkType::from_code("iCCP").unwrap(), b"name\0", None).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+//! Adam7 interlacing transmits the image in seven passes, so that a viewer can show a low
+//! resolution version of the whole image early and refine it as more data arrives. Each pass is a
+//! reduced image made of the pixels in the positions marked with its number in this 8x8 pattern,
+//! which is repeated over the whole image:
+//!
+//! ```text
+//! 1 6 4 6 2 6 4 6
+//! 7 7 7 7 7 7 7 7
+//! 5 6 5 6 5 6 5 6
+//! 7 7 7 7 7 7 7 7
+//! 3 6 4 6 3 6 4 6
+//! 7 7 7 7 7 7 7 7
+//! 5 6 5 6 5 6 5 6
+//! 7 7 7 7 7 7 7 7
+//! ```
+//!
+//! Each reduced image is filtered and serialized as if it were a complete image (its scanlines are
+//! packed and have their own filter-type byte), one after the other. Passes with no pixels (in
+//! images smaller than 5x5) are skipped entirely.
+
+use super::chunks::ImageHeader;
+
+/// Pixels of a reduced image: from the starting position `(x, y)`, every `dx` columns and `dy`
+/// rows. A non-interlaced image is a single pass with steps of 1.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Pass {
+    pub x: u32,
+    pub y: u32,
+    pub dx: u32,
+    pub dy: u32,
+}
+
+pub const ADAM7: [Pass; 7] = [
+    Pass::new(0, 0, 8, 8),
+    Pass::new(4, 0, 8, 8),
+    Pass::new(0, 4, 4, 8),
+    Pass::new(2, 0, 4, 4),
+    Pass::new(0, 2, 2, 4),
+    Pass::new(1, 0, 2, 2),
+    Pass::new(0, 1, 1, 2),
+];
+
+/// The whole image, for non-interlaced images.
+pub const FULL: Pass = Pass::new(0, 0, 1, 1);
+
+impl Pass {
+    pub const fn new(x: u32, y: u32, dx: u32, dy: u32) -> Self {
+        Self { x, y, dx, dy }
+    }
+
+    /// Number of columns of the reduced image.
+    pub fn width(&self, image_width: u32) -> u32 {
+        image_width.saturating_sub(self.x).div_ceil(self.dx)
+    }
+
+    /// Number of rows of the reduced image.
+    pub fn height(&self, image_height: u32) -> u32 {
+        image_height.saturating_sub(self.y).div_ceil(self.dy)
+    }
+
+    /// Row of the complete image where the given row of the reduced image goes.
+    pub fn image_row(&self, row: u32) -> u32 {
+        self.y + row * self.dy
+    }
+}
+
+/// The passes of the image (see `ImageHeader::interlace`) that have any pixel, along with their
+/// number (1 to 7, or 0 for non-interlaced images).
+pub fn passes(header: &ImageHeader) -> Vec<(u8, Pass)> {
+    if header.interlace == 0 {
+        return vec![(0, FULL)];
+    }
+
+    (1..)
+        .zip(ADAM7)
+        .filter(|(_, pass)| pass.width(header.width) > 0 && pass.height(header.height) > 0)
+        .collect()
+}
+
+/// Copies the pixels of a row of a reduced image, that goes in the row `y` of the complete image,
+/// into their place in `image`. Both have one element per sample (see module `samples`): each pixel
+/// takes `pixel_size` samples and each row of the complete image `width * pixel_size` samples.
+pub fn scatter_row<T: Copy>(
+    pass: &Pass,
+    y: u32,
+    width: u32,
+    pixel_size: usize,
+    data: &[T],
+    image: &mut [T],
+) {
+    let row_size = width as usize * pixel_size;
+    let image_row = &mut image[y as usize * row_size..][..row_size];
+
+    if pass.dx == 1 {
+        image_row.copy_from_slice(data);
+        return;
+    }
+
+    for (i, pixel) in data.chunks_exact(pixel_size).enumerate() {
+        let x = (pass.x + i as u32 * pass.dx) as usize;
+        image_row[x * pixel_size..][..pixel_size].copy_from_slice(pixel);
+    }
+}
+
+/// The inverse of `scatter_row`: collects the pixels of the row `y` of the complete image that
+/// belong to `pass`, which form a row of the reduced image.
+pub fn gather_row<T: Copy>(
+    pass: &Pass,
+    y: u32,
+    width: u32,
+    pixel_size: usize,
+    image: &[T],
+) -> Vec<T> {
+    let row_size = width as usize * pixel_size;
+    let image_row = &image[y as usize * row_size..][..row_size];
+
+    (0..pass.width(width))
+        .flat_map(|i| {
+            let x = (pass.x + i * pass.dx) as usize;
+            image_row[x * pixel_size..][..pixel_size].iter().copied()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes_test() {
+        let sizes: Vec<_> = ADAM7
+            .iter()
+            .map(|pass| (pass.width(10), pass.height(3)))
+            .collect();
+        assert_eq!(
+            sizes,
+            vec![(2, 1), (1, 1), (3, 0), (2, 1), (5, 1), (5, 2), (10, 1)]
+        );
+
+        // A 1x1 image only has the first pass
+        let header = ImageHeader::new((1, 1), 8, ImageHeader::GREYSCALE, true);
+        assert_eq!(passes(&header), vec![(1, ADAM7[0])]);
+    }
+
+    #[test]
+    fn scatter_test() {
+        // The second row of pass 6 is row 2, columns 1 and 3
+        let mut image = vec![0; 4 * 4 * 2];
+        scatter_row(&ADAM7[5], 2, 4, 2, &[1, 2, 3, 4], &mut image);
+        assert_eq!(image[16..24], [0, 0, 1, 2, 0, 0, 3, 4]);
+        assert_eq!(gather_row(&ADAM7[5], 2, 4, 2, &image), [1, 2, 3, 4]);
+    }
+}
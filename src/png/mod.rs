@@ -1,10 +1,34 @@
-use chunks::Chunk;
-use crc::Crc;
-use std::{fs, io, io::Write, path::Path};
+use chunks::{Chunk, ChunkType, ImageHeader, ImageTrailer, Palette, IEND, IHDR};
+use reader::ChunkReader;
+use std::{
+    fs, io,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+use writer::ChunkWriter;
 
 pub mod chunks;
+pub mod color;
+pub mod colorspace;
 pub mod crc;
+pub mod decoder;
+pub mod diff;
+pub mod edit;
+pub mod encoder;
+pub mod extract;
 pub mod filter;
+pub mod icc;
+pub mod image;
+pub mod incremental;
+pub mod inspect;
+pub mod interlace;
+pub mod optimize;
+pub mod quantize;
+pub mod reader;
+pub mod samples;
+pub mod strip;
+pub mod unfilter;
+pub mod writer;
 
 // Signature
 pub const SIGN: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
@@ -12,81 +36,142 @@ pub const SIGN: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
 /// A PNG consists in a signature (that every PNG should have) and a series of chunks, that may be
 /// of different types. The order of these last ones do not matter.
 ///
+/// Every PNG must start with an IHDR and finish with an IEND, so these are not stored in `chunks`:
+/// the header is kept apart (see `header()`) and the trailer is added when writing.
+///
+/// The rest of the chunks are edited with the methods of module `edit`, which keep them in a valid
+/// order.
+///
 /// The official spec: http://libpng.org/pub/png/spec/1.2/PNG-Structure.html
 pub struct Png {
-    // TODO?: Store directly IHDR since every PNG must have one
-    pub chunks: Vec<Box<dyn Chunk>>,
-    crc: Crc,
+    header: ImageHeader,
+    chunks: Vec<Box<dyn Chunk>>,
 }
 
 impl Png {
-    pub fn empty() -> Self {
+    pub fn new(header: ImageHeader) -> Self {
         Self {
-            chunks: Vec::with_capacity(3),
-            crc: Crc::new(),
+            header,
+            chunks: Vec::with_capacity(1),
         }
     }
 
     pub fn read(input_file: &Path) -> io::Result<Self> {
-        let file_data = fs::read(input_file)?;
+        let file = fs::File::open(input_file)?;
+        Self::read_from(BufReader::new(file))
+    }
 
-        let mut p = 0_usize;
+    /// Reads a whole PNG (signature and all the chunks until IEND) from `reader`, checking the CRC
+    /// of every chunk.
+    pub fn read_from<R: Read>(reader: R) -> io::Result<Self> {
+        let mut reader = ChunkReader::new(reader);
+        reader.read_signature()?;
 
-        if file_data[p..p + 8] != SIGN {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "The given file is not a PNG file",
-            ));
-        }
-        p += 8;
-
-        let mut png = Self::empty();
+        let header = read_header(&mut reader)?;
+        let mut png = Self::new(header);
 
         loop {
-            // Read chunk data size
-            let data_size = u32::from_be_bytes(file_data[p..p + 4].try_into().unwrap()) as usize;
-            p += 4;
-
-            // Chunk type and data
-            let chunk_data = &file_data[p..p + 4 + data_size];
-            let chunk = chunks::from_bytes(chunk_data);
-            p += 4 + data_size;
-
-            // CRC checking
-            // TODO: make optional
-            let calculated_crc = png.crc.calculate(chunk_data);
-            let read_crc = u32::from_be_bytes(file_data[p..p + 4].try_into().unwrap());
-            p += 4;
-
-            if calculated_crc != read_crc {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!(
-                        "The CRCs do not match: read {}, calculated {}",
-                        read_crc, calculated_crc
-                    ),
-                ));
-            }
-
-            png.chunks.push(chunk);
-
-            // TODO: Handle unexpected end of file
-            if p >= file_data.len() {
-                break;
+            let chunk = reader.read_chunk()?;
+
+            match chunk.get_type() {
+                IEND => break,
+                IHDR => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Found more than one IHDR chunk",
+                    ))
+                }
+                _ => png.chunks.push(chunk),
             }
         }
 
         Ok(png)
     }
 
-    pub fn write(&self, output_file: &Path) -> io::Result<()> {
-        let mut file = fs::File::create(output_file)?;
+    /// Writes the PNG to a file, returning the number of bytes written.
+    pub fn write(&self, output_file: &Path) -> io::Result<u64> {
+        let file = fs::File::create(output_file)?;
+        self.write_to(BufWriter::new(file))
+    }
+
+    /// Writes the whole PNG (signature and all the chunks) to `writer`, returning the number of
+    /// bytes written.
+    pub fn write_to<W: Write>(&self, writer: W) -> io::Result<u64> {
+        let mut writer = ChunkWriter::new(writer);
 
-        file.write(&SIGN)?;
+        writer.write_signature()?;
+        writer.write_chunk(&self.header)?;
         for chunk in &self.chunks {
-            file.write(&chunk.to_bytes(&self.crc))?;
+            writer.write_chunk(chunk.as_ref())?;
         }
+        writer.write_chunk(&ImageTrailer)?;
+        writer.flush()?;
+
+        Ok(writer.bytes_written())
+    }
+
+    /// Encodes the PNG in memory.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write_to(&mut bytes)
+            .expect("Writing to a Vec cannot fail");
+        bytes
+    }
+
+    pub fn header(&self) -> &ImageHeader {
+        &self.header
+    }
+
+    pub fn header_mut(&mut self) -> &mut ImageHeader {
+        &mut self.header
+    }
+
+    /// Every chunk but IHDR and IEND, in file order.
+    pub fn chunks(&self) -> &[Box<dyn Chunk>] {
+        &self.chunks
+    }
+
+    pub fn palette(&self) -> Option<&Palette> {
+        self.chunk_of::<Palette>()
+    }
+
+    /// Returns the first chunk of type `T`.
+    pub fn chunk_of<T: Chunk + 'static>(&self) -> Option<&T> {
+        self.chunks_of::<T>().next()
+    }
 
-        Ok(())
+    /// Iterates over every chunk of type `T`, in file order.
+    pub fn chunks_of<T: Chunk + 'static>(&self) -> impl Iterator<Item = &T> {
+        self.chunks
+            .iter()
+            .filter_map(|chunk| chunk.downcast_ref::<T>())
     }
+
+    /// Iterates over every chunk with the given chunk code, in file order. Useful for chunks that
+    /// have no data structure yet and are stored as a `GenericChunk`.
+    pub fn chunks_by_type(&self, chunk_type: ChunkType) -> impl Iterator<Item = &dyn Chunk> {
+        self.chunks
+            .iter()
+            .map(|chunk| chunk.as_ref())
+            .filter(move |chunk| chunk.get_type() == chunk_type)
+    }
+}
+
+/// Reads the first chunk, that must be an IHDR.
+fn read_header<R: Read>(reader: &mut ChunkReader<R>) -> io::Result<ImageHeader> {
+    let header = reader.read_chunk()?;
+    let header = match header.downcast_ref::<ImageHeader>() {
+        Some(header) => *header,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Expected IHDR as the first chunk, found {:?}",
+                    header.get_type()
+                ),
+            ))
+        }
+    };
+
+    Ok(header)
 }
@@ -0,0 +1,510 @@
+//! Lossless optimization: writes the same pixels in fewer bytes. The image is decoded and encoded
+//! again in several ways, and the smallest result is kept:
+//!
+//! 1. Reductions of the pixel format, when they do not change any pixel:
+//!    - 16-bit samples that are all multiples of 257 become 8-bit
+//!    - RGB becomes greyscale when every pixel is grey, and greyscale goes down to 4, 2 or 1 bits
+//!      when the levels allow it
+//!    - An alpha channel where every pixel is opaque is dropped
+//!    - Images with up to 256 colours become indexed-colour, with the palette sorted in different
+//!      ways (by colour, by how often each entry is used and by luminance)
+//! 2. Every filter strategy, with the first compression level
+//! 3. The best combinations with the rest of compression levels
+//!
+//! Every candidate is checked to decode to the same RGBA pixels as the original, and so is the final
+//! result. The ancillary chunks are kept: bKGD is converted to the new pixel format, while sBIT and
+//! hIST (which depend on the pixel format) and unknown chunks that are not safe to copy are removed
+//! if the format changes.
+
+use super::{
+    chunks::{
+        self, Background, Chunk, IccProfile, ImageHeader, Palette, Transparency, IDAT, PLTE, TRNS,
+    },
+    color::{ColorType, ConvertOptions, Luminance},
+    encoder::{EncodeOptions, FilterStrategy},
+    filter::FilterType,
+    icc,
+    image::{Image, PixelData},
+    quantize::QuantizeOptions,
+    Png,
+};
+use crate::compression::Compression;
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+};
+
+/// Chunks that must go before PLTE.
+const BEFORE_PALETTE: [&str; 5] = ["gAMA", "cHRM", "sRGB", "iCCP", "sBIT"];
+
+/// Chunks whose meaning depends on the pixel format, and that cannot be converted.
+const FORMAT_DEPENDENT: [&str; 2] = ["sBIT", "hIST"];
+
+/// Chunks that are not safe to copy (see `ChunkType::is_safe_to_copy`) but do not depend on the
+/// pixel format, so they are still valid.
+const FORMAT_INDEPENDENT: [&str; 5] = ["gAMA", "cHRM", "sRGB", "iCCP", "tIME"];
+
+/// Combinations of filter and compression that are tried with every compression level.
+const FINALISTS: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptimizeOptions {
+    pub filters: Vec<FilterStrategy>,
+    /// The first one is used to compare the filters, so it should be the fastest
+    pub compressions: Vec<Compression>,
+}
+
+impl Default for OptimizeOptions {
+    fn default() -> Self {
+        let mut filters: Vec<_> = FilterType::ALL
+            .into_iter()
+            .map(FilterStrategy::Fixed)
+            .collect();
+        filters.push(FilterStrategy::Adaptive);
+
+        Self {
+            filters,
+            compressions: vec![Compression::Default, Compression::Best],
+        }
+    }
+}
+
+/// What `Png::optimize` did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptimizeReport {
+    /// Size of the file before and after
+    pub original_size: u64,
+    pub optimized_size: u64,
+    /// The encoding that was chosen, or `None` if the original file was already the smallest
+    pub chosen: Option<(ImageHeader, FilterStrategy, Compression)>,
+    /// Number of encodings tried
+    pub trials: usize,
+}
+
+impl OptimizeReport {
+    /// Bytes saved (0 if the original was kept).
+    pub fn saved(&self) -> u64 {
+        self.original_size - self.optimized_size
+    }
+}
+
+impl Png {
+    /// Encodes the image again in the smallest way, see the module documentation.
+    pub fn optimize(&self, options: &OptimizeOptions) -> io::Result<(Png, OptimizeReport)> {
+        let image = self.decode()?;
+        let pixels = image.to_rgba_16()?;
+        let original_size = self.to_vec().len() as u64;
+
+        let mut report = OptimizeReport {
+            original_size,
+            optimized_size: original_size,
+            chosen: None,
+            trials: 0,
+        };
+
+        // (size, candidate, filter, compression)
+        let mut results = Vec::new();
+        let candidates: Vec<Image> = reductions(&image, &pixels, self)?
+            .into_iter()
+            .filter(|candidate| {
+                let background = self.chunk_of::<Background>();
+                candidate.to_rgba_16().is_ok_and(|other| other == pixels)
+                    && background.is_none_or(|background| {
+                        convert_background(&image, background, candidate).is_ok()
+                    })
+            })
+            .collect();
+
+        let Some(compression) = options.compressions.first() else {
+            return Ok((self.copy()?, report));
+        };
+        for (i, candidate) in candidates.iter().enumerate() {
+            for &filter in &options.filters {
+                let png = self.with_image(&image, candidate, filter, *compression)?;
+                results.push((png.to_vec().len(), i, filter, *compression));
+            }
+        }
+
+        results.sort_by_key(|&(size, ..)| size);
+        let finalists: Vec<_> = results.iter().take(FINALISTS).copied().collect();
+        for (_, i, filter, _) in finalists {
+            for &compression in &options.compressions[1..] {
+                let png = self.with_image(&image, &candidates[i], filter, compression)?;
+                results.push((png.to_vec().len(), i, filter, compression));
+            }
+        }
+        report.trials = results.len();
+
+        results.sort_by_key(|&(size, ..)| size);
+        for (size, i, filter, compression) in results {
+            if size as u64 >= original_size {
+                break;
+            }
+
+            // The final check, decoding the file as any reader would
+            let png = self.with_image(&image, &candidates[i], filter, compression)?;
+            let decoded = Png::read_from(&png.to_vec()[..])?.decode()?;
+            if decoded.to_rgba_16()? != pixels {
+                continue;
+            }
+
+            report.optimized_size = size as u64;
+            report.chosen = Some((*png.header(), filter, compression));
+            return Ok((png, report));
+        }
+
+        Ok((self.copy()?, report))
+    }
+
+    /// A copy of this PNG, with the same chunks.
+    fn copy(&self) -> io::Result<Png> {
+        Ok(Png {
+            header: self.header,
+            chunks: self
+                .chunks
+                .iter()
+                .map(|chunk| copy_chunk(chunk.as_ref()))
+                .collect::<io::Result<_>>()?,
+        })
+    }
+
+    /// Encodes `candidate` (the same pixels as `image`, the decoded image of this PNG) keeping the
+    /// ancillary chunks of this PNG.
+    fn with_image(
+        &self,
+        image: &Image,
+        candidate: &Image,
+        filter: FilterStrategy,
+        compression: Compression,
+    ) -> io::Result<Png> {
+        let options = EncodeOptions {
+            filter,
+            compression,
+            idat_size: (1 << 31) - 1,
+            threads: 1,
+            interlace: false,
+        };
+        let mut png = Png::from_image(candidate, &options)?;
+
+        let same_format = image.color_type == candidate.color_type
+            && image.bit_depth == candidate.bit_depth
+            && image.palette == candidate.palette;
+
+        let mut before_palette = Vec::new();
+        let mut before_data = Vec::new();
+        let mut after_data = Vec::new();
+        let mut data_seen = false;
+
+        for chunk in &self.chunks {
+            let chunk_type = chunk.get_type();
+            let code = chunk_type.get_char_code().unwrap_or_default();
+            data_seen |= chunk_type == IDAT;
+
+            let copy = match chunk_type {
+                IDAT | PLTE | TRNS => continue,
+                _ if same_format => copy_chunk(chunk.as_ref())?,
+                _ if FORMAT_DEPENDENT.contains(&code) => continue,
+                _ => match chunk.downcast_ref::<Background>() {
+                    Some(background) => Box::new(convert_background(image, background, candidate)?),
+                    None if chunk_type.is_safe_to_copy() || FORMAT_INDEPENDENT.contains(&code) => {
+                        copy_chunk(chunk.as_ref())?
+                    }
+                    None => continue,
+                },
+            };
+
+            if BEFORE_PALETTE.contains(&code) {
+                before_palette.push(copy);
+            } else if data_seen {
+                after_data.push(copy);
+            } else {
+                before_data.push(copy);
+            }
+        }
+
+        let first_data = png.chunks.iter().position(|chunk| chunk.get_type() == IDAT);
+        let first_data = first_data.unwrap_or(png.chunks.len());
+        png.chunks.splice(first_data..first_data, before_data);
+        png.chunks.splice(0..0, before_palette);
+        png.chunks.extend(after_data);
+
+        Ok(png)
+    }
+}
+
+/// Copies a chunk through its bytes, since `dyn Chunk` cannot be cloned. It fails for chunks
+/// built with invalid data, which `chunks::parse` does not accept.
+fn copy_chunk(chunk: &dyn Chunk) -> io::Result<Box<dyn Chunk>> {
+    chunks::parse(chunk.get_type(), &chunk.data_to_bytes())
+}
+
+/// The same background colour in the pixel format of `candidate`. It fails if it cannot be
+/// represented exactly.
+fn convert_background(
+    image: &Image,
+    background: &Background,
+    candidate: &Image,
+) -> io::Result<Background> {
+    let [r, g, b] = image.background_rgb_16(background)?;
+
+    let step = 65535 / ((1 << candidate.bit_depth.min(8)) - 1) as u16;
+    let step = if candidate.bit_depth == 16 { 1 } else { step };
+    let exact = |sample: u16| sample.is_multiple_of(step).then_some(sample / step);
+
+    let converted = match candidate.color() {
+        Some(ColorType::Indexed) => candidate
+            .palette
+            .as_ref()
+            .and_then(|palette| {
+                palette
+                    .entries
+                    .iter()
+                    .position(|entry| entry.map(|sample| sample as u16 * 257) == [r, g, b])
+            })
+            .map(|index| Background::palette(index as u8)),
+        Some(ColorType::Greyscale | ColorType::GreyscaleAlpha) if r == g && g == b => {
+            exact(r).map(Background::grey)
+        }
+        Some(ColorType::Truecolour | ColorType::TruecolourAlpha) => {
+            match (exact(r), exact(g), exact(b)) {
+                (Some(r), Some(g), Some(b)) => Some(Background::rgb(r, g, b)),
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+
+    converted.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The background colour cannot be represented in the new pixel format",
+        )
+    })
+}
+
+/// The pixel formats to try, starting with the original one. Some of them may turn out to change
+/// the pixels, so they are checked afterwards.
+fn reductions(image: &Image, pixels: &[[u16; 4]], png: &Png) -> io::Result<Vec<Image>> {
+    let mut candidates = vec![image.clone()];
+
+    let grey = pixels.iter().all(|&[r, g, b, _]| r == g && g == b);
+    let opaque = pixels.iter().all(|pixel| pixel[3] == u16::MAX);
+    let fits_8 = pixels
+        .iter()
+        .flatten()
+        .all(|sample| sample.is_multiple_of(257));
+
+    // A greyscale ICC profile cannot describe a colour image, nor the other way around. If the
+    // profile cannot be read, the image keeps its kind
+    let is_grey = !ColorType::from_code(image.color_type).is_some_and(ColorType::has_colour);
+    let (grey_allowed, colour_allowed) = match png.chunk_of::<IccProfile>() {
+        Some(icc) => match icc.profile().ok().as_deref().and_then(icc::color_space) {
+            Some(space) => (&space == b"GRAY", &space == b"RGB "),
+            None => (is_grey, !is_grey),
+        },
+        None => (true, true),
+    };
+
+    let color_type = match (grey && grey_allowed, opaque) {
+        (true, true) => ColorType::Greyscale,
+        (true, false) => ColorType::GreyscaleAlpha,
+        (false, true) => ColorType::Truecolour,
+        (false, false) => ColorType::TruecolourAlpha,
+    };
+    let mut bit_depth = if fits_8 { 8 } else { 16 };
+
+    // The lowest bit depth where every grey level is exact
+    if color_type == ColorType::Greyscale && fits_8 {
+        for low in [1, 2, 4] {
+            let step = 257 * (255 / ((1 << low) - 1));
+            if pixels.iter().all(|pixel| pixel[0].is_multiple_of(step)) {
+                bit_depth = low;
+                break;
+            }
+        }
+    }
+
+    let options = ConvertOptions::default();
+    let reduced = image.convert(color_type, bit_depth, &options)?;
+    if reduced != *image {
+        candidates.push(reduced);
+    }
+
+    // A palette of colours of 8 bits
+    let colours = pixels.iter().collect::<HashSet<_>>().len();
+    if fits_8 && colours <= 256 && colour_allowed {
+        let indexed = image.quantize(&QuantizeOptions::default())?;
+        let counts = index_counts(&indexed);
+
+        let by_count = sort_palette(&indexed, |index, _| u64::MAX - counts[&index]);
+        let by_luminance = sort_palette(&indexed, |_, [r, g, b]| {
+            Luminance::Rec601.of([r as u16, g as u16, b as u16]) as u64
+        });
+        candidates.extend([indexed, by_count, by_luminance]);
+    }
+
+    Ok(candidates)
+}
+
+/// Number of pixels with each palette index.
+fn index_counts(image: &Image) -> HashMap<u8, u64> {
+    let mut counts = HashMap::new();
+    for &index in image.as_u8().unwrap_or_default() {
+        *counts.entry(index).or_default() += 1;
+    }
+    counts
+}
+
+/// Reorders the palette by the given key (of each index and its colour), keeping the transparent
+/// entries first so that the tRNS stays short.
+fn sort_palette(image: &Image, key: impl Fn(u8, [u8; 3]) -> u64) -> Image {
+    let Some(palette) = &image.palette else {
+        return image.clone();
+    };
+    let alphas = image.transparency.as_ref();
+    let alpha = |index: usize| alphas.map_or(255, |alphas| alphas.palette_alpha(index));
+
+    let mut order: Vec<usize> = (0..palette.entries.len()).collect();
+    order.sort_by_key(|&index| {
+        (
+            alpha(index) == 255,
+            key(index as u8, palette.entries[index]),
+        )
+    });
+
+    let mut new_index = vec![0; order.len()];
+    for (new, &old) in order.iter().enumerate() {
+        new_index[old] = new as u8;
+    }
+
+    let transparent = order.iter().take_while(|&&index| alpha(index) != 255);
+    let data = image.as_u8().unwrap_or_default();
+
+    Image {
+        data: PixelData::U8(data.iter().map(|&i| new_index[i as usize]).collect()),
+        palette: Some(Palette::new(
+            order.iter().map(|&index| palette.entries[index]).collect(),
+        )),
+        transparency: image
+            .transparency
+            .as_ref()
+            .map(|_| Transparency::palette(transparent.map(|&index| alpha(index)).collect())),
+        ..image.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::chunks::{ChunkType, GenericChunk};
+
+    #[test]
+    fn optimize_test() {
+        // 16-bit RGBA with grey opaque pixels and only 4 levels
+        let (width, height) = (40, 30);
+        let data = (0..width * height)
+            .flat_map(|i| {
+                let level = (i % 4) as u16 * 85 * 257;
+                [level, level, level, u16::MAX]
+            })
+            .collect();
+        let image = Image::new_16(width, height, ImageHeader::TRUECOLOUR_ALPHA, data);
+
+        let options = EncodeOptions {
+            compression: Compression::None,
+            ..Default::default()
+        };
+        let mut png = Png::from_image(&image, &options).unwrap();
+        png.chunks.insert(0, Box::new(Background::rgb(0, 0, 0)));
+        png.chunks.push(Box::new(GenericChunk::from_bytes(
+            ChunkType::from_code("tEXt").unwrap(),
+            b"Title\0Test",
+        )));
+
+        let (optimized, report) = png.optimize(&OptimizeOptions::default()).unwrap();
+        assert!(report.saved() > 0);
+        assert_eq!(report.optimized_size, optimized.to_vec().len() as u64);
+
+        // Greyscale of 2 bits, or a palette of 4 entries
+        let header = optimized.header();
+        assert!(header.bit_depth == 2, "{:?}", header);
+        assert_eq!(
+            optimized.decode().unwrap().to_rgba_16().unwrap(),
+            image.to_rgba_16().unwrap()
+        );
+
+        // The ancillary chunks are kept, bKGD converted
+        let background = optimized.chunk_of::<Background>().unwrap();
+        assert!(background.data.len() < 6);
+        assert_eq!(
+            optimized.chunks.last().unwrap().get_type(),
+            ChunkType::from_code("tEXt").unwrap()
+        );
+
+        // An already optimized file is kept
+        let (_, report) = optimized.optimize(&OptimizeOptions::default()).unwrap();
+        assert_eq!(report.saved(), 0);
+
+        // A chunk built with invalid data fails instead of panicking when it is copied
+        let mut png = Png::from_image(&image, &options).unwrap();
+        png.insert(Box::new(Background {
+            data: vec![1, 2, 3],
+        }))
+        .unwrap();
+        assert!(png.optimize(&OptimizeOptions::default()).is_err());
+    }
+
+    #[test]
+    fn icc_test() {
+        // Grey pixels, which would become a greyscale image without a profile
+        let image = Image::rgb(8, 8, (0..8 * 8 * 3).map(|i| (i / 3 * 4) as u8).collect());
+
+        // RGB profile with Lab as PCS, which `icc::parse` does not support
+        let mut profile = vec![0; 132];
+        profile[..4].copy_from_slice(&132_u32.to_be_bytes());
+        profile[16..20].copy_from_slice(b"RGB ");
+        profile[20..24].copy_from_slice(b"Lab ");
+        profile[36..40].copy_from_slice(b"acsp");
+        assert!(icc::parse(&profile).is_err());
+
+        let corrupt = IccProfile {
+            name: b"corrupt".to_vec(),
+            compression: 0,
+            compressed: vec![1, 2, 3],
+        };
+        for icc in [
+            IccProfile::new("Lab", &profile),
+            IccProfile::new("truncated", &profile[..64]),
+            corrupt,
+        ] {
+            let mut png = Png::from_image(&image, &EncodeOptions::default()).unwrap();
+            png.chunks.insert(0, Box::new(icc));
+
+            let (optimized, _) = png.optimize(&OptimizeOptions::default()).unwrap();
+            let color_type = ColorType::from_code(optimized.header().color_type).unwrap();
+            assert!(color_type.has_colour(), "{:?}", color_type);
+            assert_eq!(
+                optimized.decode().unwrap().to_rgba_16().unwrap(),
+                image.to_rgba_16().unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn palette_test() {
+        let palette = Palette::new(vec![[0, 0, 0], [200, 0, 0], [0, 0, 255]]);
+        let mut image = Image::indexed(3, 1, vec![2, 1, 2], palette);
+        image.transparency = Some(Transparency::palette(vec![255, 255, 0]));
+
+        // Transparent first, then by luminance
+        let sorted = sort_palette(&image, |_, [r, g, b]| {
+            Luminance::Rec601.of([r as u16, g as u16, b as u16]) as u64
+        });
+        assert_eq!(
+            sorted.palette.as_ref().unwrap().entries,
+            vec![[0, 0, 255], [0, 0, 0], [200, 0, 0]]
+        );
+        assert_eq!(sorted.as_u8().unwrap(), [0, 2, 0]);
+        assert_eq!(sorted.to_rgba_16().unwrap(), image.to_rgba_16().unwrap());
+    }
+}
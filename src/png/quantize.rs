@@ -0,0 +1,400 @@
+//! Colour quantization: reduces an image to a palette of at most 256 colours, so that it can be
+//! stored as an indexed-colour image (which usually takes much less space).
+//!
+//! If the image already has few enough colours, they are used as they are and nothing is lost.
+//! Otherwise a palette is chosen with one of these methods, working on the RGBA colours reduced to
+//! 8 bits:
+//!
+//! - Median cut: starts with a box that contains all the colours, and repeatedly splits the box
+//!   with the longest side at the median of that channel, until there are as many boxes as
+//!   palette entries. Each box gives the average of its colours.
+//! - Octree: puts the colours in a tree where each level looks at one more bit of each channel
+//!   (with 4 channels, each node has up to 16 children), and then merges the least used leaves
+//!   with their siblings, from the deepest level up, until there are few enough leaves.
+//!
+//! Each pixel is then replaced by the nearest palette entry. With Floyd-Steinberg dithering, the
+//! difference between the pixel and its entry (the error) is spread over the neighbours that have
+//! not been processed yet, which hides the bands of flat colour at the cost of some noise:
+//!
+//! ```text
+//!          pixel   7/16
+//!   3/16   5/16    1/16
+//! ```
+
+use super::{
+    chunks::{Palette, Transparency},
+    color::ColorType,
+    image::{Image, PixelData},
+    samples,
+};
+use std::{collections::HashMap, io};
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum QuantizeMethod {
+    #[default]
+    MedianCut,
+    Octree,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct QuantizeOptions {
+    /// Maximum number of palette entries, from 1 to 256
+    pub colours: usize,
+    pub method: QuantizeMethod,
+    /// Floyd-Steinberg dithering
+    pub dither: bool,
+}
+
+impl Default for QuantizeOptions {
+    fn default() -> Self {
+        Self {
+            colours: 256,
+            method: QuantizeMethod::default(),
+            dither: false,
+        }
+    }
+}
+
+type Colour = [u8; 4];
+
+impl Image {
+    /// Converts the image to indexed colour, see the module documentation. The bit depth is the
+    /// smallest that fits the palette. Transparent entries go first in the palette, so that the
+    /// tRNS is as short as possible.
+    pub fn quantize(&self, options: &QuantizeOptions) -> io::Result<Image> {
+        if !(1..=256).contains(&options.colours) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid number of colours {}", options.colours),
+            ));
+        }
+
+        let pixels: Vec<Colour> = self
+            .to_rgba_16()?
+            .into_iter()
+            .map(|pixel| pixel.map(samples::round_16_to_8))
+            .collect();
+
+        let mut histogram: HashMap<Colour, u64> = HashMap::new();
+        for &pixel in &pixels {
+            *histogram.entry(pixel).or_default() += 1;
+        }
+        let mut histogram: Vec<(Colour, u64)> = histogram.into_iter().collect();
+        // The order of a HashMap changes between runs, but the result should not
+        histogram.sort_unstable();
+
+        let mut palette = if histogram.len() <= options.colours {
+            histogram.iter().map(|&(colour, _)| colour).collect()
+        } else {
+            match options.method {
+                QuantizeMethod::MedianCut => median_cut(histogram, options.colours),
+                QuantizeMethod::Octree => octree(&histogram, options.colours),
+            }
+        };
+        palette.sort_by_key(|colour| colour[3] == 255);
+
+        let indices = if options.dither {
+            dither(&pixels, self.width as usize, &palette)
+        } else {
+            let mut nearest_cache = HashMap::new();
+            pixels
+                .iter()
+                .map(|pixel| {
+                    *nearest_cache
+                        .entry(*pixel)
+                        .or_insert_with(|| nearest(&palette, pixel.map(|sample| sample as f32)))
+                })
+                .collect()
+        };
+
+        let bit_depth = match palette.len() {
+            0..=2 => 1,
+            3..=4 => 2,
+            5..=16 => 4,
+            _ => 8,
+        };
+        let alphas: Vec<u8> = palette
+            .iter()
+            .map(|colour| colour[3])
+            .take_while(|&alpha| alpha != 255)
+            .collect();
+
+        Ok(Image {
+            width: self.width,
+            height: self.height,
+            color_type: ColorType::Indexed.code(),
+            bit_depth,
+            data: PixelData::U8(indices),
+            palette: Some(Palette::new(
+                palette.iter().map(|&[r, g, b, _]| [r, g, b]).collect(),
+            )),
+            transparency: (!alphas.is_empty()).then(|| Transparency::palette(alphas)),
+        })
+    }
+}
+
+/// Index of the palette entry closest to the given colour.
+fn nearest(palette: &[Colour], colour: [f32; 4]) -> u8 {
+    let distance =
+        |entry: &Colour| -> f32 { (0..4).map(|i| (entry[i] as f32 - colour[i]).powi(2)).sum() };
+
+    (0..palette.len())
+        .min_by(|&a, &b| distance(&palette[a]).total_cmp(&distance(&palette[b])))
+        .unwrap() as u8
+}
+
+/// Average of some colours, weighted by the number of pixels of each one.
+fn average<'a>(colours: impl Iterator<Item = &'a (Colour, u64)>) -> Colour {
+    let mut sums = [0; 4];
+    let mut total = 0;
+    for (colour, count) in colours {
+        for (sum, &sample) in sums.iter_mut().zip(colour) {
+            *sum += sample as u64 * count;
+        }
+        total += count;
+    }
+    sums.map(|sum| ((sum + total / 2) / total) as u8)
+}
+
+fn median_cut(histogram: Vec<(Colour, u64)>, colours: usize) -> Vec<Colour> {
+    // Channel with the largest range in a box, and that range
+    let longest_side = |colours: &[(Colour, u64)]| {
+        (0..4)
+            .map(|channel| {
+                let samples = colours.iter().map(|(colour, _)| colour[channel]);
+                let range = samples.clone().max().unwrap() - samples.min().unwrap();
+                (range, channel)
+            })
+            .max()
+            .unwrap()
+    };
+
+    let mut boxes = vec![histogram];
+    while boxes.len() < colours {
+        let Some((i, channel)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, colours)| colours.len() > 1)
+            .map(|(i, colours)| (longest_side(colours), i))
+            .max()
+            .map(|((_, channel), i)| (i, channel))
+        else {
+            break;
+        };
+
+        // Split at the median pixel, keeping at least one colour on each side
+        let mut colours = boxes.swap_remove(i);
+        colours.sort_unstable_by_key(|(colour, _)| colour[channel]);
+        let half = colours.iter().map(|(_, count)| count).sum::<u64>() / 2;
+        let mut seen = 0;
+        let split = colours
+            .iter()
+            .position(|(_, count)| {
+                seen += count;
+                seen > half
+            })
+            .unwrap()
+            .clamp(1, colours.len() - 1);
+
+        let upper = colours.split_off(split);
+        boxes.push(colours);
+        boxes.push(upper);
+    }
+
+    boxes
+        .iter()
+        .map(|colours| average(colours.iter()))
+        .collect()
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    /// Index of each child in the list of nodes, 0 if there is none (the root is never a child)
+    children: [u32; 16],
+    level: u8,
+    count: u64,
+    sums: [u64; 4],
+}
+
+fn octree(histogram: &[(Colour, u64)], colours: usize) -> Vec<Colour> {
+    // Colours that only differ in the last 2 bits of each channel go to the same leaf, which keeps
+    // the tree small and does not matter for a palette of at most 256 colours
+    const DEPTH: u8 = 6;
+
+    let mut nodes = vec![Node::default()];
+    for &(colour, count) in histogram {
+        let mut node = 0;
+        for level in 0..DEPTH {
+            let bit = 7 - level;
+            let child = (0..4).fold(0, |child, i| child << 1 | (colour[i] >> bit & 1) as usize);
+
+            if nodes[node].children[child] == 0 {
+                nodes[node].children[child] = nodes.len() as u32;
+                nodes.push(Node {
+                    level: level + 1,
+                    ..Default::default()
+                });
+            }
+            node = nodes[node].children[child] as usize;
+        }
+
+        nodes[node].count += count;
+        for (sum, sample) in nodes[node].sums.iter_mut().zip(colour) {
+            *sum += sample as u64 * count;
+        }
+    }
+
+    // Pixels under each node
+    for i in (0..nodes.len()).rev() {
+        let children = nodes[i].children;
+        for child in children.into_iter().filter(|&child| child != 0) {
+            let child = child as usize;
+            nodes[i].count += nodes[child].count;
+            let sums = nodes[child].sums;
+            for (sum, child_sum) in nodes[i].sums.iter_mut().zip(sums) {
+                *sum += child_sum;
+            }
+        }
+    }
+
+    let mut leaves = nodes.iter().filter(|node| node.level == DEPTH).count();
+    'levels: for level in (0..DEPTH).rev() {
+        let mut reducible: Vec<usize> = (0..nodes.len())
+            .filter(|&i| nodes[i].level == level && nodes[i].children.iter().any(|&c| c != 0))
+            .collect();
+        reducible.sort_by_key(|&i| nodes[i].count);
+
+        for i in reducible {
+            if leaves <= colours {
+                break 'levels;
+            }
+            let children = nodes[i].children.iter().filter(|&&c| c != 0).count();
+            nodes[i].children = [0; 16];
+            leaves -= children - 1;
+        }
+    }
+
+    // The leaves are the nodes without children that are still reachable from the root
+    let mut palette = Vec::new();
+    let mut stack = vec![0];
+    while let Some(i) = stack.pop() {
+        let node = &nodes[i];
+        if node.children.iter().all(|&c| c == 0) {
+            palette.push(
+                node.sums
+                    .map(|sum| ((sum + node.count / 2) / node.count) as u8),
+            );
+        } else {
+            stack.extend(
+                node.children
+                    .iter()
+                    .filter(|&&c| c != 0)
+                    .map(|&c| c as usize),
+            );
+        }
+    }
+    palette
+}
+
+/// Floyd-Steinberg dithering, see the module documentation.
+fn dither(pixels: &[Colour], width: usize, palette: &[Colour]) -> Vec<u8> {
+    let mut indices = Vec::with_capacity(pixels.len());
+    if width == 0 {
+        return indices;
+    }
+
+    // Errors of the current and the next row, with a column of margin on each side
+    let mut current = vec![[0.0_f32; 4]; width + 2];
+    let mut next = current.clone();
+
+    for row in pixels.chunks_exact(width) {
+        for (x, pixel) in row.iter().enumerate() {
+            let wanted =
+                [0, 1, 2, 3].map(|i| (pixel[i] as f32 + current[x + 1][i]).clamp(0.0, 255.0));
+            let index = nearest(palette, wanted);
+            indices.push(index);
+
+            let chosen = palette[index as usize];
+            for i in 0..4 {
+                let error = wanted[i] - chosen[i] as f32;
+                current[x + 2][i] += error * 7.0 / 16.0;
+                next[x][i] += error * 3.0 / 16.0;
+                next[x + 1][i] += error * 5.0 / 16.0;
+                next[x + 2][i] += error * 1.0 / 16.0;
+            }
+        }
+
+        std::mem::swap(&mut current, &mut next);
+        next.iter_mut().for_each(|error| *error = [0.0; 4]);
+    }
+
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::{encoder::EncodeOptions, Png};
+
+    /// A gradient with thousands of colours.
+    fn gradient() -> Image {
+        let (width, height) = (64, 64);
+        let data = (0..height)
+            .flat_map(|y| (0..width).flat_map(move |x| [x * 4, y * 4, 128, 255]))
+            .map(|sample| sample as u8)
+            .collect();
+        Image::rgba(width, height, data)
+    }
+
+    /// Average difference per sample between the original and the quantized image.
+    fn error(original: &Image, quantized: &Image) -> f64 {
+        let original = original.to_rgba_16().unwrap();
+        let quantized = quantized.to_rgba_16().unwrap();
+        let total: f64 = original
+            .iter()
+            .flatten()
+            .zip(quantized.iter().flatten())
+            .map(|(&a, &b)| (a as f64 - b as f64).abs() / 257.0)
+            .sum();
+        total / (original.len() * 4) as f64
+    }
+
+    #[test]
+    fn quantize_test() {
+        let image = gradient();
+        for method in [QuantizeMethod::MedianCut, QuantizeMethod::Octree] {
+            for dither in [false, true] {
+                let options = QuantizeOptions {
+                    colours: 16,
+                    method,
+                    dither,
+                };
+                let quantized = image.quantize(&options).unwrap();
+                assert_eq!(quantized.bit_depth, 4);
+                assert!(quantized.palette.as_ref().unwrap().entries.len() <= 16);
+                assert!(error(&image, &quantized) < 12.0, "{:?}", options);
+
+                // It can be encoded as is
+                let png = Png::from_image(&quantized, &EncodeOptions::default()).unwrap();
+                assert_eq!(png.decode().unwrap(), quantized);
+            }
+        }
+    }
+
+    #[test]
+    fn exact_test() {
+        // Few colours are kept exactly, the transparent one first
+        let image = Image::rgba(3, 1, vec![10, 20, 30, 255, 0, 0, 0, 0, 10, 20, 30, 255]);
+        let quantized = image.quantize(&QuantizeOptions::default()).unwrap();
+        assert_eq!(quantized.bit_depth, 1);
+        assert_eq!(quantized.as_u8().unwrap(), [1, 0, 1]);
+        assert_eq!(quantized.transparency, Some(Transparency::palette(vec![0])));
+        assert_eq!(error(&image, &quantized), 0.0);
+
+        let options = QuantizeOptions {
+            colours: 0,
+            ..Default::default()
+        };
+        assert!(image.quantize(&options).is_err());
+    }
+}
@@ -0,0 +1,239 @@
+//! Reads chunks from any `io::Read`, the counterpart of `ChunkWriter`. The data of a chunk can be
+//! read all at once (`read_chunk`) or in pieces (`begin_chunk`, `read_data` and `end_chunk`), so
+//! that big chunks such as IDAT do not need to be fully loaded in memory.
+
+use super::{
+    chunks::{self, Chunk, ChunkType},
+    crc::CrcHasher,
+    SIGN,
+};
+use std::io::{self, Read};
+
+/// Chunks longer than this are not valid.
+const MAX_CHUNK_SIZE: u32 = (1 << 31) - 1;
+
+pub struct ChunkReader<R: Read> {
+    reader: R,
+    bytes_read: u64,
+    /// Chunk whose data is being read
+    current: Option<CurrentChunk>,
+}
+
+struct CurrentChunk {
+    chunk_type: ChunkType,
+    remaining: u32,
+    crc: CrcHasher,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            bytes_read: 0,
+            current: None,
+        }
+    }
+
+    /// Reads and checks the PNG signature.
+    pub fn read_signature(&mut self) -> io::Result<()> {
+        let mut signature = [0; SIGN.len()];
+        self.read_exact(&mut signature)?;
+
+        if signature != SIGN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "The given file is not a PNG file",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reads a complete chunk, checking its CRC.
+    pub fn read_chunk(&mut self) -> io::Result<Box<dyn Chunk>> {
+        self.begin_chunk()?;
+        self.finish_chunk()
+    }
+
+    /// Reads the length and type of the next chunk, returning them. Its data must then be read
+    /// with `read_data` and `end_chunk`, or with `finish_chunk`.
+    pub fn begin_chunk(&mut self) -> io::Result<(ChunkType, u32)> {
+        if self.current.is_some() {
+            self.end_chunk()?;
+        }
+
+        let mut bytes = [0; 8];
+        self.read_exact(&mut bytes)?;
+
+        let data_size = u32::from_be_bytes(bytes[..4].try_into().unwrap());
+        if data_size > MAX_CHUNK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid chunk length {}", data_size),
+            ));
+        }
+
+        let chunk_type = ChunkType::from_slice(&bytes[4..]).unwrap();
+        let mut crc = CrcHasher::new();
+        crc.update(&bytes[4..]);
+        self.current = Some(CurrentChunk {
+            chunk_type,
+            remaining: data_size,
+            crc,
+        });
+
+        Ok((chunk_type, data_size))
+    }
+
+    /// Reads data of the current chunk into `buf`, returning the number of bytes read. Returns 0
+    /// once all the data has been read.
+    pub fn read_data(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(current) = &mut self.current else {
+            return Ok(0);
+        };
+
+        let len = buf.len().min(current.remaining as usize);
+        self.reader.read_exact(&mut buf[..len]).map_err(ended)?;
+
+        current.remaining -= len as u32;
+        current.crc.update(&buf[..len]);
+        self.bytes_read += len as u64;
+
+        Ok(len)
+    }
+
+    /// Skips the rest of the data of the current chunk and checks its CRC.
+    pub fn end_chunk(&mut self) -> io::Result<()> {
+        let mut buf = [0; 4096];
+        while self.read_data(&mut buf)? > 0 {}
+
+        let Some(current) = self.current.take() else {
+            return Ok(());
+        };
+
+        // CRC checking
+        // TODO: make optional
+        let mut read_crc = [0; 4];
+        self.read_exact(&mut read_crc)?;
+        let read_crc = u32::from_be_bytes(read_crc);
+
+        let crc = current.crc.finalize();
+        if crc != read_crc {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "The CRCs of chunk {:?} do not match: read {}, calculated {}",
+                    current.chunk_type, read_crc, crc
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Reads the rest of the current chunk and builds it.
+    pub fn finish_chunk(&mut self) -> io::Result<Box<dyn Chunk>> {
+        let Some(current) = &mut self.current else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No chunk is being read",
+            ));
+        };
+        let chunk_type = current.chunk_type;
+
+        // The buffer grows with the data that is actually read, instead of trusting the length
+        // of a chunk that may come from a broken file
+        let mut data = Vec::new();
+        (&mut self.reader)
+            .take(current.remaining as u64)
+            .read_to_end(&mut data)?;
+        if data.len() < current.remaining as usize {
+            return Err(ended(io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        current.remaining = 0;
+        current.crc.update(&data);
+        self.bytes_read += data.len() as u64;
+        self.end_chunk()?;
+
+        chunks::parse(chunk_type, &data)
+    }
+
+    /// Total number of bytes read so far.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.reader.read_exact(buf).map_err(ended)?;
+        self.bytes_read += buf.len() as u64;
+        Ok(())
+    }
+}
+
+/// Gives a clearer message to the end of file errors.
+fn ended(error: io::Error) -> io::Error {
+    if error.kind() == io::ErrorKind::UnexpectedEof {
+        io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "The file ended in the middle of a chunk",
+        )
+    } else {
+        error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::{
+        chunks::{ImageData, Palette, IDAT},
+        writer::ChunkWriter,
+    };
+
+    #[test]
+    fn read_written_test() {
+        let mut writer = ChunkWriter::new(Vec::new());
+        writer.write_signature().unwrap();
+        writer
+            .write_chunk(&Palette::new(vec![[1, 2, 3], [4, 5, 6]]))
+            .unwrap();
+        writer.write_raw_chunk(IDAT, b"0123456789").unwrap();
+        let bytes = writer.into_inner();
+
+        let mut reader = ChunkReader::new(&bytes[..]);
+        reader.read_signature().unwrap();
+
+        let palette = reader.read_chunk().unwrap();
+        assert_eq!(palette.downcast_ref::<Palette>().unwrap().entries.len(), 2);
+
+        // Read the IDAT in pieces
+        assert_eq!(reader.begin_chunk().unwrap(), (IDAT, 10));
+        let mut data = [0; 4];
+        assert_eq!(reader.read_data(&mut data).unwrap(), 4);
+        assert_eq!(&data, b"0123");
+        let rest = reader.finish_chunk().unwrap();
+        assert_eq!(rest.downcast_ref::<ImageData>().unwrap().data, b"456789");
+
+        assert_eq!(reader.bytes_read(), bytes.len() as u64);
+        assert!(reader.begin_chunk().is_err());
+
+        // Corrupted CRC
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        let mut reader = ChunkReader::new(&corrupted[..]);
+        reader.read_signature().unwrap();
+        reader.read_chunk().unwrap();
+        assert!(reader.read_chunk().is_err());
+
+        // A huge length in a tiny file
+        let mut truncated = MAX_CHUNK_SIZE.to_be_bytes().to_vec();
+        truncated.extend_from_slice(b"IDAT0123");
+        let mut reader = ChunkReader::new(&truncated[..]);
+        let error = reader.read_chunk().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,130 @@
+//! Samples smaller than a byte (bit depths 1, 2 and 4, only for greyscale and indexed-colour
+//! images) are packed into bytes, with the leftmost pixel in the most significant bits of the byte:
+//!
+//! ```text
+//! bit depth 2:  | 7 6 | 5 4 | 3 2 | 1 0 |
+//!               | x=0 | x=1 | x=2 | x=3 |
+//! ```
+//!
+//! Each scanline starts at a byte boundary, so if the width times the bit depth is not a multiple
+//! of 8, the last byte of each scanline has some unused bits (padding). Their value does not
+//! matter, but they are written as zeros.
+//!
+//! An `Image` keeps one sample per byte, so scanlines are unpacked when decoding and packed again
+//! when encoding.
+
+/// Extracts `count` samples of `bit_depth` bits (1, 2, 4 or 8) from a packed scanline, one per
+/// byte. The padding bits are ignored.
+pub fn unpack(packed: &[u8], bit_depth: u8, count: usize) -> Vec<u8> {
+    debug_assert!(matches!(bit_depth, 1 | 2 | 4 | 8));
+    if bit_depth == 8 {
+        return packed[..count].to_vec();
+    }
+
+    let bits = bit_depth as usize;
+    let per_byte = 8 / bits;
+    let mask = (1 << bit_depth) - 1;
+
+    (0..count)
+        .map(|i| {
+            let shift = 8 - bits * (i % per_byte + 1);
+            (packed[i / per_byte] >> shift) & mask
+        })
+        .collect()
+}
+
+/// Packs samples of `bit_depth` bits (1, 2, 4 or 8), given one per byte, into a scanline. The
+/// padding bits are zero. Only the `bit_depth` least significant bits of each sample are used.
+pub fn pack(samples: &[u8], bit_depth: u8) -> Vec<u8> {
+    debug_assert!(matches!(bit_depth, 1 | 2 | 4 | 8));
+    if bit_depth == 8 {
+        return samples.to_vec();
+    }
+
+    let bits = bit_depth as usize;
+    let per_byte = 8 / bits;
+    let mask = (1 << bit_depth) - 1;
+
+    samples
+        .chunks(per_byte)
+        .map(|group| {
+            group.iter().enumerate().fold(0, |byte, (i, &sample)| {
+                byte | (sample & mask) << (8 - bits * (i + 1))
+            })
+        })
+        .collect()
+}
+
+/// Scales a sample of `bit_depth` bits (1 to 8) to the 0-255 range using bit replication: the bits
+/// of the sample are repeated until the byte is filled, so that 0 stays at 0 and the maximum
+/// value becomes 255. For example, `101` (3 bits) becomes `10110110`.
+///
+/// For bit depths 1, 2 and 4 this is the same as multiplying by 255, 85 and 17.
+pub fn scale_to_8(sample: u8, bit_depth: u8) -> u8 {
+    debug_assert!((1..=8).contains(&bit_depth));
+
+    let mut scaled = (sample as u16) << (8 - bit_depth);
+    let mut filled = bit_depth;
+    while filled < 8 {
+        scaled |= scaled >> filled;
+        filled *= 2;
+    }
+
+    scaled as u8
+}
+
+/// Reduces an 8-bit sample to `bit_depth` bits (1 to 8), keeping the most significant ones. It is
+/// the inverse of `scale_to_8`.
+pub fn scale_from_8(sample: u8, bit_depth: u8) -> u8 {
+    debug_assert!((1..=8).contains(&bit_depth));
+    sample >> (8 - bit_depth)
+}
+
+/// Reduces a 16-bit sample to 8 bits, rounding to the nearest value (`round(x * 255 / 65535)`).
+/// Simply keeping the high byte would make most samples slightly darker.
+pub fn round_16_to_8(sample: u16) -> u8 {
+    ((sample as u32 * 255 + 32767) / 65535) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_test() {
+        // 5 samples of 2 bits: the last byte has 2 samples and 4 bits of padding
+        let samples = [3, 0, 1, 2, 1];
+        let packed = pack(&samples, 2);
+        assert_eq!(packed, vec![0b11_00_01_10, 0b01_00_00_00]);
+        assert_eq!(unpack(&packed, 2, 5), samples);
+
+        // The padding is ignored
+        assert_eq!(unpack(&[0b1010_1111], 1, 4), vec![1, 0, 1, 0]);
+        assert_eq!(unpack(&[0x3F, 0x20], 4, 3), vec![3, 15, 2]);
+    }
+
+    #[test]
+    fn scale_test() {
+        assert_eq!(scale_to_8(1, 1), 255);
+        assert_eq!(scale_to_8(2, 2), 170);
+        assert_eq!(scale_to_8(0xA, 4), 0xAA);
+        assert_eq!(scale_to_8(0b101, 3), 0b1011_0110);
+        assert_eq!(scale_to_8(200, 8), 200);
+
+        for bit_depth in [1, 2, 4, 8] {
+            for sample in 0..1 << bit_depth {
+                let scaled = scale_to_8(sample as u8, bit_depth);
+                assert_eq!(scale_from_8(scaled, bit_depth), sample as u8);
+            }
+        }
+
+        // 257 * x is the 16-bit version of x
+        for sample in 0..=255 {
+            assert_eq!(round_16_to_8(sample * 257), sample as u8);
+        }
+        assert_eq!(round_16_to_8(0x0080), 0);
+        assert_eq!(round_16_to_8(0x0081), 1);
+        assert_eq!(round_16_to_8(0xFF7E), 254);
+        assert_eq!(round_16_to_8(0xFF7F), 255);
+    }
+}
@@ -0,0 +1,221 @@
+//! Removal of metadata: ancillary chunks can be dropped without touching the image data, for
+//! example to remove personal information (comments, dates, camera data) from uploaded images.
+//!
+//! Chunks are selected by category or by their type. Critical chunks (`IHDR`, `PLTE`, `IDAT` and
+//! `IEND`) are needed to decode the image, so they are never removed.
+
+use super::{chunks::ChunkType, Png};
+use std::io;
+
+/// Groups of related ancillary chunks.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Category {
+    /// Textual data: `tEXt`, `zTXt` and `iTXt`
+    Text,
+    /// Last modification time: `tIME`
+    Time,
+    /// Exif metadata: `eXIf`
+    Exif,
+    /// Colour space information: `gAMA`, `cHRM`, `sRGB`, `iCCP`, `sBIT`, `cICP`, `mDCv`, `cLLi`
+    Colour,
+    /// Chunks not defined by the spec (see `ChunkType::is_public`)
+    Private,
+    /// Every ancillary chunk
+    Ancillary,
+}
+
+impl Category {
+    pub const ALL: [Category; 6] = [
+        Category::Text,
+        Category::Time,
+        Category::Exif,
+        Category::Colour,
+        Category::Private,
+        Category::Ancillary,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Text => "text",
+            Category::Time => "time",
+            Category::Exif => "exif",
+            Category::Colour => "colour",
+            Category::Private => "private",
+            Category::Ancillary => "all",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|category| category.name() == name)
+    }
+
+    /// Whether the chunk type belongs to this category. Critical chunks do not belong to any.
+    pub fn contains(self, chunk_type: ChunkType) -> bool {
+        if chunk_type.is_critical() {
+            return false;
+        }
+
+        let codes: &[&[u8; 4]] = match self {
+            Category::Text => &[b"tEXt", b"zTXt", b"iTXt"],
+            Category::Time => &[b"tIME"],
+            Category::Exif => &[b"eXIf"],
+            Category::Colour => &[
+                b"gAMA", b"cHRM", b"sRGB", b"iCCP", b"sBIT", b"cICP", b"mDCv", b"cLLi",
+            ],
+            Category::Private => return !chunk_type.is_public(),
+            Category::Ancillary => return true,
+        };
+        codes.contains(&chunk_type.as_bytes())
+    }
+}
+
+/// Which chunks `Png::strip` removes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StripOptions {
+    pub categories: Vec<Category>,
+    /// Other chunk types to remove
+    pub types: Vec<ChunkType>,
+    /// Chunk types that are kept even if they belong to one of the categories
+    pub keep: Vec<ChunkType>,
+}
+
+impl StripOptions {
+    /// The chunks that may contain personal information: text, time, Exif and private chunks.
+    pub fn privacy() -> Self {
+        Self {
+            categories: vec![
+                Category::Text,
+                Category::Time,
+                Category::Exif,
+                Category::Private,
+            ],
+            ..Default::default()
+        }
+    }
+
+    /// Builds the options from a list of category names and chunk codes, like `text,time,pHYs`.
+    pub fn from_list(list: &str) -> io::Result<Self> {
+        let mut options = Self::default();
+
+        for name in list.split(',').filter(|name| !name.is_empty()) {
+            if let Some(category) = Category::from_name(name) {
+                options.categories.push(category);
+            } else {
+                options.types.push(parse_type(name)?);
+            }
+        }
+
+        Ok(options)
+    }
+
+    /// Whether a chunk of this type is removed.
+    pub fn removes(&self, chunk_type: ChunkType) -> bool {
+        !chunk_type.is_critical()
+            && !self.keep.contains(&chunk_type)
+            && (self.types.contains(&chunk_type)
+                || self
+                    .categories
+                    .iter()
+                    .any(|category| category.contains(chunk_type)))
+    }
+}
+
+/// Parses the code of an ancillary chunk.
+pub fn parse_type(code: &str) -> io::Result<ChunkType> {
+    let chunk_type = ChunkType::from_code(code).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} is neither a chunk type nor a category", code),
+        )
+    })?;
+
+    if chunk_type.is_critical() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is a critical chunk and cannot be removed", code),
+        ));
+    }
+
+    Ok(chunk_type)
+}
+
+impl Png {
+    /// Removes the ancillary chunks selected by `options`, returning the types of the removed
+    /// chunks in file order. The rest of the chunks (and so the image data) are left untouched.
+    pub fn strip(&mut self, options: &StripOptions) -> Vec<ChunkType> {
+        let mut removed = Vec::new();
+
+        self.chunks.retain(|chunk| {
+            let chunk_type = chunk.get_type();
+            let remove = options.removes(chunk_type);
+            if remove {
+                removed.push(chunk_type);
+            }
+            !remove
+        });
+
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::{
+        chunks::{Gamma, GenericChunk, Palette, IDAT, PLTE},
+        encoder::EncodeOptions,
+        image::Image,
+    };
+
+    #[test]
+    fn strip_test() {
+        let palette = Palette::new(vec![[0, 0, 0], [255, 255, 255]]);
+        let image = Image::indexed(2, 1, vec![0, 1], palette);
+        let mut png = Png::from_image(&image, &EncodeOptions::default()).unwrap();
+        let original = png.to_vec();
+
+        let generic = |code: &str| {
+            Box::new(GenericChunk::from_bytes(
+                ChunkType::from_code(code).unwrap(),
+                b"data",
+            ))
+        };
+        png.chunks.insert(0, Box::new(Gamma::new(1.0 / 2.2)));
+        png.chunks.insert(0, generic("tIME"));
+        png.chunks.push(generic("tEXt"));
+        png.chunks.push(generic("prVt"));
+        png.chunks.push(generic("pHYs"));
+
+        let mut stripped = Png::read_from(&png.to_vec()[..]).unwrap();
+        let removed = stripped.strip(&StripOptions::privacy());
+        assert_eq!(
+            removed,
+            ["tIME", "tEXt", "prVt"].map(|code| ChunkType::from_code(code).unwrap())
+        );
+
+        let types: Vec<_> = stripped.chunks.iter().map(|c| c.get_type()).collect();
+        assert_eq!(
+            types,
+            [
+                ChunkType::from_code("gAMA").unwrap(),
+                PLTE,
+                IDAT,
+                ChunkType::from_code("pHYs").unwrap()
+            ]
+        );
+
+        // Everything but the gAMA, which is the only difference with the original
+        let mut options = StripOptions::from_list("all,tEXt").unwrap();
+        options.keep.push(ChunkType::from_code("gAMA").unwrap());
+        stripped.strip(&options);
+        stripped.chunks.remove(0);
+        assert_eq!(stripped.to_vec(), original);
+
+        assert!(StripOptions::from_list("text,IDAT").is_err());
+        assert!(StripOptions::from_list("metadata").is_err());
+        assert!(StripOptions::from_list("prvw,pr1w").is_err());
+        assert!(StripOptions::from_list("prvw").is_ok());
+    }
+}
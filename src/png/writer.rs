@@ -0,0 +1,147 @@
+//! Writes chunks directly into any `io::Write`. The CRC is calculated while the bytes go through,
+//! so the chunks do not have to be copied into a buffer first (as `Chunk::to_bytes` does).
+
+use super::{
+    chunks::{Chunk, ChunkType},
+    crc::CrcHasher,
+    SIGN,
+};
+use std::io::{self, Write};
+
+pub struct ChunkWriter<W: Write> {
+    writer: W,
+    bytes_written: u64,
+}
+
+impl<W: Write> ChunkWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            bytes_written: 0,
+        }
+    }
+
+    /// Writes the PNG signature, which must be the first thing of the file.
+    pub fn write_signature(&mut self) -> io::Result<u64> {
+        self.writer.write_all(&SIGN)?;
+        self.bytes_written += SIGN.len() as u64;
+        Ok(SIGN.len() as u64)
+    }
+
+    /// Writes a complete chunk, returning its size in bytes.
+    pub fn write_chunk(&mut self, chunk: &dyn Chunk) -> io::Result<u64> {
+        let data_size = chunk.data_size();
+        self.writer.write_all(&data_size.to_be_bytes())?;
+
+        // Both the type and data sections are covered by the CRC
+        let mut hashed = CrcWriter {
+            writer: &mut self.writer,
+            crc: CrcHasher::new(),
+        };
+        hashed.write_all(chunk.get_type().as_bytes())?;
+        chunk.write_data(&mut hashed)?;
+
+        let (crc, count) = (hashed.crc.finalize(), hashed.crc.bytes_hashed());
+        if count != 4 + data_size as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Chunk {:?} declared {} bytes of data but wrote {}",
+                    chunk.get_type(),
+                    data_size,
+                    count - 4
+                ),
+            ));
+        }
+
+        self.writer.write_all(&crc.to_be_bytes())?;
+
+        let chunk_size = 12 + data_size as u64;
+        self.bytes_written += chunk_size;
+        Ok(chunk_size)
+    }
+
+    /// Writes a chunk given its type and data, without the need of a `Chunk`.
+    pub fn write_raw_chunk(&mut self, chunk_type: ChunkType, data: &[u8]) -> io::Result<u64> {
+        let data_size = u32::try_from(data.len()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Chunk data is too long: {} bytes", data.len()),
+            )
+        })?;
+
+        let mut crc = CrcHasher::new();
+        crc.update(chunk_type.as_bytes());
+        crc.update(data);
+
+        self.writer.write_all(&data_size.to_be_bytes())?;
+        self.writer.write_all(chunk_type.as_bytes())?;
+        self.writer.write_all(data)?;
+        self.writer.write_all(&crc.finalize().to_be_bytes())?;
+
+        let chunk_size = 12 + data_size as u64;
+        self.bytes_written += chunk_size;
+        Ok(chunk_size)
+    }
+
+    /// Total number of bytes written so far.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Forwards the bytes to `writer` while calculating their CRC.
+struct CrcWriter<'a, W: Write> {
+    writer: &'a mut W,
+    crc: CrcHasher,
+}
+
+impl<W: Write> Write for CrcWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.crc.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::chunks::{ImageData, ImageHeader, Palette, IDAT};
+
+    #[test]
+    fn same_as_to_bytes_test() {
+        let chunks: Vec<Box<dyn Chunk>> = vec![
+            Box::new(ImageHeader::new((640, 480), 8, 3, false)),
+            Box::new(Palette::new(vec![[0, 0, 0], [255, 128, 0]])),
+            Box::new(ImageData::from_bytes(b"some compressed data")),
+        ];
+
+        let mut writer = ChunkWriter::new(Vec::new());
+        let mut expected = Vec::new();
+        for chunk in &chunks {
+            let size = writer.write_chunk(chunk.as_ref()).unwrap();
+            let bytes = chunk.to_bytes();
+            assert_eq!(size, bytes.len() as u64);
+            expected.extend_from_slice(&bytes);
+        }
+
+        writer.write_raw_chunk(IDAT, b"more data").unwrap();
+        expected.extend_from_slice(&ImageData::from_bytes(b"more data").to_bytes());
+
+        assert_eq!(writer.bytes_written(), expected.len() as u64);
+        assert_eq!(writer.into_inner(), expected);
+    }
+}